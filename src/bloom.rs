@@ -0,0 +1,77 @@
+use crate::hash::*;
+use crate::patchy::*;
+
+// Block index with a Bloom filter over the weak hashes instead of a hash set. Weak false
+// positives only cost an extra strong hash of the window, since every weak hit is confirmed
+// with `lookup_strong`. The strong hashes are still kept exactly.
+pub struct BloomBlockIndex<H = Hash128> {
+    bits: Vec<u64>,
+    bit_count: u64,
+    hash_count: u32,
+    strong: HashMap<H, u64>,
+}
+
+// Spreads the weak hash over 64 bits. Weak hashes of similar windows share many bits, so they
+// are not used as filter positions directly.
+fn mix_weak_hash(hash_weak: u32) -> u64 {
+    let mut z = (hash_weak as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+impl<H: BlockHash> BloomBlockIndex<H> {
+    // `false_positive_rate` is the target probability that `contains_weak` accepts a weak hash
+    // that is not in `other_blocks`, clamped to [1e-9, 0.5].
+    pub fn new(other_blocks: &[Block<H>], false_positive_rate: f64) -> Self {
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let n = other_blocks.len().max(1) as f64;
+        let bit_count = ((-n * rate.ln() / (ln2 * ln2)).ceil() as u64).max(64);
+        let hash_count = ((bit_count as f64 / n * ln2).round() as u32).max(1);
+        let mut result = Self {
+            bits: vec![0; div_up(bit_count as usize, 64)],
+            bit_count,
+            hash_count,
+            strong: HashMap::default(),
+        };
+        for block in other_blocks {
+            result.insert_weak(block.hash_weak);
+            result
+                .strong
+                .entry(block.hash_strong)
+                .or_insert(block.offset);
+        }
+        result
+    }
+    // Size of the weak hash filter in bytes.
+    pub fn filter_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+    pub fn hash_count(&self) -> u32 {
+        self.hash_count
+    }
+    // Double hashing: position i is h1 + i * h2.
+    fn positions(&self, hash_weak: u32) -> impl Iterator<Item = u64> {
+        let mixed = mix_weak_hash(hash_weak);
+        let h1 = mixed & 0xFFFF_FFFF;
+        let h2 = (mixed >> 32) | 1;
+        let bit_count = self.bit_count;
+        (0..self.hash_count as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+    }
+    fn insert_weak(&mut self, hash_weak: u32) {
+        for position in self.positions(hash_weak) {
+            self.bits[(position / 64) as usize] |= 1 << (position % 64);
+        }
+    }
+}
+
+impl<H: BlockHash> BlockIndex<H> for BloomBlockIndex<H> {
+    fn contains_weak(&self, hash_weak: u32) -> bool {
+        self.positions(hash_weak)
+            .all(|position| self.bits[(position / 64) as usize] & (1 << (position % 64)) != 0)
+    }
+    fn lookup_strong(&self, hash_strong: &H) -> Option<u64> {
+        self.strong.get(hash_strong).copied()
+    }
+}
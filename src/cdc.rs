@@ -0,0 +1,123 @@
+use crate::hash::*;
+use crate::patchy::*;
+
+// Content-defined chunking with a gear hash: h = (h << 1) + GEAR[byte], so the top bits of h
+// depend on the last 64 bytes only. A chunk ends where the top log2(avg_size) bits are all zero,
+// or at max_size. Boundaries follow the content, so an insertion only changes the chunks around
+// it instead of shifting every later block.
+const fn build_gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR_TABLE: [u64; 256] = build_gear_table();
+
+fn check_cdc_sizes(min_size: usize, avg_size: usize, max_size: usize) -> Result<(), PatchError> {
+    if min_size == 0 || min_size > avg_size || avg_size > max_size {
+        return Err(PatchError::InvalidBlockSize {
+            block_size: avg_size,
+        });
+    }
+    if max_size > u32::MAX as usize {
+        return Err(PatchError::InvalidBlockSize {
+            block_size: max_size,
+        });
+    }
+    Ok(())
+}
+
+// Length of the chunk starting at the beginning of `input`.
+fn next_chunk_len(input: &[u8], min_size: usize, max_size: usize, mask: u64) -> usize {
+    if input.len() <= min_size {
+        return input.len();
+    }
+    let end = input.len().min(max_size);
+    let mut hash: u64 = 0;
+    for (i, x) in input[..end].iter().enumerate().skip(min_size) {
+        hash = (hash << 1).wrapping_add(GEAR_TABLE[*x as usize]);
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+pub fn compute_blocks_cdc(
+    input: &[u8],
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+) -> Vec<Block> {
+    try_compute_blocks_cdc(input, min_size, avg_size, max_size).unwrap()
+}
+
+// Requires 0 < min_size <= avg_size <= max_size <= u32::MAX. Only the final block may be
+// shorter than min_size.
+pub fn try_compute_blocks_cdc<H: BlockHash>(
+    input: &[u8],
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+) -> Result<Vec<Block<H>>, PatchError> {
+    check_cdc_sizes(min_size, avg_size, max_size)?;
+    let mask_bits = 63 - (avg_size as u64).leading_zeros() as u64;
+    let mask = if mask_bits == 0 {
+        0
+    } else {
+        u64::MAX << (64 - mask_bits)
+    };
+    let mut result: Vec<Block<H>> = Vec::with_capacity(input.len() / avg_size + 1);
+    let mut offset: usize = 0;
+    while offset < input.len() {
+        let size = next_chunk_len(&input[offset..], min_size, max_size, mask);
+        result.push(Block {
+            offset: offset as u64,
+            size: size as u32,
+            hash_weak: 0,
+            hash_strong: H::zero(),
+        });
+        offset += size;
+    }
+    hash_blocks::<H, RollingHash>(input, &mut result, &Blake3Hasher, true);
+    Ok(result)
+}
+
+// Diffs against blocks from `compute_blocks_cdc` called with the same sizes. The rolling search
+// of `compute_diff` needs a fixed window, so instead `input` is chunked the same way and chunks
+// are matched by strong hash; identical content produces identical chunks once the boundaries
+// resynchronize after an edit. The patch records `avg_size` as its block size.
+pub fn compute_diff_cdc<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+) -> PatchCommands {
+    try_compute_diff_cdc(input, other_blocks, min_size, avg_size, max_size).unwrap()
+}
+
+pub fn try_compute_diff_cdc<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+) -> Result<PatchCommands, PatchError> {
+    let base_blocks: Vec<Block<H>> = try_compute_blocks_cdc(input, min_size, avg_size, max_size)?;
+    Ok(build_patch_commands(
+        input.len(),
+        avg_size,
+        other_blocks,
+        &base_blocks,
+    ))
+}
@@ -0,0 +1,148 @@
+use crate::patchy::*;
+use std::cmp::min;
+
+enum SegmentSource {
+    Base { source: u64, source_id: u32 },
+    Literal { source: u64 },
+}
+
+// A range of the intermediate version written by one command of the first patch.
+struct Segment {
+    target: u64,
+    size: u64,
+    source: SegmentSource,
+}
+
+impl Patch {
+    // Combines patches v1 -> v2 and v2 -> v3 into v1 -> v3 without the v2 bytes. Base copies of
+    // `second` are resolved through the commands of `first`, so they become copies from v1 or
+    // literals taken from `first.data`. The result is uncompressed.
+    pub fn compose(first: &Patch, second: &Patch) -> Result<Patch, PatchError> {
+        first.validate_coverage()?;
+        let first_data = first.decompressed_data()?;
+        let second_data = second.decompressed_data()?;
+        let mut segments: Vec<Segment> = Vec::with_capacity(first.base.len() + first.other.len());
+        for cmd in &first.base {
+            if cmd.is_self_ref() {
+                return Err(PatchError::InvalidSourceId {
+                    source_id: cmd.source_id,
+                });
+            }
+            segments.push(Segment {
+                target: cmd.target,
+                size: cmd.size as u64,
+                source: SegmentSource::Base {
+                    source: cmd.source,
+                    source_id: cmd.source_id,
+                },
+            });
+        }
+        for cmd in &first.other {
+            let source_end = checked_usize(cmd.source)?.checked_add(cmd.size as usize);
+            if !matches!(source_end, Some(end) if end <= first_data.len()) {
+                return Err(PatchError::SourceOutOfRange {
+                    offset: cmd.source,
+                    size: cmd.size,
+                    len: first_data.len(),
+                });
+            }
+            segments.push(Segment {
+                target: cmd.target,
+                size: cmd.size as u64,
+                source: SegmentSource::Literal { source: cmd.source },
+            });
+        }
+        segments.retain(|segment| segment.size != 0);
+        segments.sort_by_key(|segment| segment.target);
+        let first_size = first.output_size();
+
+        let mut data: Vec<u8> = Vec::new();
+        let mut base: Vec<CopyCmd> = Vec::new();
+        let mut other: Vec<CopyCmd> = Vec::new();
+        for cmd in &second.other {
+            let source_begin = checked_usize(cmd.source)?;
+            let source_end = source_begin.checked_add(cmd.size as usize);
+            let slice = match source_end {
+                Some(end) if end <= second_data.len() => &second_data[source_begin..end],
+                _ => {
+                    return Err(PatchError::SourceOutOfRange {
+                        offset: cmd.source,
+                        size: cmd.size,
+                        len: second_data.len(),
+                    })
+                }
+            };
+            other.push(CopyCmd {
+                source: data.len() as u64,
+                target: cmd.target,
+                size: cmd.size,
+                source_id: 0,
+            });
+            data.extend_from_slice(slice);
+        }
+        for cmd in &second.base {
+            if cmd.is_self_ref() {
+                base.push(cmd.clone());
+                continue;
+            }
+            if cmd.source_id != 0 {
+                return Err(PatchError::InvalidSourceId {
+                    source_id: cmd.source_id,
+                });
+            }
+            let source_end = cmd.source.checked_add(cmd.size as u64);
+            if !matches!(source_end, Some(end) if end <= first_size) {
+                return Err(PatchError::SourceOutOfRange {
+                    offset: cmd.source,
+                    size: cmd.size,
+                    len: checked_usize(first_size).unwrap_or(usize::MAX),
+                });
+            }
+            let mut position = cmd.source;
+            let end = cmd.source + cmd.size as u64;
+            // Coverage was validated, so every position of v2 falls into exactly one segment.
+            let mut index =
+                segments.partition_point(|segment| segment.target + segment.size <= position);
+            while position < end {
+                let segment = &segments[index];
+                let offset = position - segment.target;
+                let size = min(segment.target + segment.size, end) - position;
+                let target = cmd.target + (position - cmd.source);
+                match segment.source {
+                    SegmentSource::Base { source, source_id } => base.push(CopyCmd {
+                        source: source + offset,
+                        target,
+                        size: size as u32,
+                        source_id,
+                    }),
+                    SegmentSource::Literal { source } => {
+                        let literal_begin = (source + offset) as usize;
+                        other.push(CopyCmd {
+                            source: data.len() as u64,
+                            target,
+                            size: size as u32,
+                            source_id: 0,
+                        });
+                        data.extend_from_slice(
+                            &first_data[literal_begin..literal_begin + size as usize],
+                        );
+                    }
+                }
+                position += size;
+                index += 1;
+            }
+        }
+        optimize_copy_cmds(&mut base);
+        optimize_copy_cmds(&mut other);
+        Ok(Patch {
+            data,
+            base,
+            other,
+            other_size: second.other_size,
+            compression: Compression::None,
+            other_hash: second.other_hash,
+            base_hash: first.base_hash,
+            block_size: second.block_size,
+        })
+    }
+}
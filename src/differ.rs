@@ -0,0 +1,175 @@
+use crate::hash::*;
+use crate::patchy::*;
+use std::marker::PhantomData;
+
+// Keeps block size, strong hash and compression consistent across signature, diff and build.
+// Unless a block size is set, each signature uses `recommended_block_size` for the length of its
+// data and diffs follow the signature. The other defaults match `compute_diff` and `build_patch`.
+#[derive(Debug, Clone, Copy)]
+pub struct Differ<H = Hash128> {
+    block_size: Option<usize>,
+    compression: Compression,
+    hash: PhantomData<H>,
+}
+
+impl Differ<Hash128> {
+    pub fn new() -> Self {
+        Differ {
+            block_size: None,
+            compression: Compression::None,
+            hash: PhantomData,
+        }
+    }
+}
+
+impl Default for Differ<Hash128> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: BlockHash> Differ<H> {
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+    // Uses `recommended_block_size` for data of `input_len` bytes. The chosen size is recorded in
+    // the built `Patch`.
+    pub fn adaptive_block_size(self, input_len: usize) -> Self {
+        self.block_size(recommended_block_size(input_len))
+    }
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+    // Compresses literal runs one by one instead of all literals at once; replaces any
+    // `compression` set before.
+    pub fn literal_encoding(self, encoding: LiteralEncoding) -> Self {
+        self.compression(Compression::PerRun(encoding))
+    }
+    // Switches the strong hash, e.g. `strong_hash::<Hash256>()`. The width is part of the type
+    // rather than a `strong_hash_bits` setting, so that a signature can only be diffed by a
+    // `Differ` of the same width; `strong_hash_bits` reports it.
+    pub fn strong_hash<H2: BlockHash>(self) -> Differ<H2> {
+        Differ {
+            block_size: self.block_size,
+            compression: self.compression,
+            hash: PhantomData,
+        }
+    }
+    // `None` when the block size is picked per signature.
+    pub fn get_block_size(&self) -> Option<usize> {
+        self.block_size
+    }
+    pub fn strong_hash_bits(&self) -> usize {
+        H::SIZE * 8
+    }
+    pub fn signature(&self, data: &[u8]) -> Result<Signature<H>, PatchError> {
+        let block_size = self
+            .block_size
+            .unwrap_or_else(|| recommended_block_size(data.len()));
+        Ok(Signature {
+            block_size,
+            blocks: try_compute_blocks_generic(data, block_size)?,
+        })
+    }
+    pub fn diff(
+        &self,
+        input: &[u8],
+        signature: &Signature<H>,
+    ) -> Result<PatchCommands, PatchError> {
+        self.check_block_size(signature.block_size)?;
+        check_signature_blocks(signature)?;
+        try_compute_diff(input, &signature.blocks, signature.block_size)
+    }
+    pub fn build(
+        &self,
+        other_data: &[u8],
+        patch_commands: &PatchCommands,
+    ) -> Result<Patch, PatchError> {
+        self.check_block_size(patch_commands.block_size)?;
+        build_patch_compressed(other_data, patch_commands, self.compression)
+    }
+    fn check_block_size(&self, block_size: usize) -> Result<(), PatchError> {
+        match self.block_size {
+            Some(expected) if expected != block_size => Err(PatchError::BlockSizeMismatch {
+                expected,
+                actual: block_size,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+// All blocks but the last must be exactly `block_size` long.
+fn check_signature_blocks<H>(signature: &Signature<H>) -> Result<(), PatchError> {
+    let block_size = signature.block_size;
+    let last = signature.blocks.len().saturating_sub(1);
+    for (i, block) in signature.blocks.iter().enumerate() {
+        let size = block.size as usize;
+        if size > block_size || (i < last && size != block_size) {
+            return Err(PatchError::BlockSizeMismatch {
+                expected: block_size,
+                actual: size,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Diffs a streamed base against a streamed target in lockstep windows of `window_size` bytes.
+// Each window yields its own `Patch` that applies to that window of the base alone, with
+// offsets relative to the window start.
+pub struct WindowDiffer {
+    window_size: usize,
+    block_size: usize,
+    base: Vec<u8>,
+    target: Vec<u8>,
+    finished: bool,
+}
+
+impl WindowDiffer {
+    pub fn new(window_size: usize, block_size: usize) -> Self {
+        assert!(window_size > 0, "WindowDiffer window size must be non-zero");
+        assert!(block_size > 0, "WindowDiffer block size must be non-zero");
+        WindowDiffer {
+            window_size,
+            block_size,
+            base: Vec::new(),
+            target: Vec::new(),
+            finished: false,
+        }
+    }
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+    pub fn feed_base(&mut self, input: &[u8]) {
+        debug_assert!(!self.finished, "WindowDiffer fed after finish");
+        self.base.extend_from_slice(input);
+    }
+    pub fn feed_target(&mut self, input: &[u8]) {
+        debug_assert!(!self.finished, "WindowDiffer fed after finish");
+        self.target.extend_from_slice(input);
+    }
+    // Marks both streams as ended, so the remaining bytes are emitted as shorter final windows.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+    // Returns the patch for the next window once both streams have filled it, or after
+    // `finish` while any bytes remain.
+    pub fn next_window(&mut self) -> Option<Patch> {
+        let complete = self.base.len() >= self.window_size && self.target.len() >= self.window_size;
+        let remaining = !self.base.is_empty() || !self.target.is_empty();
+        if !complete && (!self.finished || !remaining) {
+            return None;
+        }
+        let base_len = self.window_size.min(self.base.len());
+        let target_len = self.window_size.min(self.target.len());
+        let base: Vec<u8> = self.base.drain(..base_len).collect();
+        let target: Vec<u8> = self.target.drain(..target_len).collect();
+        let blocks = compute_blocks(&target, self.block_size);
+        let mut patch_commands = compute_diff(&base, &blocks, self.block_size);
+        patch_commands.expand_synchronized(target.len());
+        Some(build_patch_with_base(&base, &target, &patch_commands))
+    }
+}
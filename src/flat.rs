@@ -0,0 +1,106 @@
+use crate::patchy::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlatOp {
+    // `size` bytes at `source` of base `source_id`, or of the output written so far for
+    // SELF_SOURCE_ID.
+    Copy {
+        source: u64,
+        size: u32,
+        source_id: u32,
+    },
+    Literal(Vec<u8>),
+}
+
+// Commands in output order with the literal bytes inline. Each op writes the bytes right after
+// those of the previous op, so the output can be produced in a single pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlatPatch {
+    pub ops: Vec<FlatOp>,
+    pub other_size: Option<u64>,
+}
+
+impl Patch {
+    // Fails if the commands don't cover the output exactly once, as the ops carry no targets.
+    pub fn to_flat(&self) -> Result<FlatPatch, PatchError> {
+        self.validate_coverage()?;
+        let patch_data = self.decompressed_data()?;
+        let mut cmds: Vec<(&CopyCmd, bool)> = self
+            .base
+            .iter()
+            .map(|cmd| (cmd, false))
+            .chain(self.other.iter().map(|cmd| (cmd, true)))
+            .filter(|(cmd, _)| cmd.size != 0)
+            .collect();
+        cmds.sort_by_key(|(cmd, _)| cmd.target);
+        let mut ops: Vec<FlatOp> = Vec::with_capacity(cmds.len());
+        for (cmd, is_literal) in cmds {
+            if is_literal {
+                cmd.check_bounds(saturating_usize(self.output_size()), patch_data.len())?;
+                let begin = checked_usize(cmd.source)?;
+                let bytes = patch_data[begin..begin + cmd.size as usize].to_vec();
+                ops.push(FlatOp::Literal(bytes));
+            } else {
+                ops.push(FlatOp::Copy {
+                    source: cmd.source,
+                    size: cmd.size,
+                    source_id: cmd.source_id,
+                });
+            }
+        }
+        Ok(FlatPatch {
+            ops,
+            other_size: self.other_size,
+        })
+    }
+}
+
+impl FlatPatch {
+    // The result is uncompressed, with the default block size and without hashes, as a
+    // FlatPatch doesn't record them.
+    pub fn to_patch(&self) -> Patch {
+        let mut data: Vec<u8> = Vec::new();
+        let mut base: Vec<CopyCmd> = Vec::new();
+        let mut other: Vec<CopyCmd> = Vec::new();
+        let mut target: u64 = 0;
+        for op in &self.ops {
+            match op {
+                FlatOp::Copy {
+                    source,
+                    size,
+                    source_id,
+                } => {
+                    base.push(CopyCmd {
+                        source: *source,
+                        target,
+                        size: *size,
+                        source_id: *source_id,
+                    });
+                    target += *size as u64;
+                }
+                FlatOp::Literal(bytes) => {
+                    let copy = LogicalCopy {
+                        source: data.len() as u64,
+                        target,
+                        size: bytes.len() as u64,
+                    };
+                    copy.lower_into(&mut other, 0);
+                    data.extend_from_slice(bytes);
+                    target += bytes.len() as u64;
+                }
+            }
+        }
+        optimize_copy_cmds(&mut base);
+        optimize_copy_cmds(&mut other);
+        Patch {
+            data,
+            base,
+            other,
+            other_size: self.other_size,
+            compression: Compression::None,
+            other_hash: None,
+            base_hash: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
@@ -1,5 +1,20 @@
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
+use crate::patchy::PatchError;
+#[cfg(all(feature = "alloc", not(feature = "std")))]
+use alloc::string::String;
 use core::fmt;
+use core::hash::Hash;
+use serde::de::{self, DeserializeOwned, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Weak hash of a window x[0..n]:
+//   c[i] = (x[i] + ROLLING_HASH_CHAR_OFFSET) mod 2^8
+//   a = sum of c[i] mod 2^16
+//   b = sum of (n - i) * c[i] mod 2^16, i.e. the running sum of `a` after each byte
+//   hash = a | (b << 16)
+// Note that the byte offset wraps at 8 bits before it is widened.
+pub const ROLLING_HASH_CHAR_OFFSET: u8 = 31;
 
 pub struct RollingHash {
     a: u16,
@@ -18,6 +33,11 @@ impl RollingHash {
     pub fn count(&self) -> usize {
         self.count
     }
+    // The `a` and `b` accumulators and the window length, for comparing against other
+    // implementations of the recurrence above.
+    pub fn state(&self) -> (u16, u16, usize) {
+        (self.a, self.b, self.count)
+    }
     pub fn update(&mut self, input: &[u8]) {
         for x in input {
             self.add(*x);
@@ -27,54 +47,432 @@ impl RollingHash {
         (self.a as u32) | ((self.b as u32) << 16)
     }
     pub fn add(&mut self, x: u8) {
-        self.a = self.a.wrapping_add((x.wrapping_add(31)) as u16);
+        self.a = self
+            .a
+            .wrapping_add((x.wrapping_add(ROLLING_HASH_CHAR_OFFSET)) as u16);
         self.b = self.b.wrapping_add(self.a);
         self.count += 1;
     }
+    // Removes the oldest byte `x` from the window. The window must not be empty.
     pub fn sub(&mut self, x: u8) {
-        let x2 = (x.wrapping_add(31)) as u16;
+        debug_assert!(self.count > 0, "RollingHash::sub called on an empty window");
+        let x2 = (x.wrapping_add(ROLLING_HASH_CHAR_OFFSET)) as u16;
         self.a = self.a.wrapping_sub(x2);
-        self.b = self.b.wrapping_sub((self.count * (x2 as usize)) as u16);
+        self.b = self.b.wrapping_sub((self.count as u16).wrapping_mul(x2));
+        self.count -= 1;
+    }
+    // Slides a non-empty window by one byte: `out` leaves at the front, `inp` enters at the back.
+    pub fn roll(&mut self, out: u8, inp: u8) {
+        debug_assert!(
+            self.count > 0,
+            "RollingHash::roll called on an empty window"
+        );
+        let out2 = (out.wrapping_add(ROLLING_HASH_CHAR_OFFSET)) as u16;
+        let inp2 = (inp.wrapping_add(ROLLING_HASH_CHAR_OFFSET)) as u16;
+        self.a = self.a.wrapping_sub(out2).wrapping_add(inp2);
+        self.b = self
+            .b
+            .wrapping_sub((self.count as u16).wrapping_mul(out2))
+            .wrapping_add(self.a);
+    }
+    pub fn try_sub(&mut self, x: u8) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+        self.sub(x);
+        true
+    }
+}
+
+// Adler32-style checksum over a byte substitution table. Both halves are reduced modulo
+// the largest prime below 2^16, so large blocks keep all 32 bits of the weak hash useful.
+pub struct RollingHash32 {
+    a: u32,
+    b: u32,
+    count: usize,
+}
+
+const ROLLING_HASH32_MODULUS: u32 = 65521;
+
+const fn build_rolling_hash32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut state: u32 = 0x9E37_79B9;
+    let mut i = 0;
+    while i < 256 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        table[i] = state % ROLLING_HASH32_MODULUS;
+        i += 1;
+    }
+    table
+}
+
+const ROLLING_HASH32_TABLE: [u32; 256] = build_rolling_hash32_table();
+
+impl RollingHash32 {
+    pub fn new() -> Self {
+        RollingHash32 {
+            a: 0,
+            b: 0,
+            count: 0,
+        }
+    }
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    pub fn update(&mut self, input: &[u8]) {
+        for x in input {
+            self.add(*x);
+        }
+    }
+    pub fn get(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+    pub fn add(&mut self, x: u8) {
+        self.a = (self.a + ROLLING_HASH32_TABLE[x as usize]) % ROLLING_HASH32_MODULUS;
+        self.b = (self.b + self.a) % ROLLING_HASH32_MODULUS;
+        self.count += 1;
+    }
+    // Removes the oldest byte `x` from the window. The window must not be empty.
+    pub fn sub(&mut self, x: u8) {
+        debug_assert!(
+            self.count > 0,
+            "RollingHash32::sub called on an empty window"
+        );
+        let x2 = ROLLING_HASH32_TABLE[x as usize];
+        let weight = (self.count as u64 % ROLLING_HASH32_MODULUS as u64) as u32;
+        let b_sub = (weight as u64 * x2 as u64 % ROLLING_HASH32_MODULUS as u64) as u32;
+        self.a = (self.a + ROLLING_HASH32_MODULUS - x2) % ROLLING_HASH32_MODULUS;
+        self.b = (self.b + ROLLING_HASH32_MODULUS - b_sub) % ROLLING_HASH32_MODULUS;
         self.count -= 1;
     }
+    // Slides a non-empty window by one byte: `out` leaves at the front, `inp` enters at the back.
+    pub fn roll(&mut self, out: u8, inp: u8) {
+        self.sub(out);
+        self.add(inp);
+    }
+    pub fn try_sub(&mut self, x: u8) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+        self.sub(x);
+        true
+    }
 }
 
-#[derive(Clone, Copy, Deserialize, Serialize, Hash, PartialEq, Eq)]
-pub struct Hash128([u8; 16]);
+impl Default for RollingHash32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Rolling checksum used for the weak block hash. Signatures and diffs must use the same one.
+pub trait WeakHash: Send + Sync {
+    fn new() -> Self;
+    fn count(&self) -> usize;
+    fn get(&self) -> u32;
+    fn add(&mut self, x: u8);
+    fn sub(&mut self, x: u8);
+    fn roll(&mut self, out: u8, inp: u8);
+    fn compute(input: &[u8]) -> u32
+    where
+        Self: Sized,
+    {
+        let mut hash = Self::new();
+        for x in input {
+            hash.add(*x);
+        }
+        hash.get()
+    }
+}
+
+impl WeakHash for RollingHash {
+    fn new() -> Self {
+        RollingHash::new()
+    }
+    fn count(&self) -> usize {
+        RollingHash::count(self)
+    }
+    fn get(&self) -> u32 {
+        RollingHash::get(self)
+    }
+    fn add(&mut self, x: u8) {
+        RollingHash::add(self, x)
+    }
+    fn sub(&mut self, x: u8) {
+        RollingHash::sub(self, x)
+    }
+    fn roll(&mut self, out: u8, inp: u8) {
+        RollingHash::roll(self, out, inp)
+    }
+}
+
+impl WeakHash for RollingHash32 {
+    fn new() -> Self {
+        RollingHash32::new()
+    }
+    fn count(&self) -> usize {
+        RollingHash32::count(self)
+    }
+    fn get(&self) -> u32 {
+        RollingHash32::get(self)
+    }
+    fn add(&mut self, x: u8) {
+        RollingHash32::add(self, x)
+    }
+    fn sub(&mut self, x: u8) {
+        RollingHash32::sub(self, x)
+    }
+    fn roll(&mut self, out: u8, inp: u8) {
+        RollingHash32::roll(self, out, inp)
+    }
+}
+
+// The first `N` bytes of a blake3 hash, for `N` from 1 to 32. Shorter hashes make signatures
+// smaller at a higher chance of collisions; both sides of a diff must use the same `N`.
+// Serialized as `N` bytes without a length prefix.
+#[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StrongHash<const N: usize>([u8; N]);
+
+pub type Hash128 = StrongHash<16>;
+pub type Hash256 = StrongHash<32>;
+
+impl<const N: usize> StrongHash<N> {
+    const VALID_SIZE: () = assert!(N >= 1 && N <= 32, "StrongHash must be 1 to 32 bytes");
 
-impl Hash128 {
-    pub fn new_zero() -> Self{
-        Self([0; 16])
+    pub fn new_zero() -> Self {
+        Self([0; N])
     }
     pub fn new_from_blake3(hash: &blake3::Hash) -> Self {
-        let mut bytes: [u8; 16] = [0; 16];
-        bytes.copy_from_slice(&hash.as_bytes()[0..16]);
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID_SIZE;
+        let mut bytes: [u8; N] = [0; N];
+        bytes.copy_from_slice(&hash.as_bytes()[0..N]);
         Self(bytes)
     }
-    pub fn as_bytes(&self) -> &[u8; 16] {
+    pub fn from_bytes(bytes: &[u8; N]) -> Self {
+        Self(*bytes)
+    }
+    // Parses the output of `to_hex_string`, `to_hex_string_upper` or `to_hex_delimited`.
+    #[cfg(feature = "std")]
+    pub fn from_hex(hex: &str) -> Result<Self, PatchError> {
+        let mut bytes = [0u8; N];
+        hex_to_bytes(hex, &mut bytes)?;
+        Ok(Self(bytes))
+    }
+    pub fn as_bytes(&self) -> &[u8; N] {
         &self.0
-	}
-	pub fn to_hex_string(&self) -> String {
-        let mut s = String::new();
-        let table = b"0123456789abcdef";
-        for &b in self.0.iter() {
-            s.push(table[(b >> 4) as usize] as char);
-            s.push(table[(b & 0xf) as usize] as char);
+    }
+    // Compares all bytes regardless of where they differ, for hashes that guard untrusted input.
+    #[cfg(feature = "ct")]
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        use subtle::ConstantTimeEq;
+        bool::from(self.0[..].ct_eq(&other.0[..]))
+    }
+    // Equality for checking patch contents against their embedded hashes: `ct_eq` when the
+    // `ct` feature is enabled.
+    #[cfg(feature = "std")]
+    pub(crate) fn matches(&self, other: &Self) -> bool {
+        #[cfg(feature = "ct")]
+        return self.ct_eq(other);
+        #[cfg(not(feature = "ct"))]
+        return self == other;
+    }
+    #[cfg(feature = "alloc")]
+    pub fn to_hex_string(&self) -> String {
+        bytes_to_hex_string(&self.0, HEX_DIGITS_LOWER, None)
+    }
+    #[cfg(feature = "alloc")]
+    pub fn to_hex_string_upper(&self) -> String {
+        bytes_to_hex_string(&self.0, HEX_DIGITS_UPPER, None)
+    }
+    // Lower case hex with `sep` between bytes, e.g. "01:ab:..." for ':'.
+    #[cfg(feature = "alloc")]
+    pub fn to_hex_delimited(&self, sep: char) -> String {
+        bytes_to_hex_string(&self.0, HEX_DIGITS_LOWER, Some(sep))
+    }
+}
+
+impl<const N: usize> Serialize for StrongHash<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for byte in &self.0 {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+struct StrongHashVisitor<const N: usize>;
+
+impl<'de, const N: usize> Visitor<'de> for StrongHashVisitor<N> {
+    type Value = StrongHash<N>;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} hash bytes", N)
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<StrongHash<N>, A::Error> {
+        let mut bytes = [0u8; N];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
         }
-        s
+        Ok(StrongHash(bytes))
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for StrongHash<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(N, StrongHashVisitor::<N>)
+    }
+}
+
+#[cfg(feature = "alloc")]
+const HEX_DIGITS_LOWER: &[u8; 16] = b"0123456789abcdef";
+#[cfg(feature = "alloc")]
+const HEX_DIGITS_UPPER: &[u8; 16] = b"0123456789ABCDEF";
+
+#[cfg(feature = "alloc")]
+fn bytes_to_hex_string(bytes: &[u8], table: &[u8; 16], sep: Option<char>) -> String {
+    let mut s = String::with_capacity(bytes.len() * 3);
+    for (i, &b) in bytes.iter().enumerate() {
+        if let (Some(sep), true) = (sep, i != 0) {
+            s.push(sep);
+        }
+        s.push(table[(b >> 4) as usize] as char);
+        s.push(table[(b & 0xf) as usize] as char);
+    }
+    s
+}
+
+// Digits of either case. A single ASCII space or punctuation character may separate two bytes;
+// `InvalidHex` reports the offset of any other character.
+#[cfg(feature = "std")]
+fn hex_to_bytes(hex: &str, out: &mut [u8]) -> Result<(), PatchError> {
+    let mut digit_count: usize = 0;
+    let mut high_nibble: Option<u8> = None;
+    let mut separator_offset: Option<usize> = None;
+    for (offset, &c) in hex.as_bytes().iter().enumerate() {
+        let value = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            b' ' | b'!'..=b'/' | b':'..=b'@' | b'['..=b'`' | b'{'..=b'~'
+                if digit_count != 0 && high_nibble.is_none() && separator_offset.is_none() =>
+            {
+                separator_offset = Some(offset);
+                continue;
+            }
+            _ => return Err(PatchError::InvalidHex { offset }),
+        };
+        separator_offset = None;
+        match high_nibble.take() {
+            None => high_nibble = Some(value),
+            Some(high) => {
+                if let Some(byte) = out.get_mut(digit_count / 2) {
+                    *byte = (high << 4) | value;
+                }
+            }
+        }
+        digit_count += 1;
+    }
+    if let Some(offset) = separator_offset {
+        return Err(PatchError::InvalidHex { offset });
+    }
+    if digit_count != out.len() * 2 {
+        return Err(PatchError::LengthMismatch {
+            expected: (out.len() * 2) as u64,
+            actual: digit_count as u64,
+        });
+    }
+    Ok(())
+}
+
+fn fmt_hex(bytes: &[u8], f: &mut fmt::Formatter) -> fmt::Result {
+    for b in bytes {
+        write!(f, "{:02x}", b)?;
     }
+    Ok(())
 }
 
-impl fmt::Debug for Hash128 {
+// Named after the bit length, e.g. "Hash128(...)" for `StrongHash<16>`.
+impl<const N: usize> fmt::Debug for StrongHash<N> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Hash128({})", self.to_hex_string())
+        write!(f, "Hash{}(", N * 8)?;
+        fmt_hex(&self.0, f)?;
+        write!(f, ")")
+    }
+}
+
+// Bare lower case hex, the same as `to_hex_string`.
+impl<const N: usize> fmt::Display for StrongHash<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_hex(&self.0, f)
+    }
+}
+
+// Parses exactly `2 * N` hex digits, e.g. the output of `Display`. Unlike `from_hex`, no
+// separators are accepted.
+#[cfg(feature = "std")]
+impl<const N: usize> core::str::FromStr for StrongHash<N> {
+    type Err = PatchError;
+    fn from_str(hex: &str) -> Result<Self, PatchError> {
+        if let Some(offset) = hex.bytes().position(|c| !c.is_ascii_hexdigit()) {
+            return Err(PatchError::InvalidHex { offset });
+        }
+        Self::from_hex(hex)
+    }
+}
+
+// Strong block hash used by signatures and diffs. Both sides of a diff must use the same type.
+pub trait BlockHash:
+    Copy + Eq + Hash + fmt::Debug + Send + Sync + Serialize + DeserializeOwned
+{
+    const SIZE: usize;
+    fn zero() -> Self;
+    fn compute(input: &[u8]) -> Self;
+}
+
+impl<const N: usize> BlockHash for StrongHash<N> {
+    const SIZE: usize = N;
+    fn zero() -> Self {
+        Self::new_zero()
+    }
+    fn compute(input: &[u8]) -> Self {
+        compute_hash_strong_generic(input)
+    }
+}
+
+// Computes the strong hash of blocks in signatures and diffs, for hashes other than the
+// blake3-based `BlockHash::compute`, e.g. SHA-256 truncated to 16 bytes. Both sides of a diff
+// must use the same hasher.
+pub trait StrongHasher<H = Hash128>: Sync {
+    fn hash(&self, input: &[u8]) -> H;
+}
+
+// `BlockHash::compute`, the hasher of `compute_blocks` and `compute_diff`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3Hasher;
+
+impl<H: BlockHash> StrongHasher<H> for Blake3Hasher {
+    fn hash(&self, input: &[u8]) -> H {
+        H::compute(input)
     }
 }
 
 pub fn compute_hash_strong(input: &[u8]) -> Hash128 {
+    compute_hash_strong_generic(input)
+}
+
+pub fn compute_hash_strong_256(input: &[u8]) -> Hash256 {
+    compute_hash_strong_generic(input)
+}
+
+pub fn compute_hash_strong_generic<const N: usize>(input: &[u8]) -> StrongHash<N> {
     let mut hasher_blake3 = blake3::Hasher::new();
     hasher_blake3.update(input);
-    Hash128::new_from_blake3(&hasher_blake3.finalize())
+    StrongHash::new_from_blake3(&hasher_blake3.finalize())
 }
 
 pub fn compute_hash_weak(input: &[u8]) -> u32 {
@@ -82,3 +480,34 @@ pub fn compute_hash_weak(input: &[u8]) -> u32 {
     hash_rolling.update(&input);
     hash_rolling.get()
 }
+
+const fn build_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = build_crc32_table();
+
+// CRC-32 (IEEE 802.3), as used by zip and gzip.
+pub fn compute_crc32(input: &[u8]) -> u32 {
+    let mut crc: u32 = !0;
+    for x in input {
+        crc = (crc >> 8) ^ CRC32_TABLE[((crc as u8) ^ x) as usize];
+    }
+    !crc
+}
@@ -0,0 +1,210 @@
+use crate::hash::*;
+use crate::patchy::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Bytes moved per read and write when copying within the file.
+const IN_PLACE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InPlaceStep {
+    // Reads the source of the copy into memory, so that it no longer has to run before the
+    // copies that overwrite its source.
+    Spill(usize),
+    Copy(usize),
+}
+
+fn ranges_overlap(a_begin: u64, a_size: u32, b_begin: u64, b_size: u32) -> bool {
+    a_begin < b_begin + b_size as u64 && b_begin < a_begin + a_size as u64
+}
+
+// Order of the base copies when base and output share a file. A copy has to run before every
+// other copy whose target overlaps its source. Where these constraints form a cycle, the
+// smallest copy on it is spilled, which takes back its constraints.
+pub(crate) fn plan_in_place_copies(cmds: &[CopyCmd]) -> Vec<InPlaceStep> {
+    // `successors[a]` are the copies that overwrite the source of `a`. Candidates are found by
+    // source offset, as no source starts more than the largest size before a target it overlaps.
+    let mut by_source: Vec<usize> = (0..cmds.len()).collect();
+    by_source.sort_by_key(|&i| cmds[i].source);
+    let max_size = cmds.iter().map(|cmd| cmd.size as u64).max().unwrap_or(0);
+    let mut successors: Vec<Vec<usize>> = vec![Vec::new(); cmds.len()];
+    let mut predecessor_count: Vec<usize> = vec![0; cmds.len()];
+    for (writer, cmd) in cmds.iter().enumerate() {
+        let first = by_source.partition_point(|&i| cmds[i].source + max_size <= cmd.target);
+        for &reader in &by_source[first..] {
+            let source = &cmds[reader];
+            if source.source >= cmd.target + cmd.size as u64 {
+                break;
+            }
+            if reader != writer && ranges_overlap(source.source, source.size, cmd.target, cmd.size)
+            {
+                successors[reader].push(writer);
+                predecessor_count[writer] += 1;
+            }
+        }
+    }
+
+    let mut steps: Vec<InPlaceStep> = Vec::with_capacity(cmds.len());
+    let mut done: Vec<bool> = vec![false; cmds.len()];
+    let mut ready: Vec<usize> = (0..cmds.len())
+        .filter(|&i| predecessor_count[i] == 0)
+        .collect();
+    let mut remaining = cmds.len();
+    let release = |node: usize,
+                   successors: &mut Vec<Vec<usize>>,
+                   predecessor_count: &mut Vec<usize>,
+                   ready: &mut Vec<usize>| {
+        for next in std::mem::take(&mut successors[node]) {
+            predecessor_count[next] -= 1;
+            if predecessor_count[next] == 0 {
+                ready.push(next);
+            }
+        }
+    };
+    while remaining != 0 {
+        match ready.pop() {
+            Some(node) => {
+                steps.push(InPlaceStep::Copy(node));
+                done[node] = true;
+                remaining -= 1;
+                release(node, &mut successors, &mut predecessor_count, &mut ready);
+            }
+            None => {
+                // Every copy left waits for another one, so they contain a cycle. Each copy
+                // left that constrains others is on one or leads into one.
+                let node = (0..cmds.len())
+                    .filter(|&i| !done[i] && !successors[i].is_empty())
+                    .min_by_key(|&i| cmds[i].size)
+                    .unwrap();
+                steps.push(InPlaceStep::Spill(node));
+                release(node, &mut successors, &mut predecessor_count, &mut ready);
+            }
+        }
+    }
+    steps
+}
+
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buffer)
+}
+
+fn write_at(file: &mut File, offset: u64, buffer: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buffer)
+}
+
+// Copies within the file like `memmove`: when the target lies after an overlapping source,
+// chunks are moved from the end so that none is overwritten before it's read.
+fn move_within(file: &mut File, cmd: &CopyCmd, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let size = cmd.size as u64;
+    let chunk_count = div_up(cmd.size as usize, IN_PLACE_CHUNK_SIZE) as u64;
+    for i in 0..chunk_count {
+        let chunk = if cmd.target > cmd.source {
+            chunk_count - 1 - i
+        } else {
+            i
+        };
+        let offset = chunk * IN_PLACE_CHUNK_SIZE as u64;
+        let len = (size - offset).min(IN_PLACE_CHUNK_SIZE as u64) as usize;
+        buffer.resize(len, 0);
+        read_at(file, cmd.source + offset, buffer)?;
+        write_at(file, cmd.target + offset, buffer)?;
+    }
+    Ok(())
+}
+
+// Applies `patch` to the base held in `file`, leaving the output in its place without a second
+// copy on disk. Base copies run in the order of `plan_in_place_copies`, so that none reads bytes
+// that were already overwritten; the sources of copies on a cycle are held in memory in between.
+// Literals follow, then the self-referential copies in output order, as they read final output.
+// If this fails partway, the file holds neither the base nor the output.
+pub fn apply_patch_in_place(file: &mut File, patch: &Patch) -> io::Result<()> {
+    let invalid_data = |e: PatchError| io::Error::new(io::ErrorKind::InvalidData, e);
+    let base_len = file.seek(SeekFrom::End(0))?;
+    if let Some(expected) = patch.base_hash {
+        let mut hasher_blake3 = blake3::Hasher::new();
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(file, &mut hasher_blake3)?;
+        let actual = Hash128::new_from_blake3(&hasher_blake3.finalize());
+        if !actual.matches(&expected) {
+            return Err(invalid_data(PatchError::WrongBase { expected, actual }));
+        }
+    }
+    patch.validate_coverage().map_err(invalid_data)?;
+    let output_size = patch.output_size();
+    let other_size = checked_usize(output_size).map_err(invalid_data)?;
+    let patch_data = patch.decompressed_data().map_err(invalid_data)?;
+    let mut base_cmds: Vec<CopyCmd> = Vec::new();
+    let mut self_cmds: Vec<&CopyCmd> = Vec::new();
+    for cmd in patch.base.iter().filter(|cmd| cmd.size != 0) {
+        if cmd.is_self_ref() {
+            // A self copy may only read output before its target.
+            if cmd.source >= cmd.target {
+                return Err(invalid_data(PatchError::SourceOutOfRange {
+                    offset: cmd.source,
+                    size: cmd.size,
+                    len: saturating_usize(cmd.target),
+                }));
+            }
+            self_cmds.push(cmd);
+        } else if cmd.source_id != 0 {
+            return Err(invalid_data(PatchError::InvalidSourceId {
+                source_id: cmd.source_id,
+            }));
+        } else {
+            cmd.check_bounds(other_size, saturating_usize(base_len))
+                .map_err(invalid_data)?;
+            base_cmds.push(cmd.clone());
+        }
+    }
+    for cmd in &patch.other {
+        cmd.check_bounds(other_size, patch_data.len())
+            .map_err(invalid_data)?;
+    }
+
+    if output_size > base_len {
+        file.set_len(output_size)?;
+    }
+    let mut spilled: HashMap<usize, Vec<u8>> = HashMap::new();
+    let mut buffer: Vec<u8> = Vec::new();
+    for step in plan_in_place_copies(&base_cmds) {
+        match step {
+            InPlaceStep::Spill(i) => {
+                let cmd = &base_cmds[i];
+                let mut source = vec![0u8; cmd.size as usize];
+                read_at(file, cmd.source, &mut source)?;
+                spilled.insert(i, source);
+            }
+            InPlaceStep::Copy(i) => match spilled.remove(&i) {
+                Some(source) => write_at(file, base_cmds[i].target, &source)?,
+                None => move_within(file, &base_cmds[i], &mut buffer)?,
+            },
+        }
+    }
+    for cmd in &patch.other {
+        let source_begin = checked_usize(cmd.source).map_err(invalid_data)?;
+        let source = &patch_data[source_begin..source_begin + cmd.size as usize];
+        write_at(file, cmd.target, source)?;
+    }
+    self_cmds.sort_by_key(|cmd| cmd.target);
+    for cmd in self_cmds {
+        // Forward in chunks no longer than the distance, so that overlapping copies repeat
+        // their source like `CopyCmd::execute_self_checked`.
+        let distance = cmd.target - cmd.source;
+        let mut copied: u64 = 0;
+        while copied < cmd.size as u64 {
+            let len = (cmd.size as u64 - copied)
+                .min(distance)
+                .min(IN_PLACE_CHUNK_SIZE as u64) as usize;
+            buffer.resize(len, 0);
+            read_at(file, cmd.source + copied, &mut buffer)?;
+            write_at(file, cmd.target + copied, &buffer)?;
+            copied += len as u64;
+        }
+    }
+    file.set_len(output_size)?;
+    file.flush()
+}
@@ -0,0 +1,85 @@
+use crate::patchy::*;
+use serde::Serialize;
+
+// Human-readable view of a Patch. Offsets and sizes are hex strings so that they line up with
+// hex dumps of the data; `data` is the stored (possibly compressed) literal bytes.
+#[derive(Serialize)]
+struct CopyCmdView {
+    source: String,
+    target: String,
+    size: String,
+    source_id: u32,
+}
+
+#[derive(Serialize)]
+struct PatchView {
+    other_size: Option<String>,
+    block_size: String,
+    compression: Compression,
+    other_hash: Option<String>,
+    base_hash: Option<String>,
+    base: Vec<CopyCmdView>,
+    other: Vec<CopyCmdView>,
+    data_len: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+}
+
+fn to_hex(value: u64) -> String {
+    format!("{:#x}", value)
+}
+
+fn to_views(cmds: &[CopyCmd]) -> Vec<CopyCmdView> {
+    cmds.iter()
+        .map(|cmd| CopyCmdView {
+            source: to_hex(cmd.source),
+            target: to_hex(cmd.target),
+            size: to_hex(cmd.size as u64),
+            source_id: cmd.source_id,
+        })
+        .collect()
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Standard base64 with padding.
+fn to_base64(input: &[u8]) -> String {
+    let mut result = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let mut group = [0u8; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let bits = (group[0] as u32) << 16 | (group[1] as u32) << 8 | group[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+impl Patch {
+    // Pretty-printed JSON for inspection. The `data` field is omitted unless `include_data` is
+    // set; `data_len` is always present.
+    pub fn to_json(&self, include_data: bool) -> String {
+        let view = PatchView {
+            other_size: self.other_size.map(to_hex),
+            block_size: to_hex(self.block_size as u64),
+            compression: self.compression,
+            other_hash: self.other_hash.map(|hash| hash.to_hex_string()),
+            base_hash: self.base_hash.map(|hash| hash.to_hex_string()),
+            base: to_views(&self.base),
+            other: to_views(&self.other),
+            data_len: to_hex(self.data.len() as u64),
+            data: if include_data {
+                Some(to_base64(&self.data))
+            } else {
+                None
+            },
+        };
+        serde_json::to_string_pretty(&view).unwrap()
+    }
+}
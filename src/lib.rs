@@ -1,8 +1,85 @@
+// Without the default `std` feature only the hashing primitives in `hash` are available.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(all(feature = "alloc", not(feature = "std")))]
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod patchy;
+#[cfg(feature = "std")]
 pub use self::patchy::*;
 
+#[cfg(feature = "std")]
+pub mod patch_file;
+#[cfg(feature = "std")]
+pub use self::patch_file::*;
+
 pub mod hash;
 pub use self::hash::*;
 
-#[cfg(test)]
+#[cfg(feature = "std")]
+pub mod differ;
+#[cfg(feature = "std")]
+pub use self::differ::*;
+
+#[cfg(feature = "std")]
+mod compose;
+
+#[cfg(feature = "std")]
+pub mod flat;
+#[cfg(feature = "std")]
+pub use self::flat::*;
+
+#[cfg(feature = "std")]
+mod vcdiff;
+
+#[cfg(feature = "std")]
+pub mod cdc;
+#[cfg(feature = "std")]
+pub use self::cdc::*;
+
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "std")]
+pub use self::store::*;
+
+#[cfg(feature = "std")]
+pub mod signature_cache;
+#[cfg(feature = "std")]
+pub use self::signature_cache::*;
+
+#[cfg(feature = "std")]
+pub mod tree;
+#[cfg(feature = "std")]
+pub use self::tree::*;
+
+#[cfg(feature = "std")]
+pub mod in_place;
+#[cfg(feature = "std")]
+pub use self::in_place::*;
+
+#[cfg(feature = "suffix")]
+pub mod suffix;
+#[cfg(feature = "suffix")]
+pub use self::suffix::*;
+
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "mmap")]
+pub use self::mmap::*;
+
+#[cfg(feature = "signing")]
+pub mod signing;
+#[cfg(feature = "signing")]
+pub use self::signing::*;
+
+#[cfg(feature = "json")]
+mod json;
+
+#[cfg(feature = "bloom")]
+pub mod bloom;
+#[cfg(feature = "bloom")]
+pub use self::bloom::*;
+
+#[cfg(all(test, feature = "std"))]
 mod test;
@@ -1,9 +1,9 @@
 use anyhow::{anyhow, Context, Result};
 use clap::{App, AppSettings, Arg, SubCommand};
-use memmap::MmapOptions;
+use memmap2::MmapOptions;
 use patchy::hash::*;
+use patchy::patch_file::*;
 use patchy::patchy::*;
-use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::fs::File;
 use std::io::prelude::*;
@@ -28,17 +28,6 @@ fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
     zstd::stream::decode_all(data)
 }
 
-const PATCH_FILE_ID: [u8; 8] = *b"!patchy!";
-const PATCH_FILE_VERSION: u32 = 1;
-#[derive(Serialize, Deserialize)]
-struct PatchWithHeader {
-    id: [u8; 8],
-    version: u32,
-    base_hash: Hash128,
-    other_hash: Hash128,
-    patch: Patch,
-}
-
 fn hash_file(filename: &str) -> Result<()> {
     let file = File::open(filename).context("Can't open input file")?;
     let mmap = unsafe {
@@ -151,7 +140,7 @@ fn diff_files(
         patch_commands.other.len()
     );
 
-    let patch = build_patch(&other_mmap, &patch_commands);
+    let patch = build_patch_with_base(&base_mmap, &other_mmap, &patch_commands);
     println!("Patch commands: {}", patch.base.len() + patch.other.len());
 
     println!("Verifying patch");
@@ -175,15 +164,9 @@ fn diff_files(
     }
 
     println!("Serializing patch");
-    let patch_with_header = PatchWithHeader {
-        id: PATCH_FILE_ID,
-        version: PATCH_FILE_VERSION,
-        base_hash: compute_hash_strong(&base_mmap),
-        other_hash,
-        patch,
-    };
-    let patch_serialized: Vec<u8> =
-        bincode::serialize(&patch_with_header).context("Could not deserialize patch file")?;
+    let patch_with_header =
+        PatchWithHeader::new(compute_hash_strong(&base_mmap), other_hash, patch);
+    let patch_serialized: Vec<u8> = patch_with_header.serialize();
     println!(
         "Serialized uncompressed size: {:.2} MB",
         size_mb(patch_serialized.len())
@@ -225,17 +208,8 @@ fn patch_file(
             .context("Can't memory map patch file")?
     };
     let patch_decompressed = decompress(&patch_mmap).context("Could not decompress patch file")?;
-    let patch_with_header: PatchWithHeader =
-        bincode::deserialize(&patch_decompressed).context("Could not deserialize patch file")?;
-    if patch_with_header.id != PATCH_FILE_ID || patch_with_header.version != PATCH_FILE_VERSION {
-        return Err(anyhow!(
-            "Patch header is [{:?} v{}] but expected to be [{:?} v{}]",
-            patch_with_header.id,
-            patch_with_header.version,
-            PATCH_FILE_ID,
-            PATCH_FILE_VERSION
-        ));
-    }
+    let patch_with_header = PatchWithHeader::deserialize(&patch_decompressed)
+        .context("Could not deserialize patch file")?;
 
     println!("Verifying base file");
     let base_hash = compute_hash_strong(&base_mmap);
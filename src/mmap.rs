@@ -0,0 +1,90 @@
+use crate::patchy::*;
+use memmap2::{Mmap, MmapOptions};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Empty files can't be mapped, so they are represented by `None`.
+fn map_file(file: &File) -> io::Result<Option<Mmap>> {
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    let mmap = unsafe { MmapOptions::new().map(file)? };
+    Ok(Some(mmap))
+}
+
+fn mapped_slice(mmap: &Option<Mmap>) -> &[u8] {
+    match mmap {
+        Some(mmap) => mmap,
+        None => &[],
+    }
+}
+
+pub fn signature_from_path(path: impl AsRef<Path>, block_size: usize) -> io::Result<Vec<Block>> {
+    let file = File::open(path)?;
+    let mmap = map_file(&file)?;
+    try_compute_blocks(mapped_slice(&mmap), block_size)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+// Zeros in literals are skipped in chunks of this size, aligned to the output offset, so the
+// skipped ranges can become filesystem blocks that are never allocated.
+const SPARSE_CHUNK_SIZE: u64 = 4096;
+
+// Writes `bytes` at output offset `position`, seeking over chunks that are all zeros. `hole` is
+// the length of the seek still pending, which is only done before the next write.
+fn write_sparse(
+    out: &mut (impl Write + Seek),
+    bytes: &[u8],
+    position: u64,
+    hole: &mut u64,
+) -> io::Result<()> {
+    let mut begin: usize = 0;
+    while begin < bytes.len() {
+        let chunk_len = SPARSE_CHUNK_SIZE - (position + begin as u64) % SPARSE_CHUNK_SIZE;
+        let end = bytes.len().min(begin + chunk_len as usize);
+        let chunk = &bytes[begin..end];
+        if chunk_len as usize == chunk.len() && chunk.iter().all(|&x| x == 0) {
+            *hole += chunk.len() as u64;
+        } else {
+            if *hole != 0 {
+                out.seek(SeekFrom::Current(*hole as i64))?;
+                *hole = 0;
+            }
+            out.write_all(chunk)?;
+        }
+        begin = end;
+    }
+    Ok(())
+}
+
+// Runs of zeros in the patch literals are skipped rather than written, so the output file stays
+// sparse on filesystems that support holes.
+pub fn apply_patch_to_path(
+    base_path: impl AsRef<Path>,
+    patch: &Patch,
+    out_path: impl AsRef<Path>,
+) -> io::Result<()> {
+    let base_file = File::open(base_path)?;
+    let base_mmap = map_file(&base_file)?;
+    let mut out = BufWriter::new(File::create(out_path)?);
+    let mut position: u64 = 0;
+    let mut hole: u64 = 0;
+    write_patch_output(mapped_slice(&base_mmap), patch, |bytes, is_literal| {
+        if is_literal {
+            write_sparse(&mut out, bytes, position, &mut hole)?;
+        } else {
+            if hole != 0 {
+                out.seek(SeekFrom::Current(hole as i64))?;
+                hole = 0;
+            }
+            out.write_all(bytes)?;
+        }
+        position += bytes.len() as u64;
+        Ok(())
+    })?;
+    out.flush()?;
+    // A hole at the end only becomes part of the file by setting its length.
+    out.get_ref().set_len(patch.output_size())
+}
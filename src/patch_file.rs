@@ -0,0 +1,272 @@
+use crate::hash::*;
+use crate::patchy::*;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// Patch files of the `patchy` command line tool: a bincode `PatchWithHeader`, which the tool
+// compresses with zstd as a whole. bincode writes fields in order without names or defaults,
+// so every change to the layout of `Patch` or `CopyCmd` needs a new version, and the layouts
+// of earlier versions are kept below to read older files.
+pub const PATCH_FILE_ID: [u8; 8] = *b"!patchy!";
+pub const PATCH_FILE_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+pub struct PatchWithHeader {
+    pub id: [u8; 8],
+    pub version: u32,
+    pub base_hash: Hash128,
+    pub other_hash: Hash128,
+    pub patch: Patch,
+}
+
+// Leading fields of every version.
+#[derive(Deserialize)]
+struct PatchFileHeader {
+    id: [u8; 8],
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct PatchFileLayout<P> {
+    id: [u8; 8],
+    version: u32,
+    base_hash: Hash128,
+    other_hash: Hash128,
+    patch: P,
+}
+
+#[derive(Deserialize)]
+struct CopyCmdV1 {
+    source: u64,
+    target: u64,
+    size: u32,
+}
+
+impl From<CopyCmdV1> for CopyCmd {
+    fn from(cmd: CopyCmdV1) -> Self {
+        CopyCmd {
+            source: cmd.source,
+            target: cmd.target,
+            size: cmd.size,
+            source_id: 0,
+        }
+    }
+}
+
+fn convert_cmds<C: Into<CopyCmd>>(cmds: Vec<C>) -> Vec<CopyCmd> {
+    cmds.into_iter().map(Into::into).collect()
+}
+
+// Patch with the fields of version 1 and defaults for the later ones. Before `other_size` became
+// optional, an unknown size was written as `u64::MAX`.
+fn legacy_patch(data: Vec<u8>, base: Vec<CopyCmd>, other: Vec<CopyCmd>, other_size: u64) -> Patch {
+    Patch {
+        data,
+        base,
+        other,
+        other_size: Some(other_size).filter(|&size| size != u64::MAX),
+        compression: Compression::None,
+        other_hash: None,
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    }
+}
+
+#[derive(Deserialize)]
+struct PatchV1 {
+    data: Vec<u8>,
+    base: Vec<CopyCmdV1>,
+    other: Vec<CopyCmdV1>,
+    other_size: u64,
+}
+
+impl From<PatchV1> for Patch {
+    fn from(patch: PatchV1) -> Self {
+        legacy_patch(
+            patch.data,
+            convert_cmds(patch.base),
+            convert_cmds(patch.other),
+            patch.other_size,
+        )
+    }
+}
+
+// Version 2 added the compression of `data`. Fields added later, up to the layout of version 3,
+// were written with the same version, so each of these layouts is tried in turn, newest first.
+#[derive(Deserialize)]
+struct PatchV2 {
+    data: Vec<u8>,
+    base: Vec<CopyCmdV1>,
+    other: Vec<CopyCmdV1>,
+    other_size: u64,
+    compression: Compression,
+}
+
+impl From<PatchV2> for Patch {
+    fn from(patch: PatchV2) -> Self {
+        Patch {
+            compression: patch.compression,
+            ..legacy_patch(
+                patch.data,
+                convert_cmds(patch.base),
+                convert_cmds(patch.other),
+                patch.other_size,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PatchV2OtherHash {
+    data: Vec<u8>,
+    base: Vec<CopyCmdV1>,
+    other: Vec<CopyCmdV1>,
+    other_size: u64,
+    compression: Compression,
+    other_hash: Option<Hash128>,
+}
+
+impl From<PatchV2OtherHash> for Patch {
+    fn from(patch: PatchV2OtherHash) -> Self {
+        Patch {
+            compression: patch.compression,
+            other_hash: patch.other_hash,
+            ..legacy_patch(
+                patch.data,
+                convert_cmds(patch.base),
+                convert_cmds(patch.other),
+                patch.other_size,
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PatchV2SourceId {
+    data: Vec<u8>,
+    base: Vec<CopyCmd>,
+    other: Vec<CopyCmd>,
+    other_size: u64,
+    compression: Compression,
+    other_hash: Option<Hash128>,
+}
+
+impl From<PatchV2SourceId> for Patch {
+    fn from(patch: PatchV2SourceId) -> Self {
+        Patch {
+            compression: patch.compression,
+            other_hash: patch.other_hash,
+            ..legacy_patch(patch.data, patch.base, patch.other, patch.other_size)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PatchV2BlockSize {
+    data: Vec<u8>,
+    base: Vec<CopyCmd>,
+    other: Vec<CopyCmd>,
+    other_size: u64,
+    compression: Compression,
+    other_hash: Option<Hash128>,
+    block_size: usize,
+}
+
+impl From<PatchV2BlockSize> for Patch {
+    fn from(patch: PatchV2BlockSize) -> Self {
+        Patch {
+            compression: patch.compression,
+            other_hash: patch.other_hash,
+            block_size: patch.block_size,
+            ..legacy_patch(patch.data, patch.base, patch.other, patch.other_size)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PatchV2BaseHash {
+    data: Vec<u8>,
+    base: Vec<CopyCmd>,
+    other: Vec<CopyCmd>,
+    other_size: u64,
+    compression: Compression,
+    other_hash: Option<Hash128>,
+    base_hash: Option<Hash128>,
+    block_size: usize,
+}
+
+impl From<PatchV2BaseHash> for Patch {
+    fn from(patch: PatchV2BaseHash) -> Self {
+        Patch {
+            compression: patch.compression,
+            other_hash: patch.other_hash,
+            base_hash: patch.base_hash,
+            block_size: patch.block_size,
+            ..legacy_patch(patch.data, patch.base, patch.other, patch.other_size)
+        }
+    }
+}
+
+fn serialization_error(e: bincode::Error) -> PatchError {
+    PatchError::Serialization(e.to_string())
+}
+
+// Reads the whole of `bytes` as a file with patch layout `P`.
+fn decode_layout<P: DeserializeOwned + Into<Patch>>(
+    bytes: &[u8],
+) -> Result<PatchWithHeader, PatchError> {
+    let file: PatchFileLayout<P> = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(bytes)
+        .map_err(serialization_error)?;
+    Ok(PatchWithHeader {
+        id: file.id,
+        version: file.version,
+        base_hash: file.base_hash,
+        other_hash: file.other_hash,
+        patch: file.patch.into(),
+    })
+}
+
+impl PatchWithHeader {
+    pub fn new(base_hash: Hash128, other_hash: Hash128, patch: Patch) -> Self {
+        PatchWithHeader {
+            id: PATCH_FILE_ID,
+            version: PATCH_FILE_VERSION,
+            base_hash,
+            other_hash,
+            patch,
+        }
+    }
+    // Uncompressed file contents.
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+    // Reads files of every version. `version` of the result is the one of the file, while its
+    // patch is converted to the current layout.
+    pub fn deserialize(bytes: &[u8]) -> Result<PatchWithHeader, PatchError> {
+        let header: PatchFileHeader = bincode::deserialize(bytes).map_err(serialization_error)?;
+        if header.id != PATCH_FILE_ID {
+            return Err(PatchError::Serialization(format!(
+                "patch file id is {:?} but expected to be {:?}",
+                header.id, PATCH_FILE_ID
+            )));
+        }
+        match header.version {
+            1 => decode_layout::<PatchV1>(bytes),
+            2 => decode_layout::<Patch>(bytes)
+                .or_else(|_| decode_layout::<PatchV2BaseHash>(bytes))
+                .or_else(|_| decode_layout::<PatchV2BlockSize>(bytes))
+                .or_else(|_| decode_layout::<PatchV2SourceId>(bytes))
+                .or_else(|_| decode_layout::<PatchV2OtherHash>(bytes))
+                .or_else(|_| decode_layout::<PatchV2>(bytes)),
+            PATCH_FILE_VERSION => decode_layout::<Patch>(bytes),
+            version => Err(PatchError::Serialization(format!(
+                "patch file version {} is not supported, expected at most {}",
+                version, PATCH_FILE_VERSION
+            ))),
+        }
+    }
+}
@@ -1,73 +1,827 @@
 use crate::hash::*;
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::cmp::min;
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::cmp::{min, Reverse};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::BuildHasherDefault;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 
 pub const DEFAULT_BLOCK_SIZE: usize = 2048;
 
-fn div_up(num: usize, den: usize) -> usize {
-    (num + den - 1) / den
+// Fixed-key hashing, so that nothing in a diff depends on per-process random state.
+pub(crate) type DeterministicState = BuildHasherDefault<DefaultHasher>;
+pub(crate) type HashMap<K, V> = std::collections::HashMap<K, V, DeterministicState>;
+pub(crate) type HashSet<K> = std::collections::HashSet<K, DeterministicState>;
+
+pub(crate) fn div_up(num: usize, den: usize) -> usize {
+    num / den + min(num % den, 1)
+}
+
+// Offsets and sizes are stored as u64 but may not be addressable on 32-bit targets.
+pub(crate) fn checked_usize(value: u64) -> Result<usize, PatchError> {
+    usize::try_from(value).map_err(|_| PatchError::SizeOverflow { size: value })
 }
 
-fn slice_offset_from(slice: &[u8], base: &[u8]) -> u64 {
-    slice.as_ptr() as u64 - base.as_ptr() as u64
+// For lengths reported in errors, where the exact value past usize::MAX doesn't matter.
+pub(crate) fn saturating_usize(value: u64) -> usize {
+    usize::try_from(value).unwrap_or(usize::MAX)
 }
 
-pub struct Block {
+#[derive(Serialize, Deserialize)]
+pub struct Block<H = Hash128> {
     pub offset: u64,
     pub size: u32,
     pub hash_weak: u32,
-    pub hash_strong: Hash128,
+    pub hash_strong: H,
+}
+
+pub(crate) fn check_block_size(block_size: usize) -> Result<(), PatchError> {
+    if block_size == 0 || block_size > u32::MAX as usize {
+        return Err(PatchError::InvalidBlockSize { block_size });
+    }
+    Ok(())
+}
+
+// Blocks from a signature computed with a different block size would never match the rolling
+// window, so catch that up front. Only the first block is checked: it is a full block unless it
+// is the only one.
+fn check_other_blocks<H: BlockHash>(
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> Result<(), PatchError> {
+    if let Some(first) = other_blocks.first() {
+        let size = first.size as usize;
+        if size > block_size || (other_blocks.len() > 1 && size != block_size) {
+            return Err(PatchError::BlockSizeMismatch {
+                expected: block_size,
+                actual: size,
+            });
+        }
+    }
+    Ok(())
+}
+
+pub const MIN_RECOMMENDED_BLOCK_SIZE: usize = 512;
+pub const MAX_RECOMMENDED_BLOCK_SIZE: usize = 128 * 1024;
+
+// Like rsync: the square root of the input length, rounded down to a multiple of 64 and clamped
+// to [MIN_RECOMMENDED_BLOCK_SIZE, MAX_RECOMMENDED_BLOCK_SIZE]. This balances the number of
+// blocks (signature size) against the granularity of matches. A 4 MiB input gets the default
+// 2048-byte block.
+pub fn recommended_block_size(input_len: usize) -> usize {
+    let sqrt = (input_len as f64).sqrt() as usize;
+    let rounded = sqrt / 64 * 64;
+    rounded.clamp(MIN_RECOMMENDED_BLOCK_SIZE, MAX_RECOMMENDED_BLOCK_SIZE)
 }
 
 pub fn compute_blocks(input: &[u8], block_size: usize) -> Vec<Block> {
+    try_compute_blocks(input, block_size).unwrap()
+}
+
+pub fn try_compute_blocks(input: &[u8], block_size: usize) -> Result<Vec<Block>, PatchError> {
+    try_compute_blocks_generic(input, block_size)
+}
+
+// Single-threaded variant of `compute_blocks`, with identical output.
+pub fn compute_blocks_seq(input: &[u8], block_size: usize) -> Vec<Block> {
+    try_compute_blocks_impl::<Hash128, RollingHash>(input, block_size, &Blake3Hasher, false)
+        .unwrap()
+}
+
+pub fn compute_blocks_generic<H: BlockHash>(input: &[u8], block_size: usize) -> Vec<Block<H>> {
+    try_compute_blocks_generic(input, block_size).unwrap()
+}
+
+pub fn try_compute_blocks_generic<H: BlockHash>(
+    input: &[u8],
+    block_size: usize,
+) -> Result<Vec<Block<H>>, PatchError> {
+    try_compute_blocks_with::<H, RollingHash>(input, block_size)
+}
+
+// Signature with a chosen weak hash. Diffs against it must use the same `W`.
+pub fn try_compute_blocks_with<H: BlockHash, W: WeakHash>(
+    input: &[u8],
+    block_size: usize,
+) -> Result<Vec<Block<H>>, PatchError> {
+    try_compute_blocks_impl::<H, W>(input, block_size, &Blake3Hasher, true)
+}
+
+// Signature with a chosen strong hash function. Diffs against it must use the same hasher,
+// e.g. with `compute_diff_with_hasher`.
+pub fn compute_blocks_with_hasher<H: BlockHash>(
+    input: &[u8],
+    block_size: usize,
+    hasher: &impl StrongHasher<H>,
+) -> Vec<Block<H>> {
+    try_compute_blocks_with_hasher(input, block_size, hasher).unwrap()
+}
+
+pub fn try_compute_blocks_with_hasher<H: BlockHash>(
+    input: &[u8],
+    block_size: usize,
+    hasher: &impl StrongHasher<H>,
+) -> Result<Vec<Block<H>>, PatchError> {
+    try_compute_blocks_impl::<H, RollingHash>(input, block_size, hasher, true)
+}
+
+// Below this input size, hashing on the rayon thread pool costs more than it saves.
+#[cfg(feature = "parallel")]
+const PARALLEL_HASHING_THRESHOLD: usize = 256 * 1024;
+
+fn try_compute_blocks_impl<H: BlockHash, W: WeakHash>(
+    input: &[u8],
+    block_size: usize,
+    hasher: &impl StrongHasher<H>,
+    parallel: bool,
+) -> Result<Vec<Block<H>>, PatchError> {
+    check_block_size(block_size)?;
     let chunks = input.chunks(block_size);
-    let mut result: Vec<Block> = Vec::with_capacity(chunks.len());
+    let mut result: Vec<Block<H>> = Vec::with_capacity(chunks.len());
+    let mut offset: u64 = 0;
     for chunk in chunks {
         result.push(Block {
-            offset: slice_offset_from(chunk, input),
+            offset,
             size: chunk.len() as u32,
             hash_weak: 0,
-            hash_strong: Hash128::new_zero(),
+            hash_strong: H::zero(),
+        });
+        offset += chunk.len() as u64;
+    }
+    hash_blocks::<H, W>(input, &mut result, hasher, parallel);
+    Ok(result)
+}
+
+// Weak hashes of the blocks `compute_blocks` would produce, without the strong hashes, e.g. to
+// measure weak hash collisions.
+pub fn weak_hashes(input: &[u8], block_size: usize) -> Vec<u32> {
+    weak_hashes_with::<RollingHash>(input, block_size)
+}
+
+pub fn weak_hashes_with<W: WeakHash>(input: &[u8], block_size: usize) -> Vec<u32> {
+    check_block_size(block_size).unwrap();
+    #[cfg(feature = "parallel")]
+    {
+        if input.len() >= PARALLEL_HASHING_THRESHOLD {
+            return input.par_chunks(block_size).map(W::compute).collect();
+        }
+    }
+    input.chunks(block_size).map(W::compute).collect()
+}
+
+// Brings a signature from `compute_blocks` up to date with `input` after an edit, hashing
+// only what the edit touched. `changed` is the range of `input` that differs from the old data:
+// the bytes before it are unchanged and the bytes after it are the old tail, moved by the
+// change in length. An append is `old_len..input.len()`, a deletion an empty range.
+// Blocks past the edit are kept, with their offsets moved, if the length changed by a multiple
+// of `block_size`. Otherwise their boundaries no longer line up with the old ones, and every
+// block from the edit to the end is hashed again.
+pub fn update_signature<H: BlockHash>(
+    blocks: &mut Vec<Block<H>>,
+    input: &[u8],
+    changed: Range<usize>,
+    block_size: usize,
+) {
+    try_update_signature(blocks, input, changed, block_size).unwrap()
+}
+
+pub fn try_update_signature<H: BlockHash>(
+    blocks: &mut Vec<Block<H>>,
+    input: &[u8],
+    changed: Range<usize>,
+    block_size: usize,
+) -> Result<(), PatchError> {
+    check_block_size(block_size)?;
+    check_other_blocks(blocks, block_size)?;
+    assert!(
+        changed.start <= changed.end && changed.end <= input.len(),
+        "changed range is outside of input"
+    );
+    let old_len: u64 = blocks.iter().map(|block| block.size as u64).sum();
+    let new_len = input.len() as u64;
+    let tail_len = new_len - changed.end as u64;
+    if changed.start as u64 + tail_len > old_len {
+        return Err(PatchError::LengthMismatch {
+            expected: old_len,
+            actual: changed.start as u64 + tail_len,
+        });
+    }
+    let first = changed.start / block_size;
+    let kept_begin = if old_len % block_size as u64 == new_len % block_size as u64 {
+        let old_tail_begin = old_len - tail_len;
+        blocks.partition_point(|block| block.offset < old_tail_begin)
+    } else {
+        blocks.len()
+    };
+    let mut kept = blocks.split_off(kept_begin);
+    for block in &mut kept {
+        block.offset = block.offset + new_len - old_len;
+    }
+    let rehash_end = kept.first().map_or(new_len, |block| block.offset);
+    blocks.truncate(first);
+    let rehash_begin = blocks.len();
+    let mut offset = (first * block_size) as u64;
+    while offset < rehash_end {
+        let size = (rehash_end - offset).min(block_size as u64) as u32;
+        blocks.push(Block {
+            offset,
+            size,
+            hash_weak: 0,
+            hash_strong: H::zero(),
         });
+        offset += size as u64;
     }
-    result.par_iter_mut().for_each(|block| {
+    hash_blocks::<H, RollingHash>(input, &mut blocks[rehash_begin..], &Blake3Hasher, true);
+    blocks.append(&mut kept);
+    Ok(())
+}
+
+// Fills in the hashes of blocks whose offset and size are already set.
+pub(crate) fn hash_blocks<H: BlockHash, W: WeakHash>(
+    input: &[u8],
+    blocks: &mut [Block<H>],
+    hasher: &impl StrongHasher<H>,
+    parallel: bool,
+) {
+    let hash_block = |block: &mut Block<H>| {
         let block_begin = block.offset as usize;
         let block_end = block_begin + block.size as usize;
         let block_slice = &input[block_begin..block_end];
-        block.hash_weak = compute_hash_weak(block_slice);
-        block.hash_strong = compute_hash_strong(block_slice);
-    });
+        block.hash_weak = W::compute(block_slice);
+        block.hash_strong = hasher.hash(block_slice);
+    };
+    #[cfg(feature = "parallel")]
+    {
+        if parallel && input.len() >= PARALLEL_HASHING_THRESHOLD {
+            blocks.par_iter_mut().for_each(hash_block);
+            return;
+        }
+    }
+    #[cfg(not(feature = "parallel"))]
+    let _ = parallel;
+    blocks.iter_mut().for_each(hash_block);
+}
+
+// `(offset, weak hash)` of the `window`-byte windows at offsets 0, `step`, 2 * `step`, ... that
+// fit in `input`. Overlapping windows are rolled one byte at a time; with `step >= window` no
+// byte is shared, so each window is hashed from scratch.
+pub fn compute_rolling_hashes(input: &[u8], window: usize, step: usize) -> Vec<(u64, u32)> {
+    assert!(window > 0, "rolling hash window must be non-zero");
+    assert!(step > 0, "rolling hash step must be non-zero");
+    if input.len() < window {
+        return Vec::new();
+    }
+    let last = input.len() - window;
+    let mut result: Vec<(u64, u32)> = Vec::with_capacity(last / step + 1);
+    if step >= window {
+        for offset in (0..=last).step_by(step) {
+            let hash_weak = compute_hash_weak(&input[offset..offset + window]);
+            result.push((offset as u64, hash_weak));
+        }
+        return result;
+    }
+    let mut rolling_hash = RollingHash::new();
+    rolling_hash.update(&input[..window]);
+    result.push((0, rolling_hash.get()));
+    for offset in 1..=last {
+        rolling_hash.roll(input[offset - 1], input[offset + window - 1]);
+        if offset % step == 0 {
+            result.push((offset as u64, rolling_hash.get()));
+        }
+    }
+    result
+}
+
+const SIGNATURE_MAGIC: [u8; 4] = *b"PSIG";
+const SIGNATURE_VERSION: u8 = 2;
+
+#[derive(Serialize, Deserialize)]
+pub struct Signature<H = Hash128> {
+    pub block_size: usize,
+    pub blocks: Vec<Block<H>>,
+}
+
+// Layout: magic (4 bytes), format version (1 byte), strong hash size (1 byte), bincode body.
+impl<H: BlockHash> Signature<H> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::new();
+        result.extend_from_slice(&SIGNATURE_MAGIC);
+        result.push(SIGNATURE_VERSION);
+        result.push(H::SIZE as u8);
+        bincode::serialize_into(&mut result, self).unwrap();
+        result
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PatchError> {
+        let header_len = SIGNATURE_MAGIC.len() + 2;
+        if bytes.len() < header_len || bytes[0..SIGNATURE_MAGIC.len()] != SIGNATURE_MAGIC {
+            return Err(PatchError::BadMagic);
+        }
+        let version = bytes[SIGNATURE_MAGIC.len()];
+        if version != SIGNATURE_VERSION {
+            return Err(PatchError::UnsupportedVersion {
+                version: version as u32,
+            });
+        }
+        let hash_size = bytes[SIGNATURE_MAGIC.len() + 1] as usize;
+        if hash_size != H::SIZE {
+            return Err(PatchError::HashSizeMismatch {
+                expected: H::SIZE,
+                actual: hash_size,
+            });
+        }
+        let signature: Self = bincode::deserialize(&bytes[header_len..])
+            .map_err(|e| PatchError::Serialization(e.to_string()))?;
+        check_block_size(signature.block_size)?;
+        Ok(signature)
+    }
+}
+
+// Block-level comparison of two signatures. Hash lists are deduplicated and keep the order of
+// first appearance; `divergent_positions` are block indices where the two sequences differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureDiff<H = Hash128> {
+    pub added: Vec<H>,
+    pub removed: Vec<H>,
+    pub common: Vec<H>,
+    pub divergent_positions: Vec<usize>,
+    pub other_blocks_changed: usize,
+    pub other_blocks_total: usize,
+}
+
+impl<H> SignatureDiff<H> {
+    // Fraction of `other` blocks whose content doesn't appear anywhere in `base`.
+    pub fn changed_fraction(&self) -> f64 {
+        if self.other_blocks_total == 0 {
+            return 0.0;
+        }
+        self.other_blocks_changed as f64 / self.other_blocks_total as f64
+    }
+}
+
+pub fn diff_signatures<H: BlockHash>(base: &[Block<H>], other: &[Block<H>]) -> SignatureDiff<H> {
+    let base_set: HashSet<H> = base.iter().map(|block| block.hash_strong).collect();
+    let other_set: HashSet<H> = other.iter().map(|block| block.hash_strong).collect();
+    let mut seen: HashSet<H> = HashSet::default();
+    let mut result = SignatureDiff {
+        added: Vec::new(),
+        removed: Vec::new(),
+        common: Vec::new(),
+        divergent_positions: Vec::new(),
+        other_blocks_changed: 0,
+        other_blocks_total: other.len(),
+    };
+    for block in other {
+        let in_base = base_set.contains(&block.hash_strong);
+        if !in_base {
+            result.other_blocks_changed += 1;
+        }
+        if seen.insert(block.hash_strong) {
+            if in_base {
+                result.common.push(block.hash_strong);
+            } else {
+                result.added.push(block.hash_strong);
+            }
+        }
+    }
+    for block in base {
+        if !other_set.contains(&block.hash_strong) && seen.insert(block.hash_strong) {
+            result.removed.push(block.hash_strong);
+        }
+    }
+    for i in 0..base.len().max(other.len()) {
+        let base_hash = base.get(i).map(|block| block.hash_strong);
+        let other_hash = other.get(i).map(|block| block.hash_strong);
+        if base_hash != other_hash {
+            result.divergent_positions.push(i);
+        }
+    }
     result
 }
 
+pub struct SignatureBuilder {
+    block_size: usize,
+    buffer: Vec<u8>,
+    offset: u64,
+    blocks: Vec<Block>,
+}
+
+impl SignatureBuilder {
+    pub fn new(block_size: usize) -> Self {
+        check_block_size(block_size).unwrap();
+        Self {
+            block_size,
+            buffer: Vec::with_capacity(block_size),
+            offset: 0,
+            blocks: Vec::new(),
+        }
+    }
+    pub fn update(&mut self, reader: &mut impl Read) -> io::Result<()> {
+        loop {
+            let filled = self.buffer.len();
+            self.buffer.resize(self.block_size, 0);
+            let read_result = reader.read(&mut self.buffer[filled..]);
+            let read_len = match read_result {
+                Ok(read_len) => read_len,
+                Err(e) => {
+                    self.buffer.truncate(filled);
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+            self.buffer.truncate(filled + read_len);
+            if read_len == 0 {
+                return Ok(());
+            }
+            if self.buffer.len() == self.block_size {
+                self.push_block();
+            }
+        }
+    }
+    pub fn finalize(mut self) -> Vec<Block> {
+        if !self.buffer.is_empty() {
+            self.push_block();
+        }
+        self.blocks
+    }
+    fn push_block(&mut self) {
+        self.blocks.push(Block {
+            offset: self.offset,
+            size: self.buffer.len() as u32,
+            hash_weak: compute_hash_weak(&self.buffer),
+            hash_strong: compute_hash_strong(&self.buffer),
+        });
+        self.offset += self.buffer.len() as u64;
+        self.buffer.clear();
+    }
+}
+
+// `source_id` of a command that reads from the output written so far instead of a base.
+pub const SELF_SOURCE_ID: u32 = u32::MAX;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CopyCmd {
     pub source: u64,
     pub target: u64,
     pub size: u32,
+    pub source_id: u32,
 }
 
 impl CopyCmd {
     pub fn execute(&self, target: &mut [u8], source: &[u8]) {
-        let source_bounds = (
-            self.source as usize,
-            self.source as usize + self.size as usize,
-        );
-        let target_bounds = (
-            self.target as usize,
-            self.target as usize + self.size as usize,
-        );
+        let source_begin = checked_usize(self.source).unwrap();
+        let target_begin = checked_usize(self.target).unwrap();
+        let source_bounds = (source_begin, source_begin + self.size as usize);
+        let target_bounds = (target_begin, target_begin + self.size as usize);
         let source_slice = &source[source_bounds.0..source_bounds.1];
         let target_slice = target[target_bounds.0..target_bounds.1].as_mut();
         target_slice.copy_from_slice(source_slice);
     }
+    pub fn execute_checked(&self, target: &mut [u8], source: &[u8]) -> Result<(), PatchError> {
+        self.check_bounds(target.len(), source.len())?;
+        self.execute(target, source);
+        Ok(())
+    }
+    pub fn is_self_ref(&self) -> bool {
+        self.source_id == SELF_SOURCE_ID
+    }
+    // Bytes `begin..end` of the command, relative to its start.
+    fn slice(&self, begin: u64, end: u64) -> CopyCmd {
+        CopyCmd {
+            source: self.source + begin,
+            target: self.target + begin,
+            size: (end - begin) as u32,
+            source_id: self.source_id,
+        }
+    }
+    // Copies forward byte by byte from earlier output, so the source range may overlap the
+    // target range (LZ-style). The source must start before the target.
+    pub fn execute_self_checked(&self, output: &mut [u8]) -> Result<(), PatchError> {
+        if self.size == 0 {
+            return Ok(());
+        }
+        if self.source >= self.target {
+            return Err(PatchError::SourceOutOfRange {
+                offset: self.source,
+                size: self.size,
+                len: saturating_usize(self.target),
+            });
+        }
+        self.check_bounds(output.len(), output.len())?;
+        let distance = (self.target - self.source) as usize;
+        let mut copied: usize = 0;
+        while copied < self.size as usize {
+            let chunk_size = min(self.size as usize - copied, distance);
+            let chunk_source = self.source as usize + copied;
+            let chunk_target = self.target as usize + copied;
+            output.copy_within(chunk_source..chunk_source + chunk_size, chunk_target);
+            copied += chunk_size;
+        }
+        Ok(())
+    }
+    // Ranges are compared in u64 so that nothing is truncated on 32-bit targets.
+    pub(crate) fn check_bounds(
+        &self,
+        target_len: usize,
+        source_len: usize,
+    ) -> Result<(), PatchError> {
+        let range_fits = |offset: u64, len: usize| match offset.checked_add(self.size as u64) {
+            Some(end) => end <= len as u64,
+            None => false,
+        };
+        if !range_fits(self.source, source_len) {
+            return Err(PatchError::SourceOutOfRange {
+                offset: self.source,
+                size: self.size,
+                len: source_len,
+            });
+        }
+        if !range_fits(self.target, target_len) {
+            return Err(PatchError::TargetOutOfRange {
+                offset: self.target,
+                size: self.size,
+                len: target_len,
+            });
+        }
+        Ok(())
+    }
+}
+
+// A copy of any size. It is lowered into `CopyCmd`s of at most `u32::MAX` bytes each, so the
+// serialized format is unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogicalCopy {
+    pub source: u64,
+    pub target: u64,
+    pub size: u64,
+}
+
+impl LogicalCopy {
+    pub fn lower_into(&self, cmds: &mut Vec<CopyCmd>, source_id: u32) {
+        let mut offset: u64 = 0;
+        while offset < self.size {
+            let chunk_size = min(self.size - offset, u32::MAX as u64);
+            cmds.push(CopyCmd {
+                source: self.source + offset,
+                target: self.target + offset,
+                size: chunk_size as u32,
+                source_id,
+            });
+            offset += chunk_size;
+        }
+    }
+    // Reassembles commands with the same `source_id` that are contiguous in both source and
+    // target space. Commands are taken in the given order.
+    pub fn from_copy_cmds(cmds: &[CopyCmd]) -> Vec<LogicalCopy> {
+        let mut result: Vec<LogicalCopy> = Vec::new();
+        let mut last_source_id: u32 = 0;
+        for cmd in cmds.iter().filter(|cmd| cmd.size != 0) {
+            if let Some(last) = result.last_mut() {
+                if last_source_id == cmd.source_id
+                    && last.source + last.size == cmd.source
+                    && last.target + last.size == cmd.target
+                {
+                    last.size += cmd.size as u64;
+                    continue;
+                }
+            }
+            last_source_id = cmd.source_id;
+            result.push(LogicalCopy {
+                source: cmd.source,
+                target: cmd.target,
+                size: cmd.size as u64,
+            });
+        }
+        result
+    }
+}
+
+// The command list of a `Patch` that a command is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandList {
+    Base,
+    Other,
+}
+
+impl fmt::Display for CommandList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandList::Base => write!(f, "base"),
+            CommandList::Other => write!(f, "other"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    SourceOutOfRange {
+        offset: u64,
+        size: u32,
+        len: usize,
+    },
+    TargetOutOfRange {
+        offset: u64,
+        size: u32,
+        len: usize,
+    },
+    // A command of a patch being applied reads or writes out of range. `index` is its position
+    // in `list`; `source_len` is the length of the data it reads and `target_len` the output size.
+    OutOfRange {
+        list: CommandList,
+        index: usize,
+        source: u64,
+        target: u64,
+        size: u32,
+        source_len: usize,
+        target_len: usize,
+    },
+    SizeOverflow {
+        size: u64,
+    },
+    InvalidBlockSize {
+        block_size: usize,
+    },
+    BlockSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    BadMagic,
+    UnsupportedVersion {
+        version: u32,
+    },
+    Serialization(String),
+    Compression(String),
+    HashSizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    HashMismatch {
+        expected: Hash128,
+        actual: Hash128,
+    },
+    InvalidSourceId {
+        source_id: u32,
+    },
+    CoverageGap {
+        offset: u64,
+    },
+    CoverageOverlap {
+        offset: u64,
+    },
+    LengthMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    BadSignature,
+    InvalidHex {
+        offset: usize,
+    },
+    MissingChunk {
+        hash: Hash128,
+    },
+    MissingFile {
+        path: String,
+    },
+    // A tree entry path that is absolute or leaves the tree, e.g. through `..`.
+    UnsafePath {
+        path: String,
+    },
+    WrongBase {
+        expected: Hash128,
+        actual: Hash128,
+    },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::SourceOutOfRange { offset, size, len } => write!(
+                f,
+                "Copy source range [{}..{}] is outside of source data ({} bytes)",
+                offset,
+                *offset as u128 + *size as u128,
+                len
+            ),
+            PatchError::TargetOutOfRange { offset, size, len } => write!(
+                f,
+                "Copy target range [{}..{}] is outside of output data ({} bytes)",
+                offset,
+                *offset as u128 + *size as u128,
+                len
+            ),
+            PatchError::OutOfRange {
+                list,
+                index,
+                source,
+                target,
+                size,
+                source_len,
+                target_len,
+            } => write!(
+                f,
+                "Command {} of the {} list copies [{}..{}] to [{}..{}], but the source has {} \
+                 bytes and the output {}",
+                index,
+                list,
+                source,
+                *source as u128 + *size as u128,
+                target,
+                *target as u128 + *size as u128,
+                source_len,
+                target_len
+            ),
+            PatchError::SizeOverflow { size } => {
+                write!(f, "Size {} does not fit into addressable memory", size)
+            }
+            PatchError::InvalidBlockSize { block_size } => write!(
+                f,
+                "Block size {} is outside of supported range [1..{}]",
+                block_size,
+                u32::MAX
+            ),
+            PatchError::BlockSizeMismatch { expected, actual } => write!(
+                f,
+                "Block size is {} but expected to be {}",
+                actual, expected
+            ),
+            PatchError::BadMagic => write!(f, "Data does not start with the expected magic"),
+            PatchError::UnsupportedVersion { version } => {
+                write!(f, "Format version {} is not supported", version)
+            }
+            PatchError::Serialization(reason) => write!(f, "Serialization failed: {}", reason),
+            PatchError::Compression(reason) => write!(f, "Compression failed: {}", reason),
+            PatchError::HashSizeMismatch { expected, actual } => write!(
+                f,
+                "Strong hash size is {} bytes but expected to be {}",
+                actual, expected
+            ),
+            PatchError::HashMismatch { expected, actual } => write!(
+                f,
+                "Patched data hash is {:?} but expected to be {:?}",
+                actual, expected
+            ),
+            PatchError::InvalidSourceId { source_id } => {
+                write!(f, "Copy command references unknown base {}", source_id)
+            }
+            PatchError::CoverageGap { offset } => {
+                write!(f, "Output byte {} is not written by any command", offset)
+            }
+            PatchError::CoverageOverlap { offset } => {
+                write!(
+                    f,
+                    "Output byte {} is written by more than one command",
+                    offset
+                )
+            }
+            PatchError::LengthMismatch { expected, actual } => write!(
+                f,
+                "Data length is {} bytes but expected to be {}",
+                actual, expected
+            ),
+            PatchError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum is {:08x} but expected to be {:08x}",
+                actual, expected
+            ),
+            PatchError::BadSignature => write!(f, "Signature verification failed"),
+            PatchError::InvalidHex { offset } => {
+                write!(f, "Invalid hex digit at offset {}", offset)
+            }
+            PatchError::MissingChunk { hash } => write!(f, "Chunk {:?} is not in the store", hash),
+            PatchError::MissingFile { path } => write!(f, "File {} is not in the base tree", path),
+            PatchError::UnsafePath { path } => write!(f, "Path {} is outside of the tree", path),
+            PatchError::WrongBase { expected, actual } => write!(
+                f,
+                "Base data hash is {:?} but the patch was built against {:?}",
+                actual, expected
+            ),
+        }
+    }
 }
 
+impl std::error::Error for PatchError {}
+
+// Container header and CRC32, other_size, block_size, the longest compression settings, both
+// optional hashes, the two command counts and the data length.
+const PATCH_MAX_ENCODED_OVERHEAD: usize =
+    CONTAINER_HEADER_LEN + 4 + (1 + 8) + 8 + (1 + 4 + 4) + 2 * 17 + 2 * 10 + 8;
+
 pub struct PatchCommands {
     pub base: Vec<CopyCmd>,
     pub other: Vec<CopyCmd>,
+    pub block_size: usize,
 }
 
 fn compute_copy_size(cmds: &[CopyCmd]) -> usize {
@@ -80,9 +834,13 @@ fn compute_copy_size(cmds: &[CopyCmd]) -> usize {
 
 impl PatchCommands {
     pub fn new() -> Self {
+        Self::with_block_size(DEFAULT_BLOCK_SIZE)
+    }
+    pub fn with_block_size(block_size: usize) -> Self {
         Self {
             base: Vec::new(),
             other: Vec::new(),
+            block_size,
         }
     }
     pub fn need_bytes_from_base(&self) -> usize {
@@ -94,168 +852,3017 @@ impl PatchCommands {
     pub fn is_synchronized(&self) -> bool {
         self.base.is_empty() && self.other.is_empty()
     }
+    // Trims commands of both lists so that each output byte is written by exactly one of them,
+    // see `resolve_overlaps`. At equal targets, `other` commands come after `base` ones and win.
+    pub fn dedupe_overlaps(&mut self) {
+        let mut cmds: Vec<(CopyCmd, bool)> = self
+            .base
+            .drain(..)
+            .map(|cmd| (cmd, false))
+            .chain(self.other.drain(..).map(|cmd| (cmd, true)))
+            .filter(|(cmd, _)| cmd.size != 0)
+            .collect();
+        cmds.sort_by_key(|(cmd, is_other)| (cmd.target, *is_other, cmd.source_id, cmd.source));
+        resolve_overlaps(&mut cmds);
+        for (cmd, is_other) in cmds {
+            if is_other {
+                self.other.push(cmd);
+            } else {
+                self.base.push(cmd);
+            }
+        }
+    }
+    pub fn push_base_copy(&mut self, copy: LogicalCopy) {
+        copy.lower_into(&mut self.base, 0);
+    }
+    // Takes `size` bytes at `target` from `other`.
+    pub fn push_literal(&mut self, target: u64, size: u64) {
+        let copy = LogicalCopy {
+            source: target,
+            target,
+            size,
+        };
+        copy.lower_into(&mut self.other, 0);
+    }
+    // Synchronized commands mean "identical" and carry no copies. Replaces them with one base copy
+    // of `len` bytes so a patch built from them reproduces the data on its own.
+    pub(crate) fn expand_synchronized(&mut self, len: usize) {
+        if self.is_synchronized() && len != 0 {
+            self.push_base_copy(LogicalCopy {
+                source: 0,
+                target: 0,
+                size: len as u64,
+            });
+        }
+    }
+    // Turns runs of fewer than `min_run_blocks` base copies that continue each other in both
+    // source and target into literals. Expects one command per block, as the diff functions
+    // produce them, so that a run's command count is its block count.
+    pub fn drop_short_runs(&mut self, min_run_blocks: usize) {
+        self.base
+            .sort_by_key(|cmd| (cmd.target, cmd.source_id, cmd.source));
+        let mut kept: Vec<CopyCmd> = Vec::with_capacity(self.base.len());
+        let mut run_begin = 0;
+        for i in 1..=self.base.len() {
+            if i < self.base.len() {
+                let (prev, curr) = (&self.base[i - 1], &self.base[i]);
+                if prev.source_id == curr.source_id
+                    && prev.source + prev.size as u64 == curr.source
+                    && prev.target + prev.size as u64 == curr.target
+                {
+                    continue;
+                }
+            }
+            let run = &self.base[run_begin..i];
+            if run.len() >= min_run_blocks {
+                kept.extend_from_slice(run);
+            } else {
+                for cmd in run {
+                    self.other.push(CopyCmd {
+                        source: cmd.target,
+                        target: cmd.target,
+                        size: cmd.size,
+                        source_id: 0,
+                    });
+                }
+            }
+            run_begin = i;
+        }
+        self.base = kept;
+        self.other.sort_by_key(|cmd| cmd.target);
+    }
+    // Upper bound of `Patch::encode().len()` for the uncompressed patch, as build_patch may
+    // merge adjacent commands. The source and target deltas of a command are zigzag-encoded
+    // differences of offsets no larger than the end of the furthest command, so no varint
+    // of a command is longer than the one for twice that end.
+    pub fn estimated_serialized_size(&self) -> usize {
+        let cmds = || self.base.iter().chain(self.other.iter());
+        let command_count = self.base.len() + self.other.len();
+        let max_end = cmds()
+            .map(|cmd| cmd.source.max(cmd.target).saturating_add(cmd.size as u64))
+            .max()
+            .unwrap_or(0);
+        let max_size = cmds().map(|cmd| cmd.size).max().unwrap_or(0);
+        let max_source_id = cmds().map(|cmd| cmd.source_id).max().unwrap_or(0);
+        let cmd_len = 2 * varint_len(max_end.saturating_mul(2))
+            + varint_len(max_size as u64)
+            + varint_len(max_source_id as u64);
+        PATCH_MAX_ENCODED_OVERHEAD + command_count * cmd_len + self.need_bytes_from_other()
+    }
+    // Spans sorted by offset. Synchronized commands mean the whole output is the base itself.
+    pub fn coverage_report(&self, other_size: usize) -> Vec<CoverageSpan> {
+        let other_size = other_size as u64;
+        if self.is_synchronized() {
+            if other_size == 0 {
+                return Vec::new();
+            }
+            return vec![CoverageSpan {
+                offset: 0,
+                size: other_size,
+                kind: CoverageKind::FromBase,
+            }];
+        }
+        let mut spans: Vec<CoverageSpan> = self
+            .base
+            .iter()
+            .map(|cmd| (cmd, CoverageKind::FromBase))
+            .chain(self.other.iter().map(|cmd| (cmd, CoverageKind::FromOther)))
+            .filter(|(cmd, _)| cmd.size != 0)
+            .map(|(cmd, kind)| CoverageSpan {
+                offset: cmd.target,
+                size: cmd.size as u64,
+                kind,
+            })
+            .collect();
+        spans.sort_by_key(|span| span.offset);
+        let mut result: Vec<CoverageSpan> = Vec::with_capacity(spans.len());
+        let mut position: u64 = 0;
+        for span in spans {
+            if span.offset > position {
+                result.push(CoverageSpan {
+                    offset: position,
+                    size: span.offset - position,
+                    kind: CoverageKind::Uncovered,
+                });
+            }
+            position = position.max(span.offset + span.size);
+            result.push(span);
+        }
+        if position < other_size {
+            result.push(CoverageSpan {
+                offset: position,
+                size: other_size - position,
+                kind: CoverageKind::Uncovered,
+            });
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverageKind {
+    FromBase,
+    FromOther,
+    Uncovered,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageSpan {
+    pub offset: u64,
+    pub size: u64,
+    pub kind: CoverageKind,
 }
 
-fn is_synchronized(sequence: &[Hash128], blocks: &[Block]) -> bool {
-    if sequence.len() != blocks.len() {
+fn is_synchronized<H: BlockHash>(base_blocks: &[Block<H>], other_blocks: &[Block<H>]) -> bool {
+    if base_blocks.len() != other_blocks.len() {
         return false;
     }
-    for it in sequence.iter().zip(blocks.iter()) {
-        if *it.0 != it.1.hash_strong {
+    for it in base_blocks.iter().zip(other_blocks.iter()) {
+        if it.0.offset != it.1.offset
+            || it.0.size != it.1.size
+            || it.0.hash_strong != it.1.hash_strong
+        {
             return false;
         }
     }
     true
 }
 
-pub fn compute_diff(input: &[u8], other_blocks: &[Block], block_size: usize) -> PatchCommands {
-    let mut other_block_weak_set: HashSet<u32> = HashSet::new();
-    let mut other_block_strong_set: HashSet<Hash128> = HashSet::new();
-    let mut base_block_hash_map: HashMap<Hash128, u64> = HashMap::new();
-    let mut other_len = 0;
-    for block in other_blocks {
-        other_block_weak_set.insert(block.hash_weak);
-        other_block_strong_set.insert(block.hash_strong);
-        other_len += block.size as usize;
-    }
-    let find_base_block =
-        |block_begin: usize, block_end: usize, block_hash_weak: u32| -> Option<Block> {
-            if other_block_weak_set.contains(&block_hash_weak) {
-                let block_slice = &input[block_begin..block_end];
-                let block_hash_strong = compute_hash_strong(block_slice);
-                if other_block_strong_set.contains(&block_hash_strong) {
-                    let block = Block {
-                        offset: block_begin as u64,
-                        size: (block_end - block_begin) as u32,
-                        hash_weak: block_hash_weak,
-                        hash_strong: block_hash_strong,
-                    };
-                    return Some(block);
-                }
-            }
-            None
-        };
-    let mut rolling_hash = RollingHash::new();
-    let mut window_begin: usize = 0;
-    let mut window_end: usize = window_begin;
-    let mut sequence: Vec<Hash128> = Vec::new();
-    sequence.reserve(div_up(input.len(), block_size));
-    loop {
-        let remaining_len = input.len() - window_begin;
-        if remaining_len == 0 {
-            break;
-        }
-        let this_window_size: usize = min(remaining_len, block_size);
-        while rolling_hash.count() < this_window_size {
-            rolling_hash.add(input[window_end]);
-            window_end += 1;
-        }
-        match find_base_block(window_begin, window_end, rolling_hash.get()) {
-            Some(base_block) => {
-                window_begin = window_end;
-                rolling_hash = RollingHash::new();
-                base_block_hash_map.insert(base_block.hash_strong, base_block.offset);
-                sequence.push(base_block.hash_strong);
-            }
-            None => {
-                rolling_hash.sub(input[window_begin]);
-                window_begin += 1;
-            }
+// Base offsets are recorded in increasing order during the scan. Prefer the one that
+// continues the previous base copy so that optimize_copy_cmds can merge them later, and
+// otherwise the lowest, so that the choice doesn't depend on the order of the map.
+fn select_base_offset(candidates: &[u64], base_run_end: Option<u64>) -> u64 {
+    if let Some(run_end) = base_run_end {
+        if candidates.binary_search(&run_end).is_ok() {
+            return run_end;
         }
     }
-    let mut patch_commands = PatchCommands::new();
-    if input.len() != other_len || !is_synchronized(&sequence, &other_blocks) {
-        for other_block in other_blocks {
-            match base_block_hash_map.get(&other_block.hash_strong) {
-                Some(&base_offset) => {
-                    patch_commands.base.push(CopyCmd {
-                        source: base_offset,
-                        target: other_block.offset,
-                        size: other_block.size,
-                    });
-                }
-                None => {
-                    patch_commands.other.push(CopyCmd {
-                        source: other_block.offset,
-                        target: other_block.offset,
-                        size: other_block.size,
-                    });
-                }
-            }
-        }
+    candidates[0]
+}
+
+// Lookup of the blocks of `other` during a diff. A weak hit is always confirmed with
+// `lookup_strong`, so `contains_weak` may return false positives, e.g. to bound memory for
+// huge signatures.
+pub trait BlockIndex<H = Hash128> {
+    fn contains_weak(&self, hash_weak: u32) -> bool;
+    // Offset in `other` of a block with this strong hash.
+    fn lookup_strong(&self, hash_strong: &H) -> Option<u64>;
+    // Whether a window of `size` bytes with this strong hash matches a block. Indexes that keep
+    // block sizes only accept blocks of the window's length.
+    fn contains_block(&self, hash_strong: &H, _size: u32) -> bool {
+        self.lookup_strong(hash_strong).is_some()
     }
-    patch_commands
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Patch {
-    pub data: Vec<u8>,
-    pub base: Vec<CopyCmd>,
-    pub other: Vec<CopyCmd>,
-    pub other_size: u64,
+// Weak and strong hashes of the blocks of `other` in hash sets, shared by every scanner of one
+// diff. This is the index used by `compute_diff`.
+pub struct OtherBlockSet<H = Hash128> {
+    weak: HashSet<u32>,
+    // Size and offset of the first block with each strong hash and size.
+    strong: HashMap<H, Vec<(u32, u64)>>,
+    len: usize,
 }
 
-fn optimize_copy_cmds(cmds: &mut Vec<CopyCmd>) {
-    if cmds.len() > 1 {
-        cmds.sort_by_key(|v| v.target);
-        let (mut prev, rest) = cmds.split_first_mut().unwrap();
-        for curr in rest.iter_mut() {
-            if prev.source + prev.size as u64 == curr.source
-                && prev.target + prev.size as u64 == curr.target
-                && prev.size as u64 + curr.size as u64 <= u32::max_value() as u64
-            {
-                curr.source = prev.source;
-                curr.target = prev.target;
-                curr.size += prev.size;
-                prev.size = 0;
+impl<H: BlockHash> OtherBlockSet<H> {
+    pub fn new(other_blocks: &[Block<H>]) -> Self {
+        let mut result = Self {
+            weak: HashSet::default(),
+            strong: HashMap::default(),
+            len: 0,
+        };
+        result.rebuild(other_blocks);
+        result
+    }
+
+    // Replaces the contents, keeping the allocated capacity.
+    fn rebuild(&mut self, other_blocks: &[Block<H>]) {
+        self.weak.clear();
+        self.strong.clear();
+        self.len = 0;
+        for block in other_blocks {
+            self.weak.insert(block.hash_weak);
+            let entries = self.strong.entry(block.hash_strong).or_default();
+            if entries.iter().all(|&(size, _)| size != block.size) {
+                entries.push((block.size, block.offset));
             }
-            prev = curr;
+            self.len += block.size as usize;
         }
-        cmds.retain(|cmd| cmd.size != 0);
     }
 }
 
-pub fn build_patch(other_data: &[u8], patch_commands: &PatchCommands) -> Patch {
-    let mut patch_data: Vec<u8> = Vec::new();
-    let mut other_cmds: Vec<CopyCmd> = Vec::new();
-    for cmd in &patch_commands.other {
-        let patch_copy_cmd = CopyCmd {
-            source: patch_data.len() as u64,
-            target: cmd.target,
-            size: cmd.size,
-        };
-        let slice_begin = cmd.source as usize;
-        let slice_end = cmd.source as usize + cmd.size as usize;
-        let slice = &other_data[slice_begin..slice_end];
-        patch_data.extend(slice.iter().cloned());
-        other_cmds.push(patch_copy_cmd);
+impl<H: BlockHash> BlockIndex<H> for OtherBlockSet<H> {
+    fn contains_weak(&self, hash_weak: u32) -> bool {
+        self.weak.contains(&hash_weak)
     }
-    let mut result = Patch {
-        data: patch_data,
-        base: patch_commands.base.clone(),
-        other: other_cmds,
-        other_size: other_data.len() as u64,
-    };
+    fn lookup_strong(&self, hash_strong: &H) -> Option<u64> {
+        self.strong.get(hash_strong).map(|entries| entries[0].1)
+    }
+    fn contains_block(&self, hash_strong: &H, size: u32) -> bool {
+        self.strong
+            .get(hash_strong)
+            .into_iter()
+            .flatten()
+            .any(|&(block_size, _)| block_size == size)
+    }
+}
 
-    optimize_copy_cmds(&mut result.base);
-    optimize_copy_cmds(&mut result.other);
+// Blocks of several base signatures, searched for in the new data by `compute_diff_multi` and
+// `compute_diff_streaming`. A window only matches blocks of its own length.
+pub(crate) struct MultiBaseBlockSet<H = Hash128> {
+    weak: HashSet<u32>,
+    // Size, index in `bases` and offset of the first block with each strong hash and size.
+    strong: HashMap<H, Vec<(u32, u32, u64)>>,
+}
 
-    result
+impl<H: BlockHash> MultiBaseBlockSet<H> {
+    pub(crate) fn new(bases: &[&[Block<H>]]) -> Self {
+        let mut result = Self {
+            weak: HashSet::default(),
+            strong: HashMap::default(),
+        };
+        for (source_id, base_blocks) in bases.iter().enumerate() {
+            for block in base_blocks.iter() {
+                result.weak.insert(block.hash_weak);
+                let entries = result.strong.entry(block.hash_strong).or_default();
+                if entries.iter().all(|&(size, _, _)| size != block.size) {
+                    entries.push((block.size, source_id as u32, block.offset));
+                }
+            }
+        }
+        result
+    }
+
+    // Index in `bases` and offset of the block matched by `find_strong`.
+    pub(crate) fn locate(&self, hash_strong: &H, size: u32) -> Option<(u32, u64)> {
+        self.strong
+            .get(hash_strong)?
+            .iter()
+            .find(|&&(block_size, _, _)| block_size == size)
+            .map(|&(_, source_id, offset)| (source_id, offset))
+    }
 }
 
-pub fn apply_patch(base_data: &[u8], patch: &Patch) -> Vec<u8> {
-    let mut result: Vec<u8> = Vec::new();
-    result.resize(patch.other_size as usize, 0);
-    for cmd in &patch.base {
-        cmd.execute(&mut result, &base_data);
+impl<H: BlockHash> BlockIndex<H> for MultiBaseBlockSet<H> {
+    fn contains_weak(&self, hash_weak: u32) -> bool {
+        self.weak.contains(&hash_weak)
     }
-    for cmd in &patch.other {
-        cmd.execute(&mut result, &patch.data);
+    fn lookup_strong(&self, hash_strong: &H) -> Option<u64> {
+        self.strong.get(hash_strong).map(|entries| entries[0].2)
+    }
+    fn contains_block(&self, hash_strong: &H, size: u32) -> bool {
+        self.locate(hash_strong, size).is_some()
     }
-    result
 }
 
-#[cfg(test)]
-pub fn testing_optimize_copy_cmds(cmds: &mut Vec<crate::CopyCmd>) {
-    optimize_copy_cmds(cmds);
-}    
+// Second stage of the lookup, for a window whose weak hash is known to be in the index.
+fn find_strong<H: BlockHash, I: BlockIndex<H> + ?Sized>(
+    index: &I,
+    hasher: &(impl StrongHasher<H> + ?Sized),
+    window: &[u8],
+    window_begin: u64,
+    block_hash_weak: u32,
+    metrics: &mut DiffMetrics,
+) -> Option<Block<H>> {
+    metrics.weak_hits += 1;
+    let block_hash_strong = hasher.hash(window);
+    if index.contains_block(&block_hash_strong, window.len() as u32) {
+        metrics.strong_confirmations += 1;
+        return Some(Block {
+            offset: window_begin,
+            size: window.len() as u32,
+            hash_weak: block_hash_weak,
+            hash_strong: block_hash_strong,
+        });
+    }
+    metrics.strong_false_positives += 1;
+    None
+}
+
+// Counters collected by `DiffScanner`. `bytes_matched` and `bytes_literal` are only filled in
+// by `compute_diff_with_metrics`, as they depend on the final commands. `cached_rejections`
+// counts weak hits that skipped the strong hash because the window content was unchanged since
+// its last false positive; they are not included in `weak_hits`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffMetrics {
+    pub weak_hits: u64,
+    pub strong_confirmations: u64,
+    pub strong_false_positives: u64,
+    pub cached_rejections: u64,
+    pub window_slides: u64,
+    pub bytes_matched: u64,
+    pub bytes_literal: u64,
+}
+
+// Incremental search for blocks of `other` in the input. Bytes are fed in order, possibly in
+// several segments; the rolling window carries over segment boundaries.
+pub struct DiffScanner<'a, H = Hash128, W = RollingHash, I: ?Sized = OtherBlockSet<H>> {
+    index: &'a I,
+    hasher: &'a dyn StrongHasher<H>,
+    block_size: usize,
+    rolling_hash: W,
+    window: VecDeque<u8>,
+    window_begin: u64,
+    base_blocks: Vec<Block<H>>,
+    metrics: DiffMetrics,
+    // Set after a false positive on a window that repeats one byte. While that byte keeps coming
+    // in, the window content doesn't change and is rejected without hashing it again.
+    rejected_run: Option<u8>,
+}
+
+impl<'a, H: BlockHash> DiffScanner<'a, H> {
+    pub fn new(other_set: &'a OtherBlockSet<H>, block_size: usize) -> Self {
+        Self::with_weak_hash(other_set, block_size)
+    }
+}
+
+impl<'a, H: BlockHash, W: WeakHash> DiffScanner<'a, H, W> {
+    pub fn with_weak_hash(other_set: &'a OtherBlockSet<H>, block_size: usize) -> Self {
+        Self::with_index(other_set, block_size)
+    }
+}
+
+impl<'a, H: BlockHash, W: WeakHash, I: BlockIndex<H> + ?Sized> DiffScanner<'a, H, W, I> {
+    pub fn with_index(index: &'a I, block_size: usize) -> Self {
+        check_block_size(block_size).unwrap();
+        Self::with_offset(index, block_size, 0)
+    }
+
+    fn with_offset(index: &'a I, block_size: usize, offset: u64) -> Self {
+        Self::with_buffers(
+            index,
+            block_size,
+            offset,
+            VecDeque::with_capacity(block_size),
+            Vec::new(),
+        )
+    }
+
+    // `window` and `base_blocks` must be empty; only their capacity is used.
+    fn with_buffers(
+        index: &'a I,
+        block_size: usize,
+        offset: u64,
+        window: VecDeque<u8>,
+        base_blocks: Vec<Block<H>>,
+    ) -> Self {
+        debug_assert!(window.is_empty() && base_blocks.is_empty());
+        Self {
+            index,
+            hasher: &Blake3Hasher,
+            block_size,
+            rolling_hash: W::new(),
+            window,
+            window_begin: offset,
+            base_blocks,
+            metrics: DiffMetrics::default(),
+            rejected_run: None,
+        }
+    }
+
+    // Replaces the default `Blake3Hasher`. Must be the hasher of the signature.
+    pub fn with_hasher(mut self, hasher: &'a dyn StrongHasher<H>) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    // Returns the window buffer, empty after `finish`, along with the found blocks.
+    fn into_buffers(self) -> (VecDeque<u8>, Vec<Block<H>>) {
+        (self.window, self.base_blocks)
+    }
+
+    pub fn metrics(&self) -> &DiffMetrics {
+        &self.metrics
+    }
+
+    pub fn rolling_hash(&self) -> &W {
+        &self.rolling_hash
+    }
+
+    // Input offset of the first byte in the window.
+    pub fn window_begin(&self) -> u64 {
+        self.window_begin
+    }
+
+    // Input offset one past the last byte in the window, i.e. the number of bytes fed so far.
+    pub fn window_end(&self) -> u64 {
+        self.window_begin + self.window.len() as u64
+    }
+
+    pub fn base_blocks(&self) -> &[Block<H>] {
+        &self.base_blocks
+    }
+
+    pub fn into_base_blocks(self) -> Vec<Block<H>> {
+        self.base_blocks
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.base_blocks.reserve(data.len() / self.block_size);
+        for &x in data {
+            self.step(x);
+        }
+    }
+
+    // Adds one byte to the window. Returns true if the window completed a block match.
+    pub fn step(&mut self, x: u8) -> bool {
+        let repeats_rejected = self.rejected_run.take() == Some(x);
+        self.rolling_hash.add(x);
+        self.window.push_back(x);
+        if self.window.len() < self.block_size {
+            return false;
+        }
+        if repeats_rejected {
+            self.metrics.cached_rejections += 1;
+            self.slide();
+            self.rejected_run = Some(x);
+            return false;
+        }
+        self.test_window()
+    }
+
+    // Scans the bytes left in the window with shrinking windows, as at the end of the input.
+    pub fn finish(&mut self) {
+        while !self.window.is_empty() {
+            self.test_window();
+        }
+        self.rejected_run = None;
+    }
+
+    fn test_window(&mut self) -> bool {
+        let hash_weak = self.rolling_hash.get();
+        if !self.index.contains_weak(hash_weak) {
+            self.slide();
+            return false;
+        }
+        match find_strong(
+            self.index,
+            self.hasher,
+            self.window.make_contiguous(),
+            self.window_begin,
+            hash_weak,
+            &mut self.metrics,
+        ) {
+            Some(base_block) => {
+                self.window_begin += self.window.len() as u64;
+                self.window.clear();
+                self.rolling_hash = W::new();
+                self.base_blocks.push(base_block);
+                true
+            }
+            None => {
+                let x = self.slide();
+                if self.window.iter().all(|&y| y == x) {
+                    self.rejected_run = Some(x);
+                }
+                false
+            }
+        }
+    }
+
+    // Drops the first byte of the window and returns it.
+    fn slide(&mut self) -> u8 {
+        let x = self.window.pop_front().unwrap();
+        self.rolling_hash.sub(x);
+        self.window_begin += 1;
+        self.metrics.window_slides += 1;
+        x
+    }
+}
+
+// Finds blocks of `other` inside `input[scan_begin..scan_end]`. Windows never extend past `scan_end`.
+fn scan_base_blocks<H: BlockHash>(
+    input: &[u8],
+    scan_begin: usize,
+    scan_end: usize,
+    block_size: usize,
+    other_set: &OtherBlockSet<H>,
+) -> Vec<Block<H>> {
+    let mut scanner =
+        DiffScanner::<H, RollingHash, _>::with_offset(other_set, block_size, scan_begin as u64);
+    scanner.feed(&input[scan_begin..scan_end]);
+    scanner.finish();
+    scanner.into_base_blocks()
+}
+
+// A full block match can consume the bytes that would otherwise match the short final block
+// of `other`, so the tail of `input` is checked against it explicitly.
+fn match_tail_block<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    hasher: &(impl StrongHasher<H> + ?Sized),
+    base_blocks: &mut Vec<Block<H>>,
+) {
+    let tail_block = match other_blocks.last() {
+        Some(block) if (block.size as usize) < block_size => block,
+        _ => return,
+    };
+    let tail_size = tail_block.size as usize;
+    if tail_size > input.len()
+        || base_blocks
+            .iter()
+            .any(|block| block.hash_strong == tail_block.hash_strong)
+    {
+        return;
+    }
+    let tail_begin = input.len() - tail_size;
+    if hasher.hash(&input[tail_begin..]) == tail_block.hash_strong {
+        base_blocks.push(Block {
+            offset: tail_begin as u64,
+            size: tail_block.size,
+            hash_weak: tail_block.hash_weak,
+            hash_strong: tail_block.hash_strong,
+        });
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffOp {
+    Copy { source: u64, target: u64, size: u32 },
+    Literal { target: u64, size: u32 },
+}
+
+// Offsets of the base blocks by strong hash and size. The size is part of the key so that a
+// short tail block never matches a full block, or the other way round.
+type BaseBlockMap<H> = HashMap<(H, u32), Vec<u64>>;
+
+pub struct DiffOps<'a, H> {
+    other_blocks: std::slice::Iter<'a, Block<H>>,
+    base_block_hash_map: BaseBlockMap<H>,
+    base_run_end: Option<u64>,
+}
+
+impl<'a, H: BlockHash> DiffOps<'a, H> {
+    fn new(input_len: usize, other_blocks: &'a [Block<H>], base_blocks: &[Block<H>]) -> Self {
+        Self::with_map(input_len, other_blocks, base_blocks, HashMap::default())
+    }
+
+    // `base_block_hash_map` is cleared first, so a map from an earlier diff can be passed in.
+    fn with_map(
+        input_len: usize,
+        other_blocks: &'a [Block<H>],
+        base_blocks: &[Block<H>],
+        mut base_block_hash_map: BaseBlockMap<H>,
+    ) -> Self {
+        base_block_hash_map.clear();
+        let other_len: usize = other_blocks.iter().map(|block| block.size as usize).sum();
+        let other_blocks = if input_len == other_len && is_synchronized(base_blocks, other_blocks) {
+            &other_blocks[0..0]
+        } else {
+            for base_block in base_blocks {
+                base_block_hash_map
+                    .entry((base_block.hash_strong, base_block.size))
+                    .or_default()
+                    .push(base_block.offset);
+            }
+            other_blocks
+        };
+        Self {
+            other_blocks: other_blocks.iter(),
+            base_block_hash_map,
+            base_run_end: None,
+        }
+    }
+}
+
+impl<'a, H: BlockHash> Iterator for DiffOps<'a, H> {
+    type Item = DiffOp;
+    fn next(&mut self) -> Option<DiffOp> {
+        let other_block = self.other_blocks.next()?;
+        let key = (other_block.hash_strong, other_block.size);
+        match self.base_block_hash_map.get(&key) {
+            Some(base_offsets) => {
+                let base_offset = select_base_offset(base_offsets, self.base_run_end);
+                self.base_run_end = Some(base_offset + other_block.size as u64);
+                Some(DiffOp::Copy {
+                    source: base_offset,
+                    target: other_block.offset,
+                    size: other_block.size,
+                })
+            }
+            None => {
+                // A literal replacing part of a run, e.g. an edited block, shouldn't break
+                // the run: the copy after it still prefers the base offset right behind it.
+                self.base_run_end = self
+                    .base_run_end
+                    .map(|run_end| run_end + other_block.size as u64);
+                Some(DiffOp::Literal {
+                    target: other_block.offset,
+                    size: other_block.size,
+                })
+            }
+        }
+    }
+}
+
+pub(crate) fn build_patch_commands<H: BlockHash>(
+    input_len: usize,
+    block_size: usize,
+    other_blocks: &[Block<H>],
+    base_blocks: &[Block<H>],
+) -> PatchCommands {
+    let mut base_block_hash_map = HashMap::default();
+    build_patch_commands_reuse(
+        input_len,
+        block_size,
+        other_blocks,
+        base_blocks,
+        &mut base_block_hash_map,
+    )
+}
+
+fn build_patch_commands_reuse<H: BlockHash>(
+    input_len: usize,
+    block_size: usize,
+    other_blocks: &[Block<H>],
+    base_blocks: &[Block<H>],
+    base_block_hash_map: &mut BaseBlockMap<H>,
+) -> PatchCommands {
+    let mut patch_commands = PatchCommands::with_block_size(block_size);
+    let map = std::mem::take(base_block_hash_map);
+    let mut ops = DiffOps::with_map(input_len, other_blocks, base_blocks, map);
+    for op in ops.by_ref() {
+        match op {
+            DiffOp::Copy {
+                source,
+                target,
+                size,
+            } => patch_commands.base.push(CopyCmd {
+                source,
+                target,
+                size,
+                source_id: 0,
+            }),
+            DiffOp::Literal { target, size } => patch_commands.other.push(CopyCmd {
+                source: target,
+                target,
+                size,
+                source_id: 0,
+            }),
+        }
+    }
+    *base_block_hash_map = ops.base_block_hash_map;
+    patch_commands
+}
+
+// Base matches are found eagerly, but commands are produced lazily one `other` block at a time.
+pub fn compute_diff_iter<'a, H: BlockHash>(
+    input: &[u8],
+    other_blocks: &'a [Block<H>],
+    block_size: usize,
+) -> DiffOps<'a, H> {
+    check_block_size(block_size).unwrap();
+    check_other_blocks(other_blocks, block_size).unwrap();
+    let other_set = OtherBlockSet::new(other_blocks);
+    let mut base_blocks = scan_base_blocks(input, 0, input.len(), block_size, &other_set);
+    match_tail_block(
+        input,
+        other_blocks,
+        block_size,
+        &Blake3Hasher,
+        &mut base_blocks,
+    );
+    DiffOps::new(input.len(), other_blocks, &base_blocks)
+}
+
+pub fn compute_diff<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> PatchCommands {
+    try_compute_diff(input, other_blocks, block_size).unwrap()
+}
+
+pub fn try_compute_diff<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> Result<PatchCommands, PatchError> {
+    try_compute_diff_with::<H, RollingHash>(input, other_blocks, block_size)
+}
+
+// Like `compute_diff`, then narrows each literal run by growing the base copies next to it for
+// as long as the bytes still match, so that a block with a few edited bytes costs a literal of
+// about those bytes rather than the whole block. Needs `other` itself, not only its signature.
+pub fn compute_diff_refined(input: &[u8], other: &[u8], block_size: usize) -> PatchCommands {
+    let mut patch_commands = compute_diff(input, &compute_blocks(other, block_size), block_size);
+    refine_literals(&mut patch_commands, input, other);
+    patch_commands
+}
+
+// Like `compute_diff`, but emits at most `max_commands` commands in all. While there are too
+// many, the two neighbouring commands that turn the fewest copied bytes into literal bytes are
+// merged into one literal, so copies between literals go first. The cap is only exceeded when
+// the output needs more than `max_commands` literals of up to 4 GiB, e.g. for a cap of 0.
+pub fn compute_diff_bounded<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    max_commands: usize,
+) -> PatchCommands {
+    try_compute_diff_bounded(input, other_blocks, block_size, max_commands).unwrap()
+}
+
+pub fn try_compute_diff_bounded<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    max_commands: usize,
+) -> Result<PatchCommands, PatchError> {
+    let mut patch_commands = try_compute_diff(input, other_blocks, block_size)?;
+    bound_command_count(&mut patch_commands, max_commands);
+    Ok(patch_commands)
+}
+
+// A boundary between two runs in `bound_command_count`: the copied bytes that merging them
+// turns into literals, the target of the first run, and both runs with their versions.
+type RunBoundary = Reverse<(u64, u64, usize, u32, u32)>;
+
+fn bound_command_count(patch_commands: &mut PatchCommands, max_commands: usize) {
+    let mut count = patch_commands.base.len() + patch_commands.other.len();
+    if count <= max_commands {
+        return;
+    }
+    // Runs of the output in target order, each a copy or a literal (`true`), in a linked list.
+    let mut runs: Vec<(CopyCmd, bool)> = patch_commands
+        .base
+        .drain(..)
+        .map(|cmd| (cmd, false))
+        .chain(patch_commands.other.drain(..).map(|cmd| (cmd, true)))
+        .collect();
+    runs.sort_by_key(|(cmd, _)| cmd.target);
+    let run_count = runs.len();
+    let mut next: Vec<usize> = (1..=run_count).collect();
+    let mut prev: Vec<Option<usize>> = (0..run_count).map(|i| i.checked_sub(1)).collect();
+    let mut alive: Vec<bool> = vec![true; run_count];
+    // Bumped whenever a run grows, which outdates the queue entries of its boundaries.
+    let mut version: Vec<u32> = vec![0; run_count];
+    let copied = |run: &(CopyCmd, bool)| if run.1 { 0 } else { run.0.size as u64 };
+    let mut boundaries: BinaryHeap<RunBoundary> = BinaryHeap::new();
+    let push_boundary = |boundaries: &mut BinaryHeap<_>,
+                         runs: &[(CopyCmd, bool)],
+                         version: &[u32],
+                         i: usize,
+                         j: usize| {
+        if runs[i].0.size as u64 + runs[j].0.size as u64 <= u32::MAX as u64 {
+            let cost = copied(&runs[i]) + copied(&runs[j]);
+            boundaries.push(Reverse((cost, runs[i].0.target, i, version[i], version[j])));
+        }
+    };
+    for i in 1..run_count {
+        push_boundary(&mut boundaries, &runs, &version, i - 1, i);
+    }
+    while count > max_commands {
+        let (i, i_version, j_version) = match boundaries.pop() {
+            Some(Reverse((_, _, i, i_version, j_version))) => (i, i_version, j_version),
+            None => break,
+        };
+        let j = next[i];
+        if !alive[i] || j == run_count || version[i] != i_version || version[j] != j_version {
+            continue;
+        }
+        let target = runs[i].0.target;
+        runs[i] = (
+            CopyCmd {
+                source: target,
+                target,
+                size: runs[i].0.size + runs[j].0.size,
+                source_id: 0,
+            },
+            true,
+        );
+        version[i] += 1;
+        alive[j] = false;
+        next[i] = next[j];
+        if next[j] != run_count {
+            prev[next[j]] = Some(i);
+        }
+        count -= 1;
+        if let Some(before) = prev[i] {
+            push_boundary(&mut boundaries, &runs, &version, before, i);
+        }
+        if next[i] != run_count {
+            push_boundary(&mut boundaries, &runs, &version, i, next[i]);
+        }
+    }
+    for ((cmd, is_literal), _) in runs.into_iter().zip(alive).filter(|(_, alive)| *alive) {
+        if is_literal {
+            patch_commands.other.push(cmd);
+        } else {
+            patch_commands.base.push(cmd);
+        }
+    }
+    optimize_copy_cmds(&mut patch_commands.base);
+    optimize_copy_cmds(&mut patch_commands.other);
+}
+
+// Like `compute_diff`, for an `other` that mostly extends `input`, such as a log file that was
+// appended to. The leading and trailing blocks of `other` are compared with the bytes at the
+// same place in `input` and become one copy each, and only the input between them is searched
+// with the rolling hash. A pure append hashes the input once and searches less than a block.
+// Blocks in the middle are only looked for in the middle of `input`.
+pub fn compute_diff_append_aware<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> PatchCommands {
+    try_compute_diff_append_aware(input, other_blocks, block_size).unwrap()
+}
+
+pub fn try_compute_diff_append_aware<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> Result<PatchCommands, PatchError> {
+    check_block_size(block_size)?;
+    check_other_blocks(other_blocks, block_size)?;
+    let other_len: u64 = other_blocks.iter().map(|block| block.size as u64).sum();
+    let matches_at = |block: &Block<H>, input_begin: u64| {
+        let input_end = input_begin + block.size as u64;
+        if input_end > input.len() as u64 {
+            return false;
+        }
+        let slice = &input[input_begin as usize..input_end as usize];
+        compute_hash_weak(slice) == block.hash_weak && H::compute(slice) == block.hash_strong
+    };
+    let prefix_blocks = other_blocks
+        .iter()
+        .take_while(|block| matches_at(block, block.offset))
+        .count();
+    let prefix_len = other_blocks
+        .get(prefix_blocks)
+        .map_or(other_len, |block| block.offset);
+    let mut patch_commands = PatchCommands::with_block_size(block_size);
+    if prefix_len == other_len && input.len() as u64 == other_len {
+        return Ok(patch_commands);
+    }
+    // Offsets in `other` past the prefix map to `input` moved by the change in length.
+    let to_input = |offset: u64| (offset + input.len() as u64).checked_sub(other_len);
+    let suffix_blocks = other_blocks[prefix_blocks..]
+        .iter()
+        .rev()
+        .take_while(|block| match to_input(block.offset) {
+            Some(input_begin) => input_begin >= prefix_len && matches_at(block, input_begin),
+            None => false,
+        })
+        .count();
+    let suffix_begin = other_blocks.len() - suffix_blocks;
+    let other_suffix_begin = other_blocks
+        .get(suffix_begin)
+        .map_or(other_len, |block| block.offset);
+    let input_suffix_begin = to_input(other_suffix_begin).unwrap_or(input.len() as u64);
+
+    patch_commands.push_base_copy(LogicalCopy {
+        source: 0,
+        target: 0,
+        size: prefix_len,
+    });
+    let middle_blocks = &other_blocks[prefix_blocks..suffix_begin];
+    let middle_input = &input[prefix_len as usize..input_suffix_begin as usize];
+    if !middle_blocks.is_empty() {
+        let middle = try_compute_diff(middle_input, middle_blocks, block_size)?;
+        if middle.is_synchronized() {
+            patch_commands.push_base_copy(LogicalCopy {
+                source: prefix_len,
+                target: prefix_len,
+                size: other_suffix_begin - prefix_len,
+            });
+        }
+        patch_commands
+            .base
+            .extend(middle.base.into_iter().map(|cmd| CopyCmd {
+                source: cmd.source + prefix_len,
+                ..cmd
+            }));
+        patch_commands.other.extend(middle.other);
+    }
+    patch_commands.push_base_copy(LogicalCopy {
+        source: input_suffix_begin,
+        target: other_suffix_begin,
+        size: other_len - other_suffix_begin,
+    });
+    optimize_copy_cmds(&mut patch_commands.base);
+    optimize_copy_cmds(&mut patch_commands.other);
+    Ok(patch_commands)
+}
+
+// Commands must be sorted by target, as `optimize_copy_cmds` leaves them.
+fn refine_literals(patch_commands: &mut PatchCommands, input: &[u8], other: &[u8]) {
+    let base = &mut patch_commands.base;
+    for literal in &mut patch_commands.other {
+        let next_index = base.partition_point(|cmd| cmd.target < literal.target);
+        if let Some(prev) = next_index.checked_sub(1).map(|i| &mut base[i]) {
+            if prev.source_id == 0 && prev.target + prev.size as u64 == literal.target {
+                let source_end = min((prev.source + prev.size as u64) as usize, input.len());
+                let target_begin = literal.target as usize;
+                let limit = min(literal.size, u32::MAX - prev.size) as usize;
+                let matching = input[source_end..]
+                    .iter()
+                    .zip(&other[target_begin..target_begin + limit])
+                    .take_while(|(x, y)| x == y)
+                    .count() as u32;
+                prev.size += matching;
+                literal.source += matching as u64;
+                literal.target += matching as u64;
+                literal.size -= matching;
+            }
+        }
+        if let Some(next) = base.get_mut(next_index) {
+            let target_end = literal.target + literal.size as u64;
+            if next.source_id == 0 && next.target == target_end {
+                let source_begin = min(next.source as usize, input.len());
+                let limit = min(literal.size, u32::MAX - next.size) as usize;
+                let matching = input[..source_begin]
+                    .iter()
+                    .rev()
+                    .zip(
+                        other[target_end as usize - limit..target_end as usize]
+                            .iter()
+                            .rev(),
+                    )
+                    .take_while(|(x, y)| x == y)
+                    .count() as u32;
+                next.source -= matching as u64;
+                next.target -= matching as u64;
+                next.size += matching;
+                literal.size -= matching;
+            }
+        }
+    }
+    optimize_copy_cmds(&mut patch_commands.base);
+    optimize_copy_cmds(&mut patch_commands.other);
+}
+
+pub fn compute_diff_with_metrics<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> (PatchCommands, DiffMetrics) {
+    check_block_size(block_size).unwrap();
+    check_other_blocks(other_blocks, block_size).unwrap();
+    let other_set = OtherBlockSet::new(other_blocks);
+    let mut scanner = DiffScanner::new(&other_set, block_size);
+    scanner.feed(input);
+    scanner.finish();
+    let mut metrics = *scanner.metrics();
+    let mut base_blocks = scanner.into_base_blocks();
+    match_tail_block(
+        input,
+        other_blocks,
+        block_size,
+        &Blake3Hasher,
+        &mut base_blocks,
+    );
+    let patch_commands = build_patch_commands(input.len(), block_size, other_blocks, &base_blocks);
+    if patch_commands.is_synchronized() {
+        metrics.bytes_matched = other_set.len as u64;
+    } else {
+        metrics.bytes_matched = patch_commands.need_bytes_from_base() as u64;
+        metrics.bytes_literal = patch_commands.need_bytes_from_other() as u64;
+    }
+    (patch_commands, metrics)
+}
+
+// Only tests the block-aligned windows of `input`, for data where matches can't move relative
+// to block boundaries. No rolling hash is maintained.
+pub fn compute_diff_aligned<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> PatchCommands {
+    check_block_size(block_size).unwrap();
+    check_other_blocks(other_blocks, block_size).unwrap();
+    let other_set = OtherBlockSet::new(other_blocks);
+    let mut metrics = DiffMetrics::default();
+    let mut base_blocks: Vec<Block<H>> = Vec::new();
+    for (i, window) in input.chunks(block_size).enumerate() {
+        let hash_weak = compute_hash_weak(window);
+        if other_set.contains_weak(hash_weak) {
+            let window_begin = (i * block_size) as u64;
+            if let Some(block) = find_strong(
+                &other_set,
+                &Blake3Hasher,
+                window,
+                window_begin,
+                hash_weak,
+                &mut metrics,
+            ) {
+                base_blocks.push(block);
+            }
+        }
+    }
+    match_tail_block(
+        input,
+        other_blocks,
+        block_size,
+        &Blake3Hasher,
+        &mut base_blocks,
+    );
+    build_patch_commands(input.len(), block_size, other_blocks, &base_blocks)
+}
+
+// Cheap check to skip the diff for unchanged files: lengths first, then the whole-file strong
+// hashes.
+pub fn files_identical(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && compute_hash_strong(a) == compute_hash_strong(b)
+}
+
+// When `other_whole_hash` (the strong hash of all of `other`) matches `input`, the block scan
+// is skipped and empty commands are returned.
+pub fn compute_diff_with_whole_hash<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    other_whole_hash: Option<H>,
+) -> PatchCommands {
+    try_compute_diff_with_whole_hash(input, other_blocks, block_size, other_whole_hash).unwrap()
+}
+
+pub fn try_compute_diff_with_whole_hash<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    other_whole_hash: Option<H>,
+) -> Result<PatchCommands, PatchError> {
+    check_block_size(block_size)?;
+    if let Some(other_whole_hash) = other_whole_hash {
+        let other_len: u64 = other_blocks.iter().map(|block| block.size as u64).sum();
+        if input.len() as u64 == other_len && H::compute(input) == other_whole_hash {
+            return Ok(PatchCommands::with_block_size(block_size));
+        }
+    }
+    try_compute_diff(input, other_blocks, block_size)
+}
+
+// Diff against a signature computed by `try_compute_blocks_with` using the same `W`.
+pub fn try_compute_diff_with<H: BlockHash, W: WeakHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> Result<PatchCommands, PatchError> {
+    try_compute_diff_impl::<H, W>(input, other_blocks, block_size, None)
+}
+
+// Input bytes scanned between two calls of a diff progress callback.
+pub const DIFF_PROGRESS_INTERVAL: usize = 1024 * 1024;
+
+// Calls `progress` with the scan position (`window_begin`) after every DIFF_PROGRESS_INTERVAL
+// bytes of input. With `None` the input is scanned in one piece, exactly as by `compute_diff`.
+pub fn compute_diff_with_progress<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> PatchCommands {
+    try_compute_diff_with_progress(input, other_blocks, block_size, progress).unwrap()
+}
+
+pub fn try_compute_diff_with_progress<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> Result<PatchCommands, PatchError> {
+    try_compute_diff_impl::<H, RollingHash>(input, other_blocks, block_size, progress)
+}
+
+// Base matches shorter than `min_run_blocks` consecutive blocks are emitted as literals, which
+// gives fewer and longer commands at the cost of some reuse. See `drop_short_runs`.
+pub fn compute_diff_with_min_run<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    min_run_blocks: usize,
+) -> PatchCommands {
+    try_compute_diff_with_min_run(input, other_blocks, block_size, min_run_blocks).unwrap()
+}
+
+pub fn try_compute_diff_with_min_run<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    min_run_blocks: usize,
+) -> Result<PatchCommands, PatchError> {
+    let mut patch_commands = try_compute_diff(input, other_blocks, block_size)?;
+    patch_commands.drop_short_runs(min_run_blocks);
+    Ok(patch_commands)
+}
+
+fn try_compute_diff_impl<H: BlockHash, W: WeakHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> Result<PatchCommands, PatchError> {
+    check_block_size(block_size)?;
+    check_other_blocks(other_blocks, block_size)?;
+    let other_set = OtherBlockSet::new(other_blocks);
+    try_compute_diff_index_impl::<H, W, _>(
+        input,
+        other_blocks,
+        &other_set,
+        &Blake3Hasher,
+        block_size,
+        progress,
+    )
+}
+
+// Like `compute_diff`, for a signature computed with `compute_blocks_with_hasher` and the same
+// `hasher`.
+pub fn compute_diff_with_hasher<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    hasher: &impl StrongHasher<H>,
+) -> PatchCommands {
+    try_compute_diff_with_hasher(input, other_blocks, block_size, hasher).unwrap()
+}
+
+pub fn try_compute_diff_with_hasher<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    hasher: &impl StrongHasher<H>,
+) -> Result<PatchCommands, PatchError> {
+    check_block_size(block_size)?;
+    check_other_blocks(other_blocks, block_size)?;
+    let other_set = OtherBlockSet::new(other_blocks);
+    try_compute_diff_index_impl::<H, RollingHash, _>(
+        input,
+        other_blocks,
+        &other_set,
+        hasher,
+        block_size,
+        None,
+    )
+}
+
+// Like `compute_diff`, but blocks are looked up in `index` instead of hash sets built from
+// `other_blocks`. `other_blocks` is still walked in order to emit the commands.
+pub fn compute_diff_with_index<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    index: &impl BlockIndex<H>,
+    block_size: usize,
+) -> PatchCommands {
+    try_compute_diff_with_index(input, other_blocks, index, block_size).unwrap()
+}
+
+pub fn try_compute_diff_with_index<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    index: &impl BlockIndex<H>,
+    block_size: usize,
+) -> Result<PatchCommands, PatchError> {
+    check_block_size(block_size)?;
+    check_other_blocks(other_blocks, block_size)?;
+    try_compute_diff_index_impl::<H, RollingHash, _>(
+        input,
+        other_blocks,
+        index,
+        &Blake3Hasher,
+        block_size,
+        None,
+    )
+}
+
+fn try_compute_diff_index_impl<H: BlockHash, W: WeakHash, I: BlockIndex<H>>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    index: &I,
+    hasher: &impl StrongHasher<H>,
+    block_size: usize,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> Result<PatchCommands, PatchError> {
+    let mut scanner = DiffScanner::<H, W, I>::with_index(index, block_size).with_hasher(hasher);
+    match progress {
+        None => scanner.feed(input),
+        Some(progress) => {
+            for chunk in input.chunks(DIFF_PROGRESS_INTERVAL) {
+                scanner.feed(chunk);
+                progress(scanner.window_begin());
+            }
+        }
+    }
+    scanner.finish();
+    let mut base_blocks = scanner.into_base_blocks();
+    match_tail_block(input, other_blocks, block_size, hasher, &mut base_blocks);
+    Ok(build_patch_commands(
+        input.len(),
+        block_size,
+        other_blocks,
+        &base_blocks,
+    ))
+}
+
+// Buffers for `compute_diff_reuse`, kept between calls so that diffing many small files doesn't
+// allocate the hash sets and maps again for each one.
+pub struct DiffContext<H = Hash128> {
+    other_set: OtherBlockSet<H>,
+    window: VecDeque<u8>,
+    base_blocks: Vec<Block<H>>,
+    base_block_hash_map: BaseBlockMap<H>,
+}
+
+impl<H: BlockHash> DiffContext<H> {
+    pub fn new() -> Self {
+        Self {
+            other_set: OtherBlockSet::new(&[]),
+            window: VecDeque::new(),
+            base_blocks: Vec::new(),
+            base_block_hash_map: HashMap::default(),
+        }
+    }
+}
+
+impl<H: BlockHash> Default for DiffContext<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Same result as `compute_diff`, using the buffers of `ctx`.
+pub fn compute_diff_reuse<H: BlockHash>(
+    ctx: &mut DiffContext<H>,
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> PatchCommands {
+    try_compute_diff_reuse(ctx, input, other_blocks, block_size).unwrap()
+}
+
+pub fn try_compute_diff_reuse<H: BlockHash>(
+    ctx: &mut DiffContext<H>,
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+) -> Result<PatchCommands, PatchError> {
+    check_block_size(block_size)?;
+    check_other_blocks(other_blocks, block_size)?;
+    ctx.other_set.rebuild(other_blocks);
+    ctx.base_blocks.clear();
+    let mut scanner = DiffScanner::<H, RollingHash, _>::with_buffers(
+        &ctx.other_set,
+        block_size,
+        0,
+        std::mem::take(&mut ctx.window),
+        std::mem::take(&mut ctx.base_blocks),
+    );
+    scanner.feed(input);
+    scanner.finish();
+    let (window, mut base_blocks) = scanner.into_buffers();
+    ctx.window = window;
+    match_tail_block(
+        input,
+        other_blocks,
+        block_size,
+        &Blake3Hasher,
+        &mut base_blocks,
+    );
+    let patch_commands = build_patch_commands_reuse(
+        input.len(),
+        block_size,
+        other_blocks,
+        &base_blocks,
+        &mut ctx.base_block_hash_map,
+    );
+    ctx.base_blocks = base_blocks;
+    Ok(patch_commands)
+}
+
+// Returns `None` when the patch would need more than `max_literal_bytes` from `other`. The scan
+// stops as soon as the bytes of `other` not matched so far can no longer be covered by the rest
+// of the input, which is at most one block group per remaining block of input.
+pub fn compute_diff_budgeted<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    max_literal_bytes: usize,
+) -> Option<PatchCommands> {
+    check_block_size(block_size).unwrap();
+    check_other_blocks(other_blocks, block_size).unwrap();
+    let other_set = OtherBlockSet::new(other_blocks);
+    // Total size of the `other` blocks sharing each strong hash.
+    let mut unmatched_groups: HashMap<H, u64> = HashMap::default();
+    for block in other_blocks {
+        *unmatched_groups.entry(block.hash_strong).or_default() += block.size as u64;
+    }
+    let max_group_size = unmatched_groups.values().cloned().max().unwrap_or(0);
+    let mut unmatched_bytes = other_set.len as u64;
+    let mut scanner = DiffScanner::new(&other_set, block_size);
+    for (i, &x) in input.iter().enumerate() {
+        if scanner.step(x) {
+            let hash = scanner.base_blocks().last().unwrap().hash_strong;
+            if let Some(group_size) = unmatched_groups.remove(&hash) {
+                unmatched_bytes -= group_size;
+            }
+        }
+        let remaining_blocks = ((input.len() - i - 1) / block_size + 2) as u64;
+        let reachable_bytes = remaining_blocks.saturating_mul(max_group_size);
+        if unmatched_bytes.saturating_sub(reachable_bytes) > max_literal_bytes as u64 {
+            return None;
+        }
+    }
+    scanner.finish();
+    let mut base_blocks = scanner.into_base_blocks();
+    match_tail_block(
+        input,
+        other_blocks,
+        block_size,
+        &Blake3Hasher,
+        &mut base_blocks,
+    );
+    let patch_commands = build_patch_commands(input.len(), block_size, other_blocks, &base_blocks);
+    if patch_commands.need_bytes_from_other() > max_literal_bytes {
+        return None;
+    }
+    Some(patch_commands)
+}
+
+// Splits `input` into `shards` block-aligned ranges that are scanned independently.
+// Matches that would straddle a shard boundary are not found and end up as literals.
+// Without the `parallel` feature the shards are scanned one after another.
+pub fn compute_diff_parallel<H: BlockHash>(
+    input: &[u8],
+    other_blocks: &[Block<H>],
+    block_size: usize,
+    shards: usize,
+) -> PatchCommands {
+    check_block_size(block_size).unwrap();
+    check_other_blocks(other_blocks, block_size).unwrap();
+    let other_set = OtherBlockSet::new(other_blocks);
+    let shard_size = div_up(div_up(input.len(), shards.max(1)), block_size).max(1) * block_size;
+    let shard_ranges: Vec<(usize, usize)> = (0..input.len())
+        .step_by(shard_size)
+        .map(|shard_begin| (shard_begin, min(shard_begin + shard_size, input.len())))
+        .collect();
+    #[cfg(feature = "parallel")]
+    let shard_iter = shard_ranges.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let shard_iter = shard_ranges.iter();
+    let mut base_blocks: Vec<Block<H>> = shard_iter
+        .map(|&(shard_begin, shard_end)| {
+            scan_base_blocks(input, shard_begin, shard_end, block_size, &other_set)
+        })
+        .flatten()
+        .collect();
+    match_tail_block(
+        input,
+        other_blocks,
+        block_size,
+        &Blake3Hasher,
+        &mut base_blocks,
+    );
+    build_patch_commands(input.len(), block_size, other_blocks, &base_blocks)
+}
+
+pub(crate) fn push_literal_cmds(cmds: &mut Vec<CopyCmd>, begin: usize, end: usize) {
+    let copy = LogicalCopy {
+        source: begin as u64,
+        target: begin as u64,
+        size: (end - begin) as u64,
+    };
+    copy.lower_into(cmds, 0);
+}
+
+// Scans `input` (the new data) for blocks of several base signatures. Base copies carry the
+// index of the signature in `bases` as their `source_id`; unmatched bytes become literals.
+pub fn compute_diff_multi<H: BlockHash>(
+    input: &[u8],
+    bases: &[&[Block<H>]],
+    block_size: usize,
+) -> PatchCommands {
+    check_block_size(block_size).unwrap();
+    for blocks in bases {
+        check_other_blocks(blocks, block_size).unwrap();
+    }
+    let index = MultiBaseBlockSet::new(bases);
+    let mut scanner = DiffScanner::<H, RollingHash, _>::with_index(&index, block_size);
+    scanner.feed(input);
+    scanner.finish();
+    let mut patch_commands = PatchCommands::with_block_size(block_size);
+    let mut literal_begin: usize = 0;
+    for block in scanner.base_blocks() {
+        let (source_id, source) = index.locate(&block.hash_strong, block.size).unwrap();
+        push_literal_cmds(
+            &mut patch_commands.other,
+            literal_begin,
+            block.offset as usize,
+        );
+        patch_commands.base.push(CopyCmd {
+            source,
+            target: block.offset,
+            size: block.size,
+            source_id,
+        });
+        literal_begin = (block.offset + block.size as u64) as usize;
+    }
+    push_literal_cmds(&mut patch_commands.other, literal_begin, input.len());
+    patch_commands
+}
+
+// Literal command for output `begin..end`, whose bytes are the last ones of the `written` literal
+// bytes so far.
+fn push_streamed_literal(cmds: &mut Vec<CopyCmd>, written: u64, begin: u64, end: u64) {
+    let copy = LogicalCopy {
+        source: written - (end - begin),
+        target: begin,
+        size: end - begin,
+    };
+    copy.lower_into(cmds, 0);
+}
+
+// Moves the pending input bytes before `end` to `literals` and returns how many there were.
+fn move_streamed_literals(
+    pending: &mut Vec<u8>,
+    pending_begin: &mut u64,
+    end: u64,
+    literals: &mut Vec<u8>,
+) -> u64 {
+    let len = end - *pending_begin;
+    literals.extend(pending.drain(..len as usize));
+    *pending_begin = end;
+    len
+}
+
+// Read and write buffer size of `compute_diff_streaming`.
+const DIFF_STREAMING_CHUNK_SIZE: usize = 64 * 1024;
+
+// Streaming form of `compute_diff_multi` for a single base. `input` is read in chunks of
+// DIFF_STREAMING_CHUNK_SIZE and fed to a `DiffScanner`. Of its bytes, only those of the last
+// chunk and the scanner window of at most `block_size` are kept until they are matched or known
+// to be literals. Memory use is bounded by these, the base signature and the command list,
+// whatever the length of `input`.
+// Literal bytes are written to `out` in output order, at most once per chunk, instead of being
+// collected in `data`. The returned patch has empty `data` and is complete once `data` is set
+// to the bytes written to `out`.
+pub fn compute_diff_streaming<H: BlockHash>(
+    input: &mut impl Read,
+    base_blocks: &[Block<H>],
+    block_size: usize,
+    out: &mut impl Write,
+) -> io::Result<Patch> {
+    let invalid_input = |e: PatchError| io::Error::new(io::ErrorKind::InvalidInput, e);
+    check_block_size(block_size).map_err(invalid_input)?;
+    check_other_blocks(base_blocks, block_size).map_err(invalid_input)?;
+    let index = MultiBaseBlockSet::new(&[base_blocks]);
+    let mut scanner = DiffScanner::<H, RollingHash, _>::with_index(&index, block_size);
+    let mut base: Vec<CopyCmd> = Vec::new();
+    let mut other: Vec<CopyCmd> = Vec::new();
+    let mut hasher_blake3 = blake3::Hasher::new();
+    let mut read_buffer: Vec<u8> = vec![0; DIFF_STREAMING_CHUNK_SIZE];
+    // Input bytes from `pending_begin` on, which are neither copied nor written to `out` yet.
+    let mut pending: Vec<u8> = Vec::with_capacity(DIFF_STREAMING_CHUNK_SIZE + block_size);
+    let mut pending_begin: u64 = 0;
+    let mut literal_buffer: Vec<u8> = Vec::with_capacity(DIFF_STREAMING_CHUNK_SIZE + block_size);
+    let mut literal_bytes: u64 = 0;
+    let mut literal_begin: u64 = 0;
+    loop {
+        let read_len = match input.read(&mut read_buffer) {
+            Ok(read_len) => read_len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let chunk = &read_buffer[..read_len];
+        hasher_blake3.update(chunk);
+        pending.extend_from_slice(chunk);
+        if read_len == 0 {
+            scanner.finish();
+        } else {
+            scanner.feed(chunk);
+        }
+        for block in scanner.base_blocks.drain(..) {
+            literal_bytes += move_streamed_literals(
+                &mut pending,
+                &mut pending_begin,
+                block.offset,
+                &mut literal_buffer,
+            );
+            push_streamed_literal(&mut other, literal_bytes, literal_begin, block.offset);
+            let (_, source) = index.locate(&block.hash_strong, block.size).unwrap();
+            base.push(CopyCmd {
+                source,
+                target: block.offset,
+                size: block.size,
+                source_id: 0,
+            });
+            pending.drain(..block.size as usize);
+            pending_begin += block.size as u64;
+            literal_begin = pending_begin;
+        }
+        // Bytes that slid out of the window are literals.
+        literal_bytes += move_streamed_literals(
+            &mut pending,
+            &mut pending_begin,
+            scanner.window_begin(),
+            &mut literal_buffer,
+        );
+        out.write_all(&literal_buffer)?;
+        literal_buffer.clear();
+        if read_len == 0 {
+            break;
+        }
+    }
+    push_streamed_literal(&mut other, literal_bytes, literal_begin, pending_begin);
+    let mut result = Patch {
+        data: Vec::new(),
+        base,
+        other,
+        other_size: Some(pending_begin),
+        compression: Compression::None,
+        other_hash: Some(Hash128::new_from_blake3(&hasher_blake3.finalize())),
+        base_hash: None,
+        block_size,
+    };
+    optimize_copy_cmds(&mut result.base);
+    optimize_copy_cmds(&mut result.other);
+    Ok(result)
+}
+
+// Compresses literal runs of at least `min_compress_len` bytes one by one and stores shorter
+// runs raw, since a few scattered bytes only grow when compressed. A run is also stored raw
+// when compressing it doesn't make it smaller.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiteralEncoding {
+    pub level: i32,
+    pub min_compress_len: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd {
+        level: i32,
+    },
+    PerRun(LiteralEncoding),
+}
+
+// Stores the literal runs of `data` in source order, each as is or as a zstd frame of its own.
+// The stored runs are preceded by one bit per run, in the same order and set for zstd frames,
+// so that the commands in `other` keep their `source_id` of 0. Sources stay offsets into the
+// uncompressed literals.
+fn encode_literal_runs(
+    data: &[u8],
+    other: &[CopyCmd],
+    encoding: LiteralEncoding,
+) -> Result<Vec<u8>, PatchError> {
+    let mut order: Vec<&CopyCmd> = other.iter().collect();
+    order.sort_by_key(|cmd| cmd.source);
+    let tags_len = div_up(order.len(), 8);
+    let mut stored: Vec<u8> = Vec::with_capacity(tags_len + data.len());
+    stored.resize(tags_len, 0);
+    for (i, cmd) in order.into_iter().enumerate() {
+        let run_begin = checked_usize(cmd.source)?;
+        let run = &data[run_begin..run_begin + cmd.size as usize];
+        if cmd.size >= encoding.min_compress_len {
+            let compressed = zstd::stream::encode_all(run, encoding.level)
+                .map_err(|e| PatchError::Compression(e.to_string()))?;
+            if compressed.len() < run.len() {
+                stored.extend_from_slice(&compressed);
+                stored[i / 8] |= 1 << (i % 8);
+                continue;
+            }
+        }
+        stored.extend_from_slice(run);
+    }
+    Ok(stored)
+}
+
+// Whether each literal run of a `Compression::PerRun` patch, in source order, is stored as a
+// zstd frame, with the stored runs that follow the flags.
+pub(crate) fn literal_run_flags(
+    data: &[u8],
+    run_count: usize,
+) -> Result<(Vec<bool>, &[u8]), PatchError> {
+    let tags_len = div_up(run_count, 8);
+    if data.len() < tags_len {
+        return Err(PatchError::Compression(format!(
+            "{} bytes of literal run flags for {} runs",
+            data.len(),
+            run_count
+        )));
+    }
+    let (tags, stored) = data.split_at(tags_len);
+    let flags = (0..run_count)
+        .map(|i| tags[i / 8] & (1 << (i % 8)) != 0)
+        .collect();
+    Ok((flags, stored))
+}
+
+// Inverse of `encode_literal_runs`. The runs must cover the literals without gaps, as zstd
+// frames are self-delimiting but raw runs are not.
+fn decode_literal_runs(data: &[u8], other: &[CopyCmd]) -> Result<Vec<u8>, PatchError> {
+    let mut order: Vec<&CopyCmd> = other.iter().collect();
+    order.sort_by_key(|cmd| cmd.source);
+    let (flags, mut stored) = literal_run_flags(data, order.len())?;
+    let mut literals: Vec<u8> = Vec::new();
+    for (cmd, compressed) in order.into_iter().zip(flags) {
+        if cmd.source != literals.len() as u64 {
+            return Err(PatchError::Compression(format!(
+                "literal run at {} does not follow the previous run",
+                cmd.source
+            )));
+        }
+        if compressed {
+            let run_begin = literals.len();
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(stored)
+                .map_err(|e| PatchError::Compression(e.to_string()))?
+                .single_frame();
+            // One byte past the run is enough to tell that it is too long.
+            (&mut decoder)
+                .take(cmd.size as u64 + 1)
+                .read_to_end(&mut literals)
+                .map_err(|e| PatchError::Compression(e.to_string()))?;
+            if literals.len() - run_begin != cmd.size as usize {
+                return Err(PatchError::Compression(format!(
+                    "literal run at {} decompressed to {} bytes instead of {}",
+                    cmd.source,
+                    literals.len() - run_begin,
+                    cmd.size
+                )));
+            }
+            stored = decoder.finish();
+        } else {
+            if stored.len() < cmd.size as usize {
+                return Err(PatchError::Compression(format!(
+                    "literal run at {} is truncated",
+                    cmd.source
+                )));
+            }
+            let (run, rest) = stored.split_at(cmd.size as usize);
+            literals.extend_from_slice(run);
+            stored = rest;
+        }
+    }
+    if !stored.is_empty() {
+        return Err(PatchError::Compression(format!(
+            "{} bytes after the last literal run",
+            stored.len()
+        )));
+    }
+    Ok(literals)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Patch {
+    pub data: Vec<u8>,
+    pub base: Vec<CopyCmd>,
+    pub other: Vec<CopyCmd>,
+    // `None` for a patch built before the size of its output was known, e.g. from a stream.
+    // Such a patch is applied as if its output ended with its last command, see `output_size`.
+    pub other_size: Option<u64>,
+    pub compression: Compression,
+    pub other_hash: Option<Hash128>,
+    // Strong hash of the base the patch was diffed against, checked before applying.
+    pub base_hash: Option<Hash128>,
+    pub block_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PatchStats {
+    pub literal_bytes: u64,
+    pub base_bytes: u64,
+    pub base_commands: usize,
+    pub other_commands: usize,
+    pub other_size: u64,
+    // Fraction of the output copied from base. Zero for an empty output.
+    pub reuse_ratio: f64,
+}
+
+const PATCH_MAGIC: [u8; 4] = *b"PTCY";
+const PATCH_FORMAT_VERSION: u16 = 6;
+const CONTAINER_HEADER_LEN: usize = 4 + 2 + 8;
+// Smallest encoded command: four one-byte varints.
+const PATCH_CMD_MIN_ENCODED_LEN: usize = 4;
+
+// LEB128: seven bits per byte, least significant group first, high bit set on all but the last.
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Maps a wrapping difference to an unsigned value so that small steps either way stay short.
+fn zigzag_delta(value: u64, previous: u64) -> u64 {
+    let delta = value.wrapping_sub(previous) as i64;
+    ((delta << 1) ^ (delta >> 63)) as u64
+}
+
+fn unzigzag_delta(encoded: u64, previous: u64) -> u64 {
+    let delta = ((encoded >> 1) as i64) ^ -((encoded & 1) as i64);
+    previous.wrapping_add(delta as u64)
+}
+
+fn write_cmds(out: &mut Vec<u8>, cmds: &[CopyCmd]) {
+    write_varint(out, cmds.len() as u64);
+    let (mut source_end, mut target_end) = (0u64, 0u64);
+    for cmd in cmds {
+        write_varint(out, zigzag_delta(cmd.source, source_end));
+        write_varint(out, zigzag_delta(cmd.target, target_end));
+        write_varint(out, cmd.size as u64);
+        write_varint(out, cmd.source_id as u64);
+        source_end = cmd.source.wrapping_add(cmd.size as u64);
+        target_end = cmd.target.wrapping_add(cmd.size as u64);
+    }
+}
+
+fn varint_len(value: u64) -> usize {
+    (64 - value.leading_zeros() as usize).max(1).div_ceil(7)
+}
+
+// Length of what `write_cmds` writes for `cmds`.
+fn encoded_cmds_len(cmds: &[CopyCmd]) -> usize {
+    let mut result = varint_len(cmds.len() as u64);
+    let (mut source_end, mut target_end) = (0u64, 0u64);
+    for cmd in cmds {
+        result += varint_len(zigzag_delta(cmd.source, source_end))
+            + varint_len(zigzag_delta(cmd.target, target_end))
+            + varint_len(cmd.size as u64)
+            + varint_len(cmd.source_id as u64);
+        source_end = cmd.source.wrapping_add(cmd.size as u64);
+        target_end = cmd.target.wrapping_add(cmd.size as u64);
+    }
+    result
+}
+
+fn write_optional_hash(out: &mut Vec<u8>, hash: &Option<Hash128>) {
+    match hash {
+        None => out.push(0),
+        Some(hash) => {
+            out.push(1);
+            out.extend_from_slice(hash.as_bytes());
+        }
+    }
+}
+
+pub(crate) struct BodyReader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> BodyReader<'a> {
+    pub(crate) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        if self.bytes.len() < len {
+            return Err(PatchError::Serialization(
+                "unexpected end of patch body".to_string(),
+            ));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+    pub(crate) fn read_u8(&mut self) -> Result<u8, PatchError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+    fn read_u32(&mut self) -> Result<u32, PatchError> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+    pub(crate) fn read_u64(&mut self) -> Result<u64, PatchError> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+    fn read_optional_u64(&mut self) -> Result<Option<u64>, PatchError> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.read_u64()?)),
+            flag => Err(PatchError::Serialization(format!(
+                "unknown size flag {}",
+                flag
+            ))),
+        }
+    }
+    fn read_optional_hash(&mut self) -> Result<Option<Hash128>, PatchError> {
+        match self.read_u8()? {
+            0 => Ok(None),
+            1 => {
+                let mut hash = [0u8; 16];
+                hash.copy_from_slice(self.read_bytes(16)?);
+                Ok(Some(Hash128::from_bytes(&hash)))
+            }
+            flag => Err(PatchError::Serialization(format!(
+                "unknown hash flag {}",
+                flag
+            ))),
+        }
+    }
+    pub(crate) fn read_varint(&mut self) -> Result<u64, PatchError> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(PatchError::Serialization(
+                    "varint overflows u64".to_string(),
+                ));
+            }
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+    fn read_varint_u32(&mut self) -> Result<u32, PatchError> {
+        let value = self.read_varint()?;
+        if value > u32::MAX as u64 {
+            return Err(PatchError::Serialization(
+                "varint overflows u32".to_string(),
+            ));
+        }
+        Ok(value as u32)
+    }
+    fn read_cmds(&mut self) -> Result<Vec<CopyCmd>, PatchError> {
+        let count = self.read_varint()?;
+        // Reject counts the remaining body cannot hold before allocating for them.
+        if count > (self.bytes.len() / PATCH_CMD_MIN_ENCODED_LEN) as u64 {
+            return Err(PatchError::Serialization(
+                "unexpected end of patch body".to_string(),
+            ));
+        }
+        let mut cmds = Vec::with_capacity(count as usize);
+        let (mut source_end, mut target_end) = (0u64, 0u64);
+        for _ in 0..count {
+            let source = unzigzag_delta(self.read_varint()?, source_end);
+            let target = unzigzag_delta(self.read_varint()?, target_end);
+            let size = self.read_varint_u32()?;
+            let source_id = self.read_varint_u32()?;
+            source_end = source.wrapping_add(size as u64);
+            target_end = target.wrapping_add(size as u64);
+            cmds.push(CopyCmd {
+                source,
+                target,
+                size,
+                source_id,
+            });
+        }
+        Ok(cmds)
+    }
+}
+
+// Wraps `body` as magic (4 bytes), format version (u16), body length (u64), body, CRC32 of the
+// body (u32).
+pub(crate) fn encode_container(magic: &[u8; 4], version: u16, body: &[u8]) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::with_capacity(CONTAINER_HEADER_LEN + body.len() + 4);
+    result.extend_from_slice(magic);
+    result.extend_from_slice(&version.to_le_bytes());
+    result.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    result.extend_from_slice(body);
+    result.extend_from_slice(&compute_crc32(body).to_le_bytes());
+    result
+}
+
+// Checks the framing written by `encode_container` and returns the body.
+pub(crate) fn decode_container<'a>(
+    bytes: &'a [u8],
+    magic: &[u8; 4],
+    version: u16,
+) -> Result<&'a [u8], PatchError> {
+    if bytes.len() < magic.len() || bytes[0..magic.len()] != magic[..] {
+        return Err(PatchError::BadMagic);
+    }
+    if bytes.len() < CONTAINER_HEADER_LEN {
+        return Err(PatchError::LengthMismatch {
+            expected: CONTAINER_HEADER_LEN as u64,
+            actual: bytes.len() as u64,
+        });
+    }
+    let mut version_bytes = [0u8; 2];
+    version_bytes.copy_from_slice(&bytes[4..6]);
+    let actual_version = u16::from_le_bytes(version_bytes);
+    if actual_version != version {
+        return Err(PatchError::UnsupportedVersion {
+            version: actual_version as u32,
+        });
+    }
+    let mut len_bytes = [0u8; 8];
+    len_bytes.copy_from_slice(&bytes[6..CONTAINER_HEADER_LEN]);
+    let body_len = u64::from_le_bytes(len_bytes);
+    let expected_len = body_len.saturating_add((CONTAINER_HEADER_LEN + 4) as u64);
+    if expected_len != bytes.len() as u64 {
+        return Err(PatchError::LengthMismatch {
+            expected: expected_len,
+            actual: bytes.len() as u64,
+        });
+    }
+    let body = &bytes[CONTAINER_HEADER_LEN..bytes.len() - 4];
+    let mut crc_bytes = [0u8; 4];
+    crc_bytes.copy_from_slice(&bytes[bytes.len() - 4..]);
+    let expected_crc = u32::from_le_bytes(crc_bytes);
+    let actual_crc = compute_crc32(body);
+    if actual_crc != expected_crc {
+        return Err(PatchError::ChecksumMismatch {
+            expected: expected_crc,
+            actual: actual_crc,
+        });
+    }
+    Ok(body)
+}
+
+impl Patch {
+    // Layout: magic (4 bytes), format version (u16), body length (u64), body, CRC32 of the
+    // body (u32). The body does not depend on the serde backend:
+    //   other_size, a flag (u8: 0 = unknown, 1 = known) followed by the size (u64) if known,
+    //   block_size (u64),
+    //   compression tag (u8: 0 = none, 1 = zstd, 2 = per run) followed by the zstd level (i32)
+    //     if tagged 1 or 2 and the minimum run length to compress (u32) if tagged 2,
+    //   other_hash and base_hash, each a flag (u8: 0 = absent, 1 = present) followed by 16
+    //     hash bytes if present,
+    //   base and other command lists, each a count (varint) followed by
+    //     source delta, target delta, size and source_id per command, all varints,
+    //   data length (u64) followed by the data bytes; in a per-run patch these start with one
+    //     bit per literal run telling whether it is compressed, see `encode_literal_runs`.
+    // Varints are LEB128. The source and target deltas are taken from the end of the previous
+    // command in the same list (0 for the first) and zigzag-encoded, so contiguous commands cost
+    // one byte each. Fixed-width integers are little-endian on every platform.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::new();
+        self.encode_into(&mut result);
+        result
+    }
+    // Appends the encoding to `out`, reserving `serialized_size()` bytes first.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        let body_len = self.encoded_body_len();
+        out.reserve(CONTAINER_HEADER_LEN + body_len + 4);
+        out.extend_from_slice(&PATCH_MAGIC);
+        out.extend_from_slice(&PATCH_FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&(body_len as u64).to_le_bytes());
+        let body_begin = out.len();
+        self.encode_body(out);
+        debug_assert_eq!(out.len() - body_begin, body_len);
+        let crc = compute_crc32(&out[body_begin..]);
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
+    // Exact length of `encode()`, computed without encoding.
+    pub fn serialized_size(&self) -> usize {
+        CONTAINER_HEADER_LEN + self.encoded_body_len() + 4
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Patch, PatchError> {
+        Patch::decode_body(decode_container(bytes, &PATCH_MAGIC, PATCH_FORMAT_VERSION)?)
+    }
+    fn encoded_body_len(&self) -> usize {
+        let compression_len = match self.compression {
+            Compression::None => 1,
+            Compression::Zstd { .. } => 1 + 4,
+            Compression::PerRun(_) => 1 + 4 + 4,
+        };
+        let hash_len = |hash: &Option<Hash128>| 1 + hash.map_or(0, |hash| hash.as_bytes().len());
+        1 + self.other_size.map_or(0, |_| 8)
+            + 8
+            + compression_len
+            + hash_len(&self.other_hash)
+            + hash_len(&self.base_hash)
+            + encoded_cmds_len(&self.base)
+            + encoded_cmds_len(&self.other)
+            + 8
+            + self.data.len()
+    }
+    fn encode_body(&self, body: &mut Vec<u8>) {
+        match self.other_size {
+            None => body.push(0),
+            Some(other_size) => {
+                body.push(1);
+                body.extend_from_slice(&other_size.to_le_bytes());
+            }
+        }
+        body.extend_from_slice(&(self.block_size as u64).to_le_bytes());
+        match self.compression {
+            Compression::None => body.push(0),
+            Compression::Zstd { level } => {
+                body.push(1);
+                body.extend_from_slice(&level.to_le_bytes());
+            }
+            Compression::PerRun(encoding) => {
+                body.push(2);
+                body.extend_from_slice(&encoding.level.to_le_bytes());
+                body.extend_from_slice(&encoding.min_compress_len.to_le_bytes());
+            }
+        }
+        write_optional_hash(body, &self.other_hash);
+        write_optional_hash(body, &self.base_hash);
+        write_cmds(body, &self.base);
+        write_cmds(body, &self.other);
+        body.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+        body.extend_from_slice(&self.data);
+    }
+    fn decode_body(body: &[u8]) -> Result<Patch, PatchError> {
+        let mut reader = BodyReader { bytes: body };
+        let other_size = reader.read_optional_u64()?;
+        let block_size = checked_usize(reader.read_u64()?)?;
+        let compression = match reader.read_u8()? {
+            0 => Compression::None,
+            1 => Compression::Zstd {
+                level: reader.read_u32()? as i32,
+            },
+            2 => Compression::PerRun(LiteralEncoding {
+                level: reader.read_u32()? as i32,
+                min_compress_len: reader.read_u32()?,
+            }),
+            tag => {
+                return Err(PatchError::Serialization(format!(
+                    "unknown compression tag {}",
+                    tag
+                )))
+            }
+        };
+        let other_hash = reader.read_optional_hash()?;
+        let base_hash = reader.read_optional_hash()?;
+        let base = reader.read_cmds()?;
+        let other = reader.read_cmds()?;
+        let data_len = checked_usize(reader.read_u64()?)?;
+        let data = reader.read_bytes(data_len)?.to_vec();
+        if !reader.bytes.is_empty() {
+            return Err(PatchError::Serialization(format!(
+                "{} trailing bytes after patch body",
+                reader.bytes.len()
+            )));
+        }
+        Ok(Patch {
+            data,
+            base,
+            other,
+            other_size,
+            compression,
+            other_hash,
+            base_hash,
+            block_size,
+        })
+    }
+    pub fn decompressed_data(&self) -> Result<Cow<'_, [u8]>, PatchError> {
+        match self.compression {
+            Compression::None => Ok(Cow::Borrowed(&self.data)),
+            Compression::Zstd { .. } => {
+                // Literals past the end of the last run are never read, so decompression stops
+                // one byte after it.
+                let expected = self
+                    .other
+                    .iter()
+                    .map(|cmd| cmd.source.saturating_add(cmd.size as u64))
+                    .max()
+                    .unwrap_or(0);
+                let mut data: Vec<u8> = Vec::new();
+                zstd::stream::read::Decoder::new(self.data.as_slice())
+                    .and_then(|decoder| {
+                        decoder
+                            .take(expected.saturating_add(1))
+                            .read_to_end(&mut data)
+                    })
+                    .map_err(|e| PatchError::Compression(e.to_string()))?;
+                if data.len() as u64 > expected {
+                    return Err(PatchError::Compression(format!(
+                        "literals decompress to more than the {} bytes the commands read",
+                        expected
+                    )));
+                }
+                Ok(Cow::Owned(data))
+            }
+            Compression::PerRun(_) => decode_literal_runs(&self.data, &self.other).map(Cow::Owned),
+        }
+    }
+    // `other_size`, or the end of the last command if it's unknown.
+    pub fn output_size(&self) -> u64 {
+        if let Some(other_size) = self.other_size {
+            return other_size;
+        }
+        self.base
+            .iter()
+            .chain(&self.other)
+            .map(|cmd| cmd.target.saturating_add(cmd.size as u64))
+            .max()
+            .unwrap_or(0)
+    }
+    // `output_size` as a length to allocate. Output past the end of the last command could only
+    // be left unwritten, so a patch that claims such a size fails with `CoverageGap` at that end
+    // before anything is allocated.
+    pub(crate) fn checked_output_size(&self) -> Result<usize, PatchError> {
+        let other_size = self.output_size();
+        let end = self
+            .base
+            .iter()
+            .chain(&self.other)
+            .map(|cmd| cmd.target.saturating_add(cmd.size as u64))
+            .max()
+            .unwrap_or(0);
+        if other_size > end {
+            return Err(PatchError::CoverageGap { offset: end });
+        }
+        checked_usize(other_size)
+    }
+    // `literal_bytes` is the stored size of `data`, i.e. after compression.
+    pub fn stats(&self) -> PatchStats {
+        let base_bytes: u64 = self.base.iter().map(|cmd| cmd.size as u64).sum();
+        let other_size = self.output_size();
+        let reuse_ratio = if other_size == 0 {
+            0.0
+        } else {
+            base_bytes as f64 / other_size as f64
+        };
+        PatchStats {
+            literal_bytes: self.data.len() as u64,
+            base_bytes,
+            base_commands: self.base.len(),
+            other_commands: self.other.len(),
+            other_size,
+            reuse_ratio,
+        }
+    }
+    // Fraction of the output copied rather than taken from `data`, the same as
+    // `stats().reuse_ratio`. Zero for an empty output.
+    pub fn efficiency(&self) -> f64 {
+        self.stats().reuse_ratio
+    }
+    // Fraction of the output taken from `data`, counted before compression. For a patch that
+    // covers its output exactly once, `efficiency() + literal_ratio()` is 1.
+    pub fn literal_ratio(&self) -> f64 {
+        let other_size = self.output_size();
+        if other_size == 0 {
+            return 0.0;
+        }
+        let literal_bytes: u64 = self.other.iter().map(|cmd| cmd.size as u64).sum();
+        literal_bytes as f64 / other_size as f64
+    }
+    // Sorted ranges of the base that applying the patch reads, with overlapping and adjacent
+    // ranges merged. Self-copies read the output and copies from other bases read other data,
+    // so only commands with `source_id` 0 count.
+    pub fn base_read_ranges(&self) -> Vec<Range<u64>> {
+        let mut sources: Vec<Range<u64>> = self
+            .base
+            .iter()
+            .filter(|cmd| cmd.source_id == 0 && cmd.size != 0)
+            .map(|cmd| cmd.source..cmd.source + cmd.size as u64)
+            .collect();
+        sources.sort_by_key(|range| range.start);
+        let mut ranges: Vec<Range<u64>> = Vec::with_capacity(sources.len());
+        for source in sources {
+            match ranges.last_mut() {
+                Some(last) if source.start <= last.end => last.end = last.end.max(source.end),
+                _ => ranges.push(source),
+            }
+        }
+        ranges
+    }
+    // Builds the patch that turns `result_data` (the output of this patch) back into `base_data`,
+    // by diffing the two with this patch's block size and compression.
+    pub fn invert(&self, base_data: &[u8], result_data: &[u8]) -> Patch {
+        self.try_invert(base_data, result_data).unwrap()
+    }
+    pub fn try_invert(&self, base_data: &[u8], result_data: &[u8]) -> Result<Patch, PatchError> {
+        if result_data.len() as u64 != self.output_size() {
+            return Err(PatchError::LengthMismatch {
+                expected: self.output_size(),
+                actual: result_data.len() as u64,
+            });
+        }
+        let base_blocks = try_compute_blocks(base_data, self.block_size)?;
+        let mut patch_commands = try_compute_diff(result_data, &base_blocks, self.block_size)?;
+        patch_commands.expand_synchronized(base_data.len());
+        let mut result = build_patch_compressed(base_data, &patch_commands, self.compression)?;
+        result.base_hash = Some(compute_hash_strong(result_data));
+        Ok(result)
+    }
+    // Fails with `WrongBase` when the patch records a base hash that `base_data` doesn't match.
+    pub fn check_base(&self, base_data: &[u8]) -> Result<(), PatchError> {
+        if let Some(expected) = self.base_hash {
+            let actual = compute_hash_strong(base_data);
+            if !actual.matches(&expected) {
+                return Err(PatchError::WrongBase { expected, actual });
+            }
+        }
+        Ok(())
+    }
+    pub fn validate_coverage(&self) -> Result<(), PatchError> {
+        let mut ranges: Vec<(u64, u32)> = self
+            .base
+            .iter()
+            .chain(self.other.iter())
+            .filter(|cmd| cmd.size != 0)
+            .map(|cmd| (cmd.target, cmd.size))
+            .collect();
+        ranges.sort_unstable();
+        let other_size = self.output_size();
+        let mut position: u64 = 0;
+        for (target, size) in ranges {
+            if target > position {
+                return Err(PatchError::CoverageGap { offset: position });
+            } else if target < position {
+                return Err(PatchError::CoverageOverlap { offset: target });
+            }
+            position = target.saturating_add(size as u64);
+            if position > other_size {
+                return Err(PatchError::TargetOutOfRange {
+                    offset: target,
+                    size,
+                    len: saturating_usize(other_size),
+                });
+            }
+        }
+        if position != other_size {
+            return Err(PatchError::CoverageGap { offset: position });
+        }
+        Ok(())
+    }
+}
+
+// Trims commands sorted by target so that no two of them write the same output byte. Where
+// ranges overlap, the command later in the order wins. An earlier command that extends past a
+// later one keeps its remainder after it as a separate command.
+fn resolve_overlaps<T: Copy>(cmds: &mut Vec<(CopyCmd, T)>) {
+    let mut result: Vec<(CopyCmd, T)> = Vec::with_capacity(cmds.len());
+    let mut tails: Vec<(CopyCmd, T)> = Vec::new();
+    for (curr, tag) in cmds.drain(..) {
+        let curr_end = curr.target + curr.size as u64;
+        let mut head: Option<(CopyCmd, T)> = None;
+        // `result` is sorted and free of overlaps, so the commands that reach into `curr` are
+        // the last ones, and only the first of them can start before it.
+        while let Some((last, last_tag)) = result.pop() {
+            let last_end = last.target + last.size as u64;
+            if last_end <= curr.target {
+                result.push((last, last_tag));
+                break;
+            }
+            // The remainder of a command split by an earlier one can start past `curr`.
+            if last.target >= curr_end {
+                tails.push((last, last_tag));
+                continue;
+            }
+            if last_end > curr_end {
+                tails.push((
+                    last.slice(curr_end - last.target, last.size as u64),
+                    last_tag,
+                ));
+            }
+            if last.target < curr.target {
+                head = Some((last.slice(0, curr.target - last.target), last_tag));
+            }
+        }
+        result.extend(head);
+        result.push((curr, tag));
+        result.extend(tails.drain(..).rev());
+    }
+    *cmds = result;
+}
+
+// Merges commands that are contiguous in both source and target space. Zero-sized commands are
+// dropped first so that they can't split an otherwise contiguous run. Overlapping targets are
+// trimmed first with `resolve_overlaps`, in (target, source_id, source) order.
+pub(crate) fn optimize_copy_cmds(cmds: &mut Vec<CopyCmd>) {
+    if cmds.iter().any(|cmd| cmd.size == 0) {
+        cmds.retain(|cmd| cmd.size != 0);
+    }
+    if cmds.len() <= 1 {
+        return;
+    }
+    let sort_key = |v: &CopyCmd| (v.target, v.source_id, v.source);
+    // Diffs usually produce commands in order already.
+    if cmds
+        .windows(2)
+        .any(|pair| sort_key(&pair[0]) > sort_key(&pair[1]))
+    {
+        cmds.sort_by_key(sort_key);
+    }
+    if cmds
+        .windows(2)
+        .any(|pair| pair[0].target + pair[0].size as u64 > pair[1].target)
+    {
+        let mut tagged: Vec<(CopyCmd, ())> = cmds.drain(..).map(|cmd| (cmd, ())).collect();
+        resolve_overlaps(&mut tagged);
+        cmds.extend(tagged.into_iter().map(|(cmd, ())| cmd));
+    }
+    let mut last = 0;
+    for i in 1..cmds.len() {
+        let (prev, curr) = (&cmds[last], &cmds[i]);
+        if prev.source_id == curr.source_id
+            && prev.source + prev.size as u64 == curr.source
+            && prev.target + prev.size as u64 == curr.target
+            && prev.size as u64 + curr.size as u64 <= u32::MAX as u64
+        {
+            cmds[last].size += cmds[i].size;
+        } else {
+            last += 1;
+            // Until the first merge, every command is already in place.
+            if last != i {
+                cmds.swap(last, i);
+            }
+        }
+    }
+    cmds.truncate(last + 1);
+}
+
+pub fn build_patch(other_data: &[u8], patch_commands: &PatchCommands) -> Patch {
+    let mut patch_data: Vec<u8> = Vec::new();
+    let mut other_cmds: Vec<CopyCmd> = Vec::new();
+    for cmd in &patch_commands.other {
+        let patch_copy_cmd = CopyCmd {
+            source: patch_data.len() as u64,
+            target: cmd.target,
+            size: cmd.size,
+            source_id: 0,
+        };
+        let slice_begin = checked_usize(cmd.source).unwrap();
+        let slice_end = slice_begin + cmd.size as usize;
+        let slice = &other_data[slice_begin..slice_end];
+        patch_data.extend(slice.iter().cloned());
+        other_cmds.push(patch_copy_cmd);
+    }
+    let mut result = Patch {
+        data: patch_data,
+        base: patch_commands.base.clone(),
+        other: other_cmds,
+        other_size: Some(other_data.len() as u64),
+        compression: Compression::None,
+        other_hash: Some(compute_hash_strong(other_data)),
+        base_hash: None,
+        block_size: patch_commands.block_size,
+    };
+
+    optimize_copy_cmds(&mut result.base);
+    optimize_copy_cmds(&mut result.other);
+
+    result
+}
+
+// Like `build_patch`, but also records the strong hash of `base_data` so that applying the patch
+// to a different base fails with `WrongBase` instead of producing corrupt output.
+pub fn build_patch_with_base(
+    base_data: &[u8],
+    other_data: &[u8],
+    patch_commands: &PatchCommands,
+) -> Patch {
+    let mut result = build_patch(other_data, patch_commands);
+    result.base_hash = Some(compute_hash_strong(base_data));
+    result
+}
+
+// Reads literal spans from `other` on demand instead of requiring all of it in memory. `other`
+// is also streamed once to compute `other_hash`.
+pub fn build_patch_from_reader(
+    other: &mut (impl Read + Seek),
+    patch_commands: &PatchCommands,
+) -> io::Result<Patch> {
+    let mut patch_data: Vec<u8> = Vec::with_capacity(patch_commands.need_bytes_from_other());
+    let mut other_cmds: Vec<CopyCmd> = Vec::with_capacity(patch_commands.other.len());
+    for cmd in &patch_commands.other {
+        other_cmds.push(CopyCmd {
+            source: patch_data.len() as u64,
+            target: cmd.target,
+            size: cmd.size,
+            source_id: 0,
+        });
+        other.seek(SeekFrom::Start(cmd.source))?;
+        let slice_begin = patch_data.len();
+        patch_data.resize(slice_begin + cmd.size as usize, 0);
+        other.read_exact(&mut patch_data[slice_begin..])?;
+    }
+    let mut hasher_blake3 = blake3::Hasher::new();
+    other.seek(SeekFrom::Start(0))?;
+    let other_size = io::copy(other, &mut hasher_blake3)?;
+    let mut result = Patch {
+        data: patch_data,
+        base: patch_commands.base.clone(),
+        other: other_cmds,
+        other_size: Some(other_size),
+        compression: Compression::None,
+        other_hash: Some(Hash128::new_from_blake3(&hasher_blake3.finalize())),
+        base_hash: None,
+        block_size: patch_commands.block_size,
+    };
+
+    optimize_copy_cmds(&mut result.base);
+    optimize_copy_cmds(&mut result.other);
+
+    Ok(result)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchStrategy {
+    Delta,
+    Whole,
+}
+
+// Stores all of `other_data` as literals when the delta patch wouldn't be smaller.
+pub fn build_patch_optimal(
+    other_data: &[u8],
+    patch_commands: &PatchCommands,
+) -> (Patch, PatchStrategy) {
+    let mut whole_commands = PatchCommands::with_block_size(patch_commands.block_size);
+    push_literal_cmds(&mut whole_commands.other, 0, other_data.len());
+    if patch_commands.estimated_serialized_size() < whole_commands.estimated_serialized_size() {
+        (
+            build_patch(other_data, patch_commands),
+            PatchStrategy::Delta,
+        )
+    } else {
+        (
+            build_patch(other_data, &whole_commands),
+            PatchStrategy::Whole,
+        )
+    }
+}
+
+pub fn build_patch_compressed(
+    other_data: &[u8],
+    patch_commands: &PatchCommands,
+    compression: Compression,
+) -> Result<Patch, PatchError> {
+    let mut result = build_patch(other_data, patch_commands);
+    match compression {
+        Compression::None => {}
+        Compression::Zstd { level } => {
+            result.data = zstd::stream::encode_all(result.data.as_slice(), level)
+                .map_err(|e| PatchError::Compression(e.to_string()))?;
+        }
+        Compression::PerRun(encoding) => {
+            result.data = encode_literal_runs(&result.data, &result.other, encoding)?;
+        }
+    }
+    result.compression = compression;
+    Ok(result)
+}
+
+pub fn apply_patch(base_data: &[u8], patch: &Patch) -> Vec<u8> {
+    apply_patch_checked(base_data, patch).unwrap()
+}
+
+// Patches without `other_hash` are applied without verification.
+pub fn apply_patch_verified(base_data: &[u8], patch: &Patch) -> Result<Vec<u8>, PatchError> {
+    let result = apply_patch_checked(base_data, patch)?;
+    if let Some(expected) = patch.other_hash {
+        let actual = compute_hash_strong(&result);
+        if !actual.matches(&expected) {
+            return Err(PatchError::HashMismatch { expected, actual });
+        }
+    }
+    Ok(result)
+}
+
+// Output that was already written can't be read back, so self-referential commands are
+// rejected with `InvalidSourceId`.
+pub fn apply_patch_to_writer(
+    base_data: &[u8],
+    patch: &Patch,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    write_patch_output(base_data, patch, |bytes, _| out.write_all(bytes))
+}
+
+// Passes the output of `patch` to `write` in order, one command at a time, along with whether
+// the bytes are a literal from the patch data.
+pub(crate) fn write_patch_output(
+    base_data: &[u8],
+    patch: &Patch,
+    mut write: impl FnMut(&[u8], bool) -> io::Result<()>,
+) -> io::Result<()> {
+    let invalid_data = |e: PatchError| io::Error::new(io::ErrorKind::InvalidData, e);
+    patch.check_base(base_data).map_err(invalid_data)?;
+    let other_size = checked_usize(patch.output_size()).map_err(invalid_data)?;
+    let patch_data = patch.decompressed_data().map_err(invalid_data)?;
+    let mut cmds: Vec<(&CopyCmd, &[u8], bool)> =
+        Vec::with_capacity(patch.base.len() + patch.other.len());
+    for cmd in &patch.base {
+        let source = get_base_data(&[base_data], cmd).map_err(invalid_data)?;
+        cmds.push((cmd, source, false));
+    }
+    cmds.extend(
+        patch
+            .other
+            .iter()
+            .map(|cmd| (cmd, patch_data.as_ref(), true)),
+    );
+    cmds.sort_by_key(|(cmd, _, _)| cmd.target);
+    let mut position: u64 = 0;
+    for (cmd, source, is_literal) in cmds {
+        if cmd.target > position {
+            return Err(invalid_data(PatchError::CoverageGap { offset: position }));
+        } else if cmd.target < position {
+            return Err(invalid_data(PatchError::CoverageOverlap {
+                offset: cmd.target,
+            }));
+        }
+        cmd.check_bounds(other_size, source.len())
+            .map_err(invalid_data)?;
+        let source_begin = checked_usize(cmd.source).map_err(invalid_data)?;
+        write(
+            &source[source_begin..source_begin + cmd.size as usize],
+            is_literal,
+        )?;
+        position += cmd.size as u64;
+    }
+    if position != patch.output_size() {
+        return Err(invalid_data(PatchError::CoverageGap { offset: position }));
+    }
+    Ok(())
+}
+
+// Reads the base copies from `base` on demand instead of requiring all of it in memory. The
+// output is written in target order, so base reads follow target order too; they only seek
+// forward when the base copies keep their order, which is the common case for edited files.
+// With `base_hash` set, `base` is streamed once more up front to check it.
+pub fn apply_patch_from_base_reader(
+    base: &mut (impl Read + Seek),
+    patch: &Patch,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let invalid_data = |e: PatchError| io::Error::new(io::ErrorKind::InvalidData, e);
+    let base_len = base.seek(SeekFrom::End(0))?;
+    if let Some(expected) = patch.base_hash {
+        let mut hasher_blake3 = blake3::Hasher::new();
+        base.seek(SeekFrom::Start(0))?;
+        io::copy(base, &mut hasher_blake3)?;
+        let actual = Hash128::new_from_blake3(&hasher_blake3.finalize());
+        if !actual.matches(&expected) {
+            return Err(invalid_data(PatchError::WrongBase { expected, actual }));
+        }
+    }
+    let other_size = checked_usize(patch.output_size()).map_err(invalid_data)?;
+    let patch_data = patch.decompressed_data().map_err(invalid_data)?;
+    let mut cmds: Vec<(&CopyCmd, bool)> = patch
+        .base
+        .iter()
+        .map(|cmd| (cmd, true))
+        .chain(patch.other.iter().map(|cmd| (cmd, false)))
+        .collect();
+    cmds.sort_by_key(|(cmd, _)| cmd.target);
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut position: u64 = 0;
+    for (cmd, from_base) in cmds {
+        if cmd.target > position {
+            return Err(invalid_data(PatchError::CoverageGap { offset: position }));
+        } else if cmd.target < position {
+            return Err(invalid_data(PatchError::CoverageOverlap {
+                offset: cmd.target,
+            }));
+        }
+        if from_base {
+            if cmd.source_id != 0 {
+                return Err(invalid_data(PatchError::InvalidSourceId {
+                    source_id: cmd.source_id,
+                }));
+            }
+            cmd.check_bounds(other_size, saturating_usize(base_len))
+                .map_err(invalid_data)?;
+            base.seek(SeekFrom::Start(cmd.source))?;
+            buffer.resize(cmd.size as usize, 0);
+            base.read_exact(&mut buffer)?;
+            out.write_all(&buffer)?;
+        } else {
+            cmd.check_bounds(other_size, patch_data.len())
+                .map_err(invalid_data)?;
+            let source_begin = checked_usize(cmd.source).map_err(invalid_data)?;
+            out.write_all(&patch_data[source_begin..source_begin + cmd.size as usize])?;
+        }
+        position += cmd.size as u64;
+    }
+    if position != patch.output_size() {
+        return Err(invalid_data(PatchError::CoverageGap { offset: position }));
+    }
+    Ok(())
+}
+
+fn mark_written(written: &mut [u64], begin: usize, end: usize) {
+    let mut i = begin;
+    while i < end {
+        if i & 63 == 0 && end - i >= 64 {
+            written[i / 64] = u64::MAX;
+            i += 64;
+        } else {
+            written[i / 64] |= 1 << (i % 64);
+            i += 1;
+        }
+    }
+}
+
+// Like `apply_patch_checked`, but also records which output bytes the commands wrote and fails
+// with `CoverageGap` at the first byte that no command wrote.
+pub fn apply_patch_strict(base_data: &[u8], patch: &Patch) -> Result<Vec<u8>, PatchError> {
+    let result = apply_patch_checked(base_data, patch)?;
+    let mut written: Vec<u64> = vec![0; div_up(result.len(), 64)];
+    for cmd in patch.base.iter().chain(patch.other.iter()) {
+        let begin = cmd.target as usize;
+        mark_written(&mut written, begin, begin + cmd.size as usize);
+    }
+    for (word_index, word) in written.iter().enumerate() {
+        if *word != u64::MAX {
+            let offset = word_index * 64 + word.trailing_ones() as usize;
+            if offset < result.len() {
+                return Err(PatchError::CoverageGap {
+                    offset: offset as u64,
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn is_written(written: &[u64], begin: usize, end: usize) -> bool {
+    (begin..end).all(|i| written[i / 64] & (1 << (i % 64)) != 0)
+}
+
+// Maximal runs of output bytes that no command wrote, in order.
+fn unwritten_ranges(written: &[u64], len: usize) -> Vec<Range<u64>> {
+    let mut result: Vec<Range<u64>> = Vec::new();
+    let mut i = 0;
+    while i < len {
+        if i & 63 == 0 && written[i / 64] == u64::MAX {
+            i += 64;
+            continue;
+        }
+        if written[i / 64] & (1 << (i % 64)) == 0 {
+            match result.last_mut() {
+                Some(range) if range.end == i as u64 => range.end += 1,
+                _ => result.push(i as u64..i as u64 + 1),
+            }
+        }
+        i += 1;
+    }
+    result
+}
+
+pub fn apply_patch_partial(
+    base_data: &[u8],
+    patch: &Patch,
+    up_to_command: usize,
+) -> (Vec<u8>, Vec<Range<u64>>) {
+    apply_patch_partial_checked(base_data, patch, up_to_command).unwrap()
+}
+
+// Applies only the first `up_to_command` commands in encoded order (all of `base`, then
+// `other`), e.g. those received before a transfer was interrupted, and returns the output
+// along with the target ranges that are still missing. Missing bytes are zero. A self copy
+// whose source isn't written yet is left out and its target reported as missing.
+pub fn apply_patch_partial_checked(
+    base_data: &[u8],
+    patch: &Patch,
+    up_to_command: usize,
+) -> Result<(Vec<u8>, Vec<Range<u64>>), PatchError> {
+    patch.check_base(base_data)?;
+    let other_size = patch.checked_output_size()?;
+    let patch_data = patch.decompressed_data()?;
+    let mut result: Vec<u8> = vec![0; other_size];
+    let mut written: Vec<u64> = vec![0; div_up(other_size, 64)];
+    let mut self_cmds: Vec<&CopyCmd> = Vec::new();
+    let received = patch
+        .base
+        .iter()
+        .map(|cmd| (cmd, true))
+        .chain(patch.other.iter().map(|cmd| (cmd, false)))
+        .take(up_to_command);
+    for (cmd, from_base) in received {
+        if from_base && cmd.is_self_ref() {
+            self_cmds.push(cmd);
+            continue;
+        }
+        if from_base {
+            cmd.execute_checked(&mut result, get_base_data(&[base_data], cmd)?)?;
+        } else {
+            cmd.execute_checked(&mut result, &patch_data)?;
+        }
+        let begin = cmd.target as usize;
+        mark_written(&mut written, begin, begin + cmd.size as usize);
+    }
+    self_cmds.sort_by_key(|cmd| cmd.target);
+    for cmd in self_cmds {
+        // An overlapping copy writes the tail of its own source before reading it.
+        let source_begin = checked_usize(cmd.source)?;
+        let mut source_end = source_begin
+            .saturating_add(cmd.size as usize)
+            .min(other_size);
+        if cmd.source < cmd.target {
+            source_end = source_end.min(cmd.target as usize);
+        }
+        if !is_written(&written, source_begin.min(source_end), source_end) {
+            continue;
+        }
+        cmd.execute_self_checked(&mut result)?;
+        let begin = cmd.target as usize;
+        mark_written(&mut written, begin, begin + cmd.size as usize);
+    }
+    let missing = unwritten_ranges(&written, other_size);
+    Ok((result, missing))
+}
+
+pub fn apply_patch_checked(base_data: &[u8], patch: &Patch) -> Result<Vec<u8>, PatchError> {
+    patch.check_base(base_data)?;
+    apply_patch_multi_checked(&[base_data], patch)
+}
+
+pub fn apply_patch_multi(bases: &[&[u8]], patch: &Patch) -> Vec<u8> {
+    apply_patch_multi_checked(bases, patch).unwrap()
+}
+
+fn get_base_data<'a>(bases: &[&'a [u8]], cmd: &CopyCmd) -> Result<&'a [u8], PatchError> {
+    match bases.get(cmd.source_id as usize) {
+        Some(base_data) => Ok(base_data),
+        None => Err(PatchError::InvalidSourceId {
+            source_id: cmd.source_id,
+        }),
+    }
+}
+
+pub fn apply_patch_multi_checked(bases: &[&[u8]], patch: &Patch) -> Result<Vec<u8>, PatchError> {
+    let mut result: Vec<u8> = Vec::new();
+    apply_patch_multi_into(bases, patch, &mut result)?;
+    Ok(result)
+}
+
+// Reuses the allocation of `scratch` for the output. `scratch` must not alias `base_data`.
+pub fn apply_patch_into(
+    base_data: &[u8],
+    patch: &Patch,
+    scratch: &mut Vec<u8>,
+) -> Result<(), PatchError> {
+    patch.check_base(base_data)?;
+    apply_patch_multi_into(&[base_data], patch, scratch)
+}
+
+fn apply_patch_multi_into(
+    bases: &[&[u8]],
+    patch: &Patch,
+    result: &mut Vec<u8>,
+) -> Result<(), PatchError> {
+    let other_size = patch.checked_output_size()?;
+    let patch_data = patch.decompressed_data()?;
+    result.clear();
+    result.resize(other_size, 0);
+    let mut self_cmds: Vec<(usize, &CopyCmd)> = Vec::new();
+    for (index, cmd) in patch.base.iter().enumerate() {
+        if cmd.is_self_ref() {
+            self_cmds.push((index, cmd));
+        } else {
+            let base_data = get_base_data(bases, cmd)?;
+            cmd.execute_checked(result, base_data).map_err(|e| {
+                locate_range_error(
+                    e,
+                    CommandList::Base,
+                    index,
+                    cmd,
+                    base_data.len(),
+                    other_size,
+                )
+            })?;
+        }
+    }
+    for (index, cmd) in patch.other.iter().enumerate() {
+        cmd.execute_checked(result, &patch_data).map_err(|e| {
+            locate_range_error(
+                e,
+                CommandList::Other,
+                index,
+                cmd,
+                patch_data.len(),
+                other_size,
+            )
+        })?;
+    }
+    // Self copies read output written by earlier commands, so they run last in target order.
+    self_cmds.sort_by_key(|(_, cmd)| cmd.target);
+    for (index, cmd) in self_cmds {
+        cmd.execute_self_checked(result).map_err(|e| {
+            locate_range_error(
+                e,
+                CommandList::Base,
+                index,
+                cmd,
+                saturating_usize(cmd.target),
+                other_size,
+            )
+        })?;
+    }
+    Ok(())
+}
+
+// Turns a range error of `cmd` into `OutOfRange`, which names the command.
+fn locate_range_error(
+    error: PatchError,
+    list: CommandList,
+    index: usize,
+    cmd: &CopyCmd,
+    source_len: usize,
+    target_len: usize,
+) -> PatchError {
+    match error {
+        PatchError::SourceOutOfRange { .. } | PatchError::TargetOutOfRange { .. } => {
+            PatchError::OutOfRange {
+                list,
+                index,
+                source: cmd.source,
+                target: cmd.target,
+                size: cmd.size,
+                source_len,
+                target_len,
+            }
+        }
+        error => error,
+    }
+}
+
+// Size of the output partitions that `apply_patch_parallel` fills concurrently.
+pub const PARALLEL_APPLY_CHUNK_SIZE: usize = 1024 * 1024;
+
+pub fn apply_patch_parallel(base_data: &[u8], patch: &Patch) -> Vec<u8> {
+    apply_patch_parallel_checked(base_data, patch).unwrap()
+}
+
+// Produces the same output as `apply_patch_checked`. The output is split into partitions of
+// PARALLEL_APPLY_CHUNK_SIZE bytes, and every copy is clipped to the partitions it touches, so a
+// copy that straddles a boundary is applied in pieces. Within a partition, copies run in the
+// same order as the serial apply, so overlapping copies resolve identically. Self copies run
+// serially at the end. Without the `parallel` feature the partitions are filled one after
+// another.
+pub fn apply_patch_parallel_checked(
+    base_data: &[u8],
+    patch: &Patch,
+) -> Result<Vec<u8>, PatchError> {
+    patch.check_base(base_data)?;
+    let other_size = patch.checked_output_size()?;
+    let patch_data = patch.decompressed_data()?;
+    // Each command with the data it reads and where it is in the patch, for errors.
+    let mut cmds: Vec<(&CopyCmd, &[u8], CommandList, usize)> =
+        Vec::with_capacity(patch.base.len() + patch.other.len());
+    let mut self_cmds: Vec<(usize, &CopyCmd)> = Vec::new();
+    for (index, cmd) in patch.base.iter().enumerate() {
+        if cmd.is_self_ref() {
+            self_cmds.push((index, cmd));
+        } else {
+            let source = get_base_data(&[base_data], cmd)?;
+            cmds.push((cmd, source, CommandList::Base, index));
+        }
+    }
+    for (index, cmd) in patch.other.iter().enumerate() {
+        cmds.push((cmd, patch_data.as_ref(), CommandList::Other, index));
+    }
+    let chunk_count = div_up(other_size, PARALLEL_APPLY_CHUNK_SIZE);
+    let mut chunk_cmds: Vec<Vec<usize>> = vec![Vec::new(); chunk_count];
+    for (i, &(cmd, source, list, index)) in cmds.iter().enumerate() {
+        cmd.check_bounds(other_size, source.len())
+            .map_err(|e| locate_range_error(e, list, index, cmd, source.len(), other_size))?;
+        if cmd.size == 0 {
+            continue;
+        }
+        let first_chunk = cmd.target as usize / PARALLEL_APPLY_CHUNK_SIZE;
+        let last_chunk = (cmd.target as usize + cmd.size as usize - 1) / PARALLEL_APPLY_CHUNK_SIZE;
+        for chunk in &mut chunk_cmds[first_chunk..=last_chunk] {
+            chunk.push(i);
+        }
+    }
+    let mut result: Vec<u8> = vec![0; other_size];
+    let fill_chunk = |(chunk_index, chunk): (usize, &mut [u8])| {
+        let chunk_begin = chunk_index * PARALLEL_APPLY_CHUNK_SIZE;
+        let chunk_end = chunk_begin + chunk.len();
+        for &i in &chunk_cmds[chunk_index] {
+            let (cmd, source, ..) = cmds[i];
+            let target = cmd.target as usize;
+            let begin = target.max(chunk_begin);
+            let end = min(target + cmd.size as usize, chunk_end);
+            let source_begin = cmd.source as usize + (begin - target);
+            chunk[begin - chunk_begin..end - chunk_begin]
+                .copy_from_slice(&source[source_begin..source_begin + (end - begin)]);
+        }
+    };
+    #[cfg(feature = "parallel")]
+    result
+        .par_chunks_mut(PARALLEL_APPLY_CHUNK_SIZE)
+        .enumerate()
+        .for_each(fill_chunk);
+    #[cfg(not(feature = "parallel"))]
+    result
+        .chunks_mut(PARALLEL_APPLY_CHUNK_SIZE)
+        .enumerate()
+        .for_each(fill_chunk);
+    self_cmds.sort_by_key(|(_, cmd)| cmd.target);
+    for (index, cmd) in self_cmds {
+        cmd.execute_self_checked(&mut result).map_err(|e| {
+            locate_range_error(
+                e,
+                CommandList::Base,
+                index,
+                cmd,
+                saturating_usize(cmd.target),
+                other_size,
+            )
+        })?;
+    }
+    Ok(result)
+}
+
+fn keep_earliest(first: &mut Option<(u64, Option<CopyCmd>)>, offset: u64, cmd: Option<&CopyCmd>) {
+    if !matches!(first, Some((first_offset, _)) if *first_offset <= offset) {
+        *first = Some((offset, cmd.cloned()));
+    }
+}
+
+// Compares the output of each command with `expected_other` as it is applied. Returns the lowest
+// differing output offset and the command that wrote it. The command is `None` for bytes that no
+// command wrote and for offsets past the end of the shorter of the two outputs, as no command is
+// behind those. A command that can't be applied, e.g. one reading past its source, is reported
+// as diverging at its target rather than failing the call. Errors are left for a patch that
+// can't be examined at all: literals that don't decompress, or an output too large to hold.
+pub fn first_divergence(
+    base_data: &[u8],
+    patch: &Patch,
+    expected_other: &[u8],
+) -> Result<Option<(u64, Option<CopyCmd>)>, PatchError> {
+    let other_size = checked_usize(patch.output_size())?;
+    let patch_data = patch.decompressed_data()?;
+    let mut output: Vec<u8> = vec![0; other_size];
+    let mut first: Option<(u64, Option<CopyCmd>)> = None;
+    let mut self_cmds: Vec<&CopyCmd> = patch.base.iter().filter(|cmd| cmd.is_self_ref()).collect();
+    self_cmds.sort_by_key(|cmd| cmd.target);
+    let base_cmds = patch.base.iter().filter(|cmd| !cmd.is_self_ref());
+    let cmds = base_cmds
+        .map(|cmd| (cmd, Some(base_data)))
+        .chain(
+            patch
+                .other
+                .iter()
+                .map(|cmd| (cmd, Some(patch_data.as_ref()))),
+        )
+        .chain(self_cmds.into_iter().map(|cmd| (cmd, None)));
+    for (cmd, source) in cmds {
+        let executed = match source {
+            Some(source) if cmd.source_id == 0 => cmd.execute_checked(&mut output, source),
+            Some(_) => Err(PatchError::InvalidSourceId {
+                source_id: cmd.source_id,
+            }),
+            None => cmd.execute_self_checked(&mut output),
+        };
+        if executed.is_err() {
+            keep_earliest(&mut first, cmd.target, Some(cmd));
+            continue;
+        }
+        let begin = cmd.target as usize;
+        let end = begin + cmd.size as usize;
+        let compare_end = min(end, expected_other.len());
+        if begin < compare_end {
+            let written = &output[begin..compare_end];
+            if let Some(i) = written
+                .iter()
+                .zip(&expected_other[begin..compare_end])
+                .position(|(x, y)| x != y)
+            {
+                keep_earliest(&mut first, (begin + i) as u64, Some(cmd));
+            }
+        }
+    }
+    let common_len = min(output.len(), expected_other.len());
+    let mismatch = output[0..common_len]
+        .iter()
+        .zip(&expected_other[0..common_len])
+        .position(|(x, y)| x != y);
+    match mismatch {
+        Some(i) => keep_earliest(&mut first, i as u64, None),
+        None if output.len() != expected_other.len() => {
+            keep_earliest(&mut first, common_len as u64, None)
+        }
+        None => (),
+    }
+    Ok(first)
+}
+
+#[cfg(test)]
+pub fn testing_optimize_copy_cmds(cmds: &mut Vec<crate::CopyCmd>) {
+    optimize_copy_cmds(cmds);
+}
@@ -0,0 +1,132 @@
+use crate::hash::*;
+use crate::patchy::*;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const SIGNATURE_CACHE_MAGIC: [u8; 4] = *b"PSCC";
+const SIGNATURE_CACHE_VERSION: u16 = 1;
+
+// File metadata a cached signature is valid for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CacheKey {
+    path: String,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    len: u64,
+    block_size: u64,
+}
+
+impl CacheKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.path.len() as u64);
+        out.extend_from_slice(self.path.as_bytes());
+        out.extend_from_slice(&self.mtime_secs.to_le_bytes());
+        out.extend_from_slice(&self.mtime_nanos.to_le_bytes());
+        out.extend_from_slice(&self.len.to_le_bytes());
+        out.extend_from_slice(&self.block_size.to_le_bytes());
+    }
+    fn decode(reader: &mut BodyReader) -> Result<CacheKey, PatchError> {
+        let path_len = checked_usize(reader.read_varint()?)?;
+        let path = String::from_utf8(reader.read_bytes(path_len)?.to_vec())
+            .map_err(|e| PatchError::Serialization(e.to_string()))?;
+        let mtime_secs = reader.read_u64()?;
+        let mut nanos = [0u8; 4];
+        nanos.copy_from_slice(reader.read_bytes(4)?);
+        Ok(CacheKey {
+            path,
+            mtime_secs,
+            mtime_nanos: u32::from_le_bytes(nanos),
+            len: reader.read_u64()?,
+            block_size: reader.read_u64()?,
+        })
+    }
+}
+
+// Signatures of files stored in `cache_dir`, keyed by path, modification time, length and
+// block size. A signature is recomputed only when one of them changed. Each cache file is a
+// `Patch::encode`-style container holding the key followed by `Signature::to_bytes`.
+pub struct SignatureCache {
+    cache_dir: PathBuf,
+    hits: u64,
+    misses: u64,
+}
+
+impl SignatureCache {
+    pub fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            cache_dir: cache_dir.into(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+    // Number of signatures loaded from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+    // Number of signatures computed from the file.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+    pub fn signature(
+        &mut self,
+        path: impl AsRef<Path>,
+        block_size: usize,
+    ) -> io::Result<Vec<Block>> {
+        let invalid_input = |e: PatchError| io::Error::new(io::ErrorKind::InvalidInput, e);
+        check_block_size(block_size).map_err(invalid_input)?;
+        let path = fs::canonicalize(path)?;
+        let metadata = fs::metadata(&path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let key = CacheKey {
+            path: path.to_string_lossy().into_owned(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            len: metadata.len(),
+            block_size: block_size as u64,
+        };
+        let cache_path = self.cache_path(&key);
+        // An unreadable or stale cache file is treated as a miss and overwritten.
+        if let Ok(bytes) = fs::read(&cache_path) {
+            if let Ok(Some(blocks)) = Self::decode_entry(&bytes, &key) {
+                self.hits += 1;
+                return Ok(blocks);
+            }
+        }
+        self.misses += 1;
+        let mut builder = SignatureBuilder::new(block_size);
+        builder.update(&mut File::open(&path)?)?;
+        let blocks = builder.finalize();
+        let signature = Signature { block_size, blocks };
+        let mut body: Vec<u8> = Vec::new();
+        key.encode(&mut body);
+        body.extend_from_slice(&signature.to_bytes());
+        fs::create_dir_all(&self.cache_dir)?;
+        fs::write(
+            &cache_path,
+            encode_container(&SIGNATURE_CACHE_MAGIC, SIGNATURE_CACHE_VERSION, &body),
+        )?;
+        Ok(signature.blocks)
+    }
+    // One file per path and block size, so a changed file replaces its stale entry.
+    fn cache_path(&self, key: &CacheKey) -> PathBuf {
+        let mut name = key.path.as_bytes().to_vec();
+        name.extend_from_slice(&key.block_size.to_le_bytes());
+        let file_name = format!("{}.psig", compute_hash_strong(&name).to_hex_string());
+        self.cache_dir.join(file_name)
+    }
+    fn decode_entry(bytes: &[u8], key: &CacheKey) -> Result<Option<Vec<Block>>, PatchError> {
+        let body = decode_container(bytes, &SIGNATURE_CACHE_MAGIC, SIGNATURE_CACHE_VERSION)?;
+        let mut reader = BodyReader { bytes: body };
+        if CacheKey::decode(&mut reader)? != *key {
+            return Ok(None);
+        }
+        let signature = Signature::<Hash128>::from_bytes(reader.bytes)?;
+        Ok(Some(signature.blocks))
+    }
+}
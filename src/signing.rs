@@ -0,0 +1,35 @@
+use crate::patchy::*;
+pub use ed25519_dalek;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+pub const SIGNATURE_LEN: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+// Layout: the `Patch::encode` container (magic, version, body length, body, CRC32) followed by
+// a 64-byte Ed25519 signature of all preceding bytes.
+impl Patch {
+    pub fn encode_signed(&self, signing_key: &SigningKey) -> Vec<u8> {
+        let mut result = self.encode();
+        let signature = signing_key.sign(&result);
+        result.extend_from_slice(&signature.to_bytes());
+        result
+    }
+    // Verification is strict, so that a patch has only one valid signature per key.
+    pub fn decode_verified(
+        bytes: &[u8],
+        verifying_key: &VerifyingKey,
+    ) -> Result<Patch, PatchError> {
+        if bytes.len() < SIGNATURE_LEN {
+            return Err(PatchError::LengthMismatch {
+                expected: SIGNATURE_LEN as u64,
+                actual: bytes.len() as u64,
+            });
+        }
+        let (message, signature_bytes) = bytes.split_at(bytes.len() - SIGNATURE_LEN);
+        let mut signature = [0u8; SIGNATURE_LEN];
+        signature.copy_from_slice(signature_bytes);
+        verifying_key
+            .verify_strict(message, &Signature::from_bytes(&signature))
+            .map_err(|_| PatchError::BadSignature)?;
+        Patch::decode(message)
+    }
+}
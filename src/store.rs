@@ -0,0 +1,59 @@
+use crate::hash::*;
+use crate::patchy::*;
+use std::collections::HashMap;
+
+// Content-addressed block store. Each unique block is kept once, keyed by its strong hash, and a
+// file is represented by the manifest of its block hashes in order.
+#[derive(Default)]
+pub struct ChunkStore {
+    chunks: HashMap<Hash128, Vec<u8>>,
+    stored_bytes: usize,
+}
+
+impl ChunkStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+    // Total size of the unique blocks held by the store.
+    pub fn stored_bytes(&self) -> usize {
+        self.stored_bytes
+    }
+    pub fn contains(&self, hash: &Hash128) -> bool {
+        self.chunks.contains_key(hash)
+    }
+    pub fn get(&self, hash: &Hash128) -> Option<&[u8]> {
+        self.chunks.get(hash).map(|chunk| chunk.as_slice())
+    }
+    // Splits `data` with `compute_blocks` and stores the blocks not already present.
+    pub fn ingest(&mut self, data: &[u8], block_size: usize) -> Vec<Hash128> {
+        let blocks = compute_blocks(data, block_size);
+        let mut manifest: Vec<Hash128> = Vec::with_capacity(blocks.len());
+        for block in &blocks {
+            let stored_bytes = &mut self.stored_bytes;
+            self.chunks.entry(block.hash_strong).or_insert_with(|| {
+                let begin = block.offset as usize;
+                let chunk = data[begin..begin + block.size as usize].to_vec();
+                *stored_bytes += chunk.len();
+                chunk
+            });
+            manifest.push(block.hash_strong);
+        }
+        manifest
+    }
+    pub fn reconstruct(&self, manifest: &[Hash128]) -> Result<Vec<u8>, PatchError> {
+        let mut result: Vec<u8> = Vec::new();
+        for hash in manifest {
+            match self.chunks.get(hash) {
+                Some(chunk) => result.extend_from_slice(chunk),
+                None => return Err(PatchError::MissingChunk { hash: *hash }),
+            }
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,83 @@
+use crate::patchy::*;
+use std::cmp::{min, Ordering};
+
+// Suffix array construction by prefix doubling, O(n log^2 n).
+fn build_suffix_array(data: &[u8]) -> Vec<usize> {
+    let n = data.len();
+    let mut suffixes: Vec<usize> = (0..n).collect();
+    let mut rank: Vec<usize> = data.iter().map(|&x| x as usize).collect();
+    let mut next_rank: Vec<usize> = vec![0; n];
+    if n < 2 {
+        return suffixes;
+    }
+    let mut k: usize = 1;
+    loop {
+        {
+            let key = |i: usize| (rank[i], if i + k < n { rank[i + k] + 1 } else { 0 });
+            suffixes.sort_unstable_by_key(|&i| key(i));
+            next_rank[suffixes[0]] = 0;
+            for j in 1..n {
+                let is_new_rank = key(suffixes[j - 1]) < key(suffixes[j]);
+                next_rank[suffixes[j]] = next_rank[suffixes[j - 1]] + is_new_rank as usize;
+            }
+        }
+        std::mem::swap(&mut rank, &mut next_rank);
+        if rank[suffixes[n - 1]] == n - 1 {
+            break;
+        }
+        k *= 2;
+    }
+    suffixes
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+// Returns (offset, length) of the longest prefix of `pattern` found in `base`.
+fn find_longest_match(base: &[u8], suffixes: &[usize], pattern: &[u8]) -> (usize, usize) {
+    let position = suffixes
+        .binary_search_by(|&suffix| match base[suffix..].cmp(pattern) {
+            Ordering::Equal => Ordering::Less,
+            ordering => ordering,
+        })
+        .unwrap_or_else(|position| position);
+    let mut best = (0, 0);
+    let candidates = &suffixes[position.saturating_sub(1)..min(position + 1, suffixes.len())];
+    for &offset in candidates {
+        let len = common_prefix_len(&base[offset..], pattern);
+        if len > best.1 {
+            best = (offset, len);
+        }
+    }
+    best
+}
+
+// Finds the longest matches of `input` (new data) in `base` using a suffix array over `base`.
+// Matches shorter than `block_size_hint` bytes are not worth a command and become literals.
+pub fn compute_diff_suffix(input: &[u8], base: &[u8], block_size_hint: usize) -> PatchCommands {
+    let min_match_len = block_size_hint.max(1);
+    let suffixes = build_suffix_array(base);
+    let mut patch_commands = PatchCommands::with_block_size(block_size_hint);
+    let mut position: usize = 0;
+    let mut literal_begin: usize = 0;
+    while position < input.len() {
+        let (offset, len) = find_longest_match(base, &suffixes, &input[position..]);
+        if len >= min_match_len {
+            push_literal_cmds(&mut patch_commands.other, literal_begin, position);
+            let len = min(len, u32::MAX as usize);
+            patch_commands.base.push(CopyCmd {
+                source: offset as u64,
+                target: position as u64,
+                size: len as u32,
+                source_id: 0,
+            });
+            position += len;
+            literal_begin = position;
+        } else {
+            position += 1;
+        }
+    }
+    push_literal_cmds(&mut patch_commands.other, literal_begin, input.len());
+    patch_commands
+}
@@ -112,6 +112,7 @@ fn test_optimize_commands_large() {
             source: total_size,
             target: total_size,
             size: 1 << 30u64,
+            source_id: 0,
         };
         total_size += cmd.size as u64;
         cmds.push(cmd);
@@ -126,3 +127,3898 @@ fn test_optimize_commands_large() {
     println!("optimized commands: {:?}", &cmds);
     assert_eq!(cmds.len(), 3);
 }
+
+#[test]
+fn test_rolling_hash_try_sub_empty() {
+    let mut hash = RollingHash::new();
+    assert!(!hash.try_sub(b'a'));
+    assert_eq!(hash.count(), 0);
+    assert_eq!(hash.get(), 0);
+    hash.update(b"ab");
+    assert!(hash.try_sub(b'a'));
+    assert_eq!(hash.get(), compute_hash_weak(b"b"));
+}
+
+#[test]
+fn test_apply_patch_checked_out_of_range() {
+    let a = b"abcdefgh".to_vec();
+    let b = b"abcdXXgh".to_vec();
+    let b_blocks = compute_blocks(&b, 2);
+    let patch_commands = compute_diff(&a, &b_blocks, 2);
+    let mut patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch_checked(&a, &patch).unwrap(), b);
+
+    patch.base[0].source = a.len() as u64;
+    let size = patch.base[0].size;
+    assert_eq!(
+        apply_patch_checked(&a, &patch),
+        Err(PatchError::OutOfRange {
+            list: CommandList::Base,
+            index: 0,
+            source: a.len() as u64,
+            target: 0,
+            size,
+            source_len: a.len(),
+            target_len: b.len(),
+        })
+    );
+    patch.base[0].source = 0;
+    patch.base[0].target = u64::MAX;
+    assert!(matches!(
+        apply_patch_checked(&a, &patch),
+        Err(PatchError::OutOfRange {
+            list: CommandList::Base,
+            index: 0,
+            target: u64::MAX,
+            ..
+        })
+    ));
+    patch.base[0].target = 0;
+
+    let last = patch.other.len() - 1;
+    patch.other[last].size += 1;
+    let error = apply_patch_checked(&a, &patch).unwrap_err();
+    assert!(matches!(
+        error,
+        PatchError::OutOfRange {
+            list: CommandList::Other,
+            index,
+            ..
+        } if index == last
+    ));
+    assert!(error
+        .to_string()
+        .starts_with(&format!("Command {} of the other list", last)));
+}
+
+#[test]
+fn test_invalid_block_size() {
+    let a = b"abcd".to_vec();
+    assert_eq!(
+        try_compute_blocks(&a, 0).err(),
+        Some(PatchError::InvalidBlockSize { block_size: 0 })
+    );
+    assert!(matches!(
+        try_compute_diff::<Hash128>(&a, &[], 0),
+        Err(PatchError::InvalidBlockSize { block_size: 0 })
+    ));
+}
+
+#[test]
+fn test_patch_block_size_1() {
+    do_test_patch(b"abcd".to_vec(), b"dcbax".to_vec(), 1);
+    let a = b"abcd".to_vec();
+    assert_eq!(try_compute_blocks(&a, 1).unwrap().len(), 4);
+}
+
+#[test]
+fn test_patch_repeated_base_blocks() {
+    let block_size = 2048;
+    let block: Vec<u8> = (0..block_size).map(|i| (i * 7 + i / 256) as u8).collect();
+    let mut a: Vec<u8> = Vec::new();
+    for _ in 0..3 {
+        a.extend_from_slice(&block);
+    }
+    let mut b: Vec<u8> = vec![0xAB; block_size];
+    b.extend_from_slice(&a);
+    let b_blocks = compute_blocks(&b, block_size);
+    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+    assert_eq!(patch_commands.base.len(), 3);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(patch.base.len(), 1);
+    assert_eq!(patch.base[0].source, 0);
+    assert_eq!(patch.base[0].size as usize, 3 * block_size);
+    assert_eq!(apply_patch(&a, &patch), b);
+}
+
+#[cfg(test)]
+struct ChunkedReader<'a> {
+    data: &'a [u8],
+    chunk_size: usize,
+}
+
+#[cfg(test)]
+impl<'a> std::io::Read for ChunkedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = std::cmp::min(std::cmp::min(buf.len(), self.chunk_size), self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+fn assert_blocks_eq(a: &[Block], b: &[Block]) {
+    assert_eq!(a.len(), b.len());
+    for (x, y) in a.iter().zip(b.iter()) {
+        assert_eq!(x.offset, y.offset);
+        assert_eq!(x.size, y.size);
+        assert_eq!(x.hash_weak, y.hash_weak);
+        assert_eq!(x.hash_strong, y.hash_strong);
+    }
+}
+
+#[test]
+fn test_signature_builder_matches_compute_blocks() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i ^ (i >> 7)) as u8).collect();
+    let block_size = 1000;
+    let (first, second) = data.split_at(12_345);
+    let mut builder = SignatureBuilder::new(block_size);
+    for part in &[first, second] {
+        let mut reader = ChunkedReader {
+            data: part,
+            chunk_size: 333,
+        };
+        builder.update(&mut reader).unwrap();
+    }
+    assert_blocks_eq(&builder.finalize(), &compute_blocks(&data, block_size));
+    assert!(SignatureBuilder::new(block_size).finalize().is_empty());
+}
+
+#[test]
+fn test_signature_round_trip() {
+    let a = b"hello world, hello patchy".to_vec();
+    let b = b"hello patchy, hello world".to_vec();
+    let block_size = 4;
+    let signature = Signature {
+        block_size,
+        blocks: compute_blocks(&b, block_size),
+    };
+    let bytes = signature.to_bytes();
+    assert_eq!(&bytes[0..4], b"PSIG");
+    let decoded = Signature::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.block_size, block_size);
+    assert_blocks_eq(&decoded.blocks, &signature.blocks);
+
+    let patch_commands = compute_diff(&a, &decoded.blocks, decoded.block_size);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    let mut bad_magic = bytes.clone();
+    bad_magic[0] = b'X';
+    assert_eq!(
+        Signature::<Hash128>::from_bytes(&bad_magic).err(),
+        Some(PatchError::BadMagic)
+    );
+    let mut bad_version = bytes.clone();
+    bad_version[4] = 0xFF;
+    assert!(matches!(
+        Signature::<Hash128>::from_bytes(&bad_version),
+        Err(PatchError::UnsupportedVersion { version: 255 })
+    ));
+    assert!(matches!(
+        Signature::<Hash128>::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(PatchError::Serialization(_))
+    ));
+}
+
+#[test]
+fn test_compute_blocks_offsets() {
+    let input: Vec<u8> = (0..10u8).collect();
+    let blocks = compute_blocks(&input, 4);
+    let layout: Vec<(u64, u32)> = blocks.iter().map(|b| (b.offset, b.size)).collect();
+    assert_eq!(layout, vec![(0, 4), (4, 4), (8, 2)]);
+    assert_eq!(blocks[2].hash_strong, compute_hash_strong(&input[8..10]));
+    assert!(compute_blocks(&[], 4).is_empty());
+}
+
+#[test]
+fn test_patch_compressed_data() {
+    let a: Vec<u8> = vec![1; 64 * 1024];
+    let mut b = a.clone();
+    for (i, x) in b[4096..36864].iter_mut().enumerate() {
+        *x = b"some literal text "[i % 18];
+    }
+    let block_size = 1024;
+    let b_blocks = compute_blocks(&b, block_size);
+    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+    let raw = build_patch(&b, &patch_commands);
+    assert_eq!(raw.compression, Compression::None);
+    let compressed =
+        build_patch_compressed(&b, &patch_commands, Compression::Zstd { level: 3 }).unwrap();
+    assert_eq!(compressed.compression, Compression::Zstd { level: 3 });
+    assert!(compressed.data.len() < raw.data.len() / 10);
+    assert_eq!(apply_patch(&a, &compressed), b);
+
+    let serialized = bincode::serialize(&compressed).unwrap();
+    let deserialized: Patch = bincode::deserialize(&serialized).unwrap();
+    assert_eq!(deserialized.compression, compressed.compression);
+    assert_eq!(apply_patch(&a, &deserialized), b);
+}
+
+#[test]
+fn test_apply_patch_to_writer() {
+    let a: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let mut b = a.clone();
+    b.splice(5000..5000, b"inserted bytes".iter().cloned());
+    let block_size = 64;
+    let b_blocks = compute_blocks(&b, block_size);
+    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+    let mut patch = build_patch(&b, &patch_commands);
+    let mut out: Vec<u8> = Vec::new();
+    apply_patch_to_writer(&a, &patch, &mut out).unwrap();
+    assert_eq!(out, b);
+
+    let removed = patch.other.remove(0);
+    let mut out: Vec<u8> = Vec::new();
+    let err = apply_patch_to_writer(&a, &patch, &mut out).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let inner = err.into_inner().unwrap().downcast::<PatchError>().unwrap();
+    assert_eq!(
+        *inner,
+        PatchError::CoverageGap {
+            offset: removed.target
+        }
+    );
+}
+
+#[test]
+fn test_validate_coverage() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"0123xxxx89abcdefyy".to_vec();
+    let b_blocks = compute_blocks(&b, 4);
+    let patch_commands = compute_diff(&a, &b_blocks, 4);
+    let mut patch = build_patch(&b, &patch_commands);
+    assert_eq!(patch.validate_coverage(), Ok(()));
+
+    patch.base.push(CopyCmd {
+        source: 0,
+        target: 2,
+        size: 4,
+        source_id: 0,
+    });
+    assert_eq!(
+        patch.validate_coverage(),
+        Err(PatchError::CoverageOverlap { offset: 2 })
+    );
+    patch.base.pop();
+
+    let last = patch.other.pop().unwrap();
+    assert_eq!(
+        patch.validate_coverage(),
+        Err(PatchError::CoverageGap {
+            offset: last.target
+        })
+    );
+}
+
+#[test]
+fn test_compute_diff_parallel() {
+    let mut a: Vec<u8> = Vec::new();
+    let mut seed: u32 = 12345;
+    for _ in 0..256 * 1024 {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        a.push((seed >> 16) as u8);
+    }
+    let mut b = a.clone();
+    b.splice(1000..1000, b"shifted".iter().cloned());
+    b[100_000] ^= 0xFF;
+    let block_size = 512;
+    let b_blocks = compute_blocks(&b, block_size);
+    let serial = compute_diff(&a, &b_blocks, block_size);
+    for &shards in &[0, 1, 3, 8, 1000] {
+        let patch_commands = compute_diff_parallel(&a, &b_blocks, block_size, shards);
+        assert!(patch_commands.need_bytes_from_base() <= serial.need_bytes_from_base());
+        let patch = build_patch(&b, &patch_commands);
+        assert_eq!(apply_patch(&a, &patch), b);
+    }
+    let single = compute_diff_parallel(&a, &b_blocks, block_size, 1);
+    assert_eq!(single.need_bytes_from_base(), serial.need_bytes_from_base());
+}
+
+#[cfg(feature = "ct")]
+#[test]
+fn test_hash_ct_eq() {
+    let hash = compute_hash_strong(b"patchy");
+    assert!(hash.ct_eq(&Hash128::from_bytes(hash.as_bytes())));
+    for i in [0, 7, 15] {
+        let mut bytes = *hash.as_bytes();
+        bytes[i] ^= 0x01;
+        assert!(!hash.ct_eq(&Hash128::from_bytes(&bytes)));
+    }
+
+    let a: Vec<u8> = (0..4096u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b[100] ^= 0xFF;
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 256), 256);
+    let mut patch = build_patch_with_base(&a, &b, &patch_commands);
+    assert_eq!(apply_patch_verified(&a, &patch).unwrap(), b);
+    assert!(matches!(
+        apply_patch_verified(&b, &patch),
+        Err(PatchError::WrongBase { .. })
+    ));
+    patch.other_hash = Some(Hash128::new_zero());
+    assert!(matches!(
+        apply_patch_verified(&a, &patch),
+        Err(PatchError::HashMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_hash_hex_formats() {
+    let hash = Hash128::from_bytes(&[
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54, 0x32,
+        0x10,
+    ]);
+    assert_eq!(hash.to_hex_string(), "0123456789abcdeffedcba9876543210");
+    assert_eq!(
+        hash.to_hex_string_upper(),
+        "0123456789ABCDEFFEDCBA9876543210"
+    );
+    assert_eq!(
+        hash.to_hex_delimited(':'),
+        "01:23:45:67:89:ab:cd:ef:fe:dc:ba:98:76:54:32:10"
+    );
+    for sep in &[':', '-', ' ', '.'] {
+        assert_eq!(Hash128::from_hex(&hash.to_hex_delimited(*sep)), Ok(hash));
+    }
+    let mixed = "01:23-45 67.89aB:cD:eF:fE:Dc:Ba:98:76:54:32:10";
+    assert_eq!(Hash128::from_hex(mixed), Ok(hash));
+    assert_eq!(
+        Hash128::from_hex(&hash.to_hex_delimited(':').to_uppercase()),
+        Ok(hash)
+    );
+    let hash256 = compute_hash_strong_256(b"patchy");
+    assert_eq!(
+        Hash256::from_hex(&hash256.to_hex_delimited('-')),
+        Ok(hash256)
+    );
+    assert_eq!(
+        Hash256::from_hex(&hash256.to_hex_string_upper()),
+        Ok(hash256)
+    );
+
+    // Separators only go between bytes, one at a time.
+    assert_eq!(
+        Hash128::from_hex(&format!(":{}", hash.to_hex_string())),
+        Err(PatchError::InvalidHex { offset: 0 })
+    );
+    assert_eq!(
+        Hash128::from_hex("0:123456789abcdeffedcba9876543210"),
+        Err(PatchError::InvalidHex { offset: 1 })
+    );
+    assert_eq!(
+        Hash128::from_hex("01::23456789abcdeffedcba9876543210"),
+        Err(PatchError::InvalidHex { offset: 3 })
+    );
+    assert_eq!(
+        Hash128::from_hex(&format!("{}:", hash.to_hex_delimited(':'))),
+        Err(PatchError::InvalidHex { offset: 47 })
+    );
+    assert_eq!(
+        Hash128::from_hex(&format!("{}:00", hash.to_hex_delimited(':'))),
+        Err(PatchError::LengthMismatch {
+            expected: 32,
+            actual: 34
+        })
+    );
+}
+
+#[test]
+fn test_patch_hash256() {
+    let a = b"The quick brown fox jumps over the lazy dog".to_vec();
+    let b = b"The quick red fox jumps over the lazy dog!".to_vec();
+    let block_size = 4;
+    let b_blocks: Vec<Block<Hash256>> = compute_blocks_generic(&b, block_size);
+    assert_eq!(
+        b_blocks[0].hash_strong.as_bytes(),
+        blake3::hash(&b[0..4]).as_bytes()
+    );
+    let signature = Signature {
+        block_size,
+        blocks: b_blocks,
+    };
+    let bytes = signature.to_bytes();
+    assert_eq!(
+        Signature::<Hash128>::from_bytes(&bytes).err(),
+        Some(PatchError::HashSizeMismatch {
+            expected: 16,
+            actual: 32
+        })
+    );
+    let decoded = Signature::<Hash256>::from_bytes(&bytes).unwrap();
+    let patch_commands = compute_diff(&a, &decoded.blocks, decoded.block_size);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+    assert_eq!(
+        compute_hash_strong_256(&a).to_hex_string()[..32],
+        compute_hash_strong(&a).to_hex_string()
+    );
+}
+
+#[test]
+fn test_strong_hash_truncation() {
+    let a: Vec<u8> = (0..8 * 1024u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b.splice(100..100, a[5000..6000].iter().cloned());
+    b[7000] ^= 0xFF;
+    let full = blake3::hash(&b[0..256]);
+    let b_blocks: Vec<Block<StrongHash<8>>> = compute_blocks_generic(&b, 256);
+    assert_eq!(b_blocks[0].hash_strong.as_bytes(), &full.as_bytes()[..8]);
+    assert_eq!(
+        format!("{:?}", b_blocks[0].hash_strong),
+        format!("Hash64({})", &full.to_hex()[..16])
+    );
+
+    // Serialized hashes are exactly N bytes, and signatures record N.
+    let signature = Signature {
+        block_size: 256,
+        blocks: b_blocks,
+    };
+    let bytes_8 = signature.to_bytes();
+    let bytes_16 = Signature {
+        block_size: 256,
+        blocks: compute_blocks(&b, 256),
+    }
+    .to_bytes();
+    assert_eq!(bytes_16.len() - bytes_8.len(), 8 * signature.blocks.len());
+    assert_eq!(
+        Signature::<StrongHash<24>>::from_bytes(&bytes_8).err(),
+        Some(PatchError::HashSizeMismatch {
+            expected: 24,
+            actual: 8
+        })
+    );
+    let decoded = Signature::<StrongHash<8>>::from_bytes(&bytes_8).unwrap();
+    let patch_commands = compute_diff(&a, &decoded.blocks, decoded.block_size);
+    assert_eq!(apply_patch(&a, &build_patch(&b, &patch_commands)), b);
+
+    let b_blocks: Vec<Block<StrongHash<24>>> = compute_blocks_generic(&b, 256);
+    let patch_commands = compute_diff(&a, &b_blocks, 256);
+    assert_eq!(apply_patch(&a, &build_patch(&b, &patch_commands)), b);
+    let hash: StrongHash<24> = compute_hash_strong_generic(b"patchy");
+    assert_eq!(StrongHash::<24>::from_hex(&hash.to_hex_string()), Ok(hash));
+    assert_eq!(
+        hash.to_hex_string(),
+        compute_hash_strong_256(b"patchy").to_hex_string()[..48]
+    );
+}
+
+#[test]
+fn test_hash_from_bytes_and_hex() {
+    let hash = compute_hash_strong(b"patchy");
+    assert_eq!(Hash128::from_bytes(hash.as_bytes()), hash);
+    assert_eq!(Hash128::from_hex(&hash.to_hex_string()), Ok(hash));
+    assert_eq!(
+        Hash128::from_hex(&hash.to_hex_string().to_uppercase()),
+        Ok(hash)
+    );
+    let hash256 = compute_hash_strong_256(b"patchy");
+    assert_eq!(Hash256::from_bytes(hash256.as_bytes()), hash256);
+    assert_eq!(Hash256::from_hex(&hash256.to_hex_string()), Ok(hash256));
+
+    assert_eq!(
+        Hash128::from_hex("00ff"),
+        Err(PatchError::LengthMismatch {
+            expected: 32,
+            actual: 4
+        })
+    );
+    let mut bad_digit = hash.to_hex_string();
+    bad_digit.replace_range(7..8, "g");
+    assert_eq!(
+        Hash128::from_hex(&bad_digit),
+        Err(PatchError::InvalidHex { offset: 7 })
+    );
+    assert_eq!(
+        Hash128::from_hex(&format!("+{}", &hash.to_hex_string()[1..])),
+        Err(PatchError::InvalidHex { offset: 0 })
+    );
+}
+
+#[test]
+fn test_apply_patch_verified() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"0123456789ABCDEF".to_vec();
+    let b_blocks = compute_blocks(&b, 4);
+    let patch_commands = compute_diff(&a, &b_blocks, 4);
+    let mut patch = build_patch(&b, &patch_commands);
+    assert_eq!(patch.other_hash, Some(compute_hash_strong(&b)));
+    assert_eq!(apply_patch_verified(&a, &patch).unwrap(), b);
+
+    let wrong_base = b"X123456789abcdef".to_vec();
+    assert_eq!(apply_patch(&wrong_base, &patch).len(), b.len());
+    assert!(matches!(
+        apply_patch_verified(&wrong_base, &patch),
+        Err(PatchError::HashMismatch { .. })
+    ));
+
+    patch.other_hash = None;
+    assert!(apply_patch_verified(&wrong_base, &patch).is_ok());
+}
+
+#[test]
+fn test_apply_patch_wrong_base() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"0123456789ABCDEF".to_vec();
+    let b_blocks = compute_blocks(&b, 4);
+    let patch_commands = compute_diff(&a, &b_blocks, 4);
+    let patch = build_patch_with_base(&a, &b, &patch_commands);
+    assert_eq!(patch.base_hash, Some(compute_hash_strong(&a)));
+    assert_eq!(apply_patch_checked(&a, &patch).unwrap(), b);
+    assert_eq!(
+        Patch::decode(&patch.encode()).unwrap().base_hash,
+        patch.base_hash
+    );
+
+    let wrong_base = b"X123456789abcdef".to_vec();
+    let expected_error = Err(PatchError::WrongBase {
+        expected: compute_hash_strong(&a),
+        actual: compute_hash_strong(&wrong_base),
+    });
+    assert_eq!(apply_patch_checked(&wrong_base, &patch), expected_error);
+    assert_eq!(
+        apply_patch_parallel_checked(&wrong_base, &patch),
+        expected_error
+    );
+    let mut scratch: Vec<u8> = Vec::new();
+    assert_eq!(
+        apply_patch_into(&wrong_base, &patch, &mut scratch).err(),
+        expected_error.err()
+    );
+}
+
+#[test]
+fn test_patch_shared_short_tail() {
+    let a = b"zzXYab".to_vec();
+    let b = b"XYabab".to_vec();
+    let b_blocks = compute_blocks(&b, 4);
+    let patch_commands = compute_diff(&a, &b_blocks, 4);
+    assert_eq!(patch_commands.need_bytes_from_other(), 0);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    let parallel_commands = compute_diff_parallel(&a, &b_blocks, 4, 1);
+    assert_eq!(parallel_commands.need_bytes_from_other(), 0);
+}
+
+#[test]
+fn test_estimated_serialized_size() {
+    let a: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 31 + i / 97) as u8).collect();
+    let mut b = a.clone();
+    for i in (0..b.len()).step_by(5000) {
+        b[i] ^= 0x55;
+    }
+    let block_size = 256;
+    let b_blocks = compute_blocks(&b, block_size);
+    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+    let estimate = patch_commands.estimated_serialized_size();
+    let patch = build_patch(&b, &patch_commands);
+    let actual = patch.encode().len();
+    assert!(estimate >= actual);
+    assert!(estimate < actual + actual / 2);
+    assert!(estimate < b.len());
+
+    // Commands at their longest encoding, far apart in both lists.
+    let mut patch_commands = PatchCommands::with_block_size(block_size);
+    for i in 0..4u64 {
+        patch_commands.base.push(CopyCmd {
+            source: (u64::MAX >> 1) * (i % 2),
+            target: (1 << 40) * (3 - i),
+            size: u32::MAX,
+            source_id: u32::MAX - 1,
+        });
+    }
+    let patch = Patch {
+        data: Vec::new(),
+        base: patch_commands.base.clone(),
+        other: Vec::new(),
+        other_size: Some(0),
+        compression: Compression::PerRun(LiteralEncoding {
+            level: 3,
+            min_compress_len: 64,
+        }),
+        other_hash: Some(compute_hash_strong(b"other")),
+        base_hash: Some(compute_hash_strong(b"base")),
+        block_size,
+    };
+    assert!(patch_commands.estimated_serialized_size() >= patch.encode().len());
+}
+
+#[test]
+fn test_patch_multi_base() {
+    let v1: Vec<u8> = (0..8192u32).map(|i| (i * 13 + i / 300) as u8).collect();
+    let v2: Vec<u8> = (0..8192u32).map(|i| (i * 29 + i / 77) as u8).collect();
+    let mut v3: Vec<u8> = Vec::new();
+    v3.extend_from_slice(&v2[1000..5000]);
+    v3.extend_from_slice(b"fresh bytes");
+    v3.extend_from_slice(&v1[0..3000]);
+    v3.extend_from_slice(&v2[7000..]);
+    let block_size = 256;
+    let v1_blocks = compute_blocks(&v1, block_size);
+    let v2_blocks = compute_blocks(&v2, block_size);
+    let patch_commands = compute_diff_multi(&v3, &[&v1_blocks, &v2_blocks], block_size);
+    assert!(patch_commands.base.iter().any(|cmd| cmd.source_id == 0));
+    assert!(patch_commands.base.iter().any(|cmd| cmd.source_id == 1));
+    assert!(patch_commands.need_bytes_from_other() < v3.len() / 4);
+    let patch = build_patch(&v3, &patch_commands);
+    assert_eq!(apply_patch_multi(&[&v1, &v2], &patch), v3);
+    assert_eq!(
+        apply_patch_multi_checked(&[&v1], &patch).err(),
+        Some(PatchError::InvalidSourceId { source_id: 1 })
+    );
+}
+
+#[test]
+fn test_compute_diff_streaming() {
+    let a: Vec<u8> = (0..200 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b: Vec<u8> = Vec::new();
+    b.extend_from_slice(&a[150_000..]);
+    b.extend((0..100_000u32).map(|i| (i * 7 + i / 300) as u8));
+    b.extend_from_slice(&a[1000..90_000]);
+    b.extend_from_slice(b"tail");
+    let block_size = 1024;
+    let a_blocks = compute_blocks(&a, block_size);
+    let triples = |cmds: &[CopyCmd]| {
+        cmds.iter()
+            .map(|cmd| (cmd.source, cmd.target, cmd.size))
+            .collect::<Vec<_>>()
+    };
+    let expected = build_patch(&b, &compute_diff_multi(&b, &[&a_blocks], block_size));
+    for &chunk_size in &[1, 1000, 1 << 20] {
+        let mut reader = ChunkedReader {
+            data: &b,
+            chunk_size,
+        };
+        let mut out: Vec<u8> = Vec::new();
+        let mut patch =
+            compute_diff_streaming(&mut reader, &a_blocks, block_size, &mut out).unwrap();
+        assert!(patch.data.is_empty());
+        assert_eq!(triples(&patch.base), triples(&expected.base));
+        assert_eq!(triples(&patch.other), triples(&expected.other));
+        assert_eq!(out, expected.data);
+        assert_eq!(patch.other_size, Some(b.len() as u64));
+        patch.data = out;
+        assert_eq!(apply_patch_verified(&a, &patch).unwrap(), b);
+    }
+
+    let mut out: Vec<u8> = Vec::new();
+    let patch = compute_diff_streaming(&mut &b""[..], &a_blocks, block_size, &mut out).unwrap();
+    assert!(patch.base.is_empty() && patch.other.is_empty() && out.is_empty());
+    assert_eq!(apply_patch_verified(&a, &patch).unwrap(), b"");
+}
+
+#[test]
+fn test_patch_block_size() {
+    let a = b"abcdefgh".to_vec();
+    let b = b"abcdXXgh".to_vec();
+    let b_blocks = compute_blocks(&b, 2);
+    let patch_commands = compute_diff(&a, &b_blocks, 2);
+    assert_eq!(patch_commands.block_size, 2);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(patch.block_size, 2);
+    assert_eq!(PatchCommands::new().block_size, DEFAULT_BLOCK_SIZE);
+}
+
+#[test]
+fn test_optimize_commands_out_of_order() {
+    let cmd = |source: u64, target: u64, size: u32| CopyCmd {
+        source,
+        target,
+        size,
+        source_id: 0,
+    };
+    let mut cmds = vec![
+        cmd(130, 30, 10),
+        cmd(500, 10, 0),
+        cmd(110, 10, 10),
+        cmd(900, 100, 5),
+        cmd(100, 0, 10),
+        cmd(120, 20, 10),
+    ];
+    testing_optimize_copy_cmds(&mut cmds);
+    assert_eq!(cmds.len(), 2);
+    assert_eq!((cmds[0].source, cmds[0].target, cmds[0].size), (100, 0, 40));
+    assert_eq!(
+        (cmds[1].source, cmds[1].target, cmds[1].size),
+        (900, 100, 5)
+    );
+}
+
+#[test]
+fn test_optimize_commands_sorted() {
+    let cmd = |source: u64, target: u64, size: u32| CopyCmd {
+        source,
+        target,
+        size,
+        source_id: 0,
+    };
+    let triples = |cmds: &[CopyCmd]| {
+        cmds.iter()
+            .map(|cmd| (cmd.source, cmd.target, cmd.size))
+            .collect::<Vec<_>>()
+    };
+    // Nothing to merge: the commands stay as they are.
+    let sorted = vec![
+        cmd(0, 0, 10),
+        cmd(50, 10, 5),
+        cmd(20, 15, 5),
+        cmd(90, 20, 1),
+    ];
+    let mut cmds = sorted.clone();
+    testing_optimize_copy_cmds(&mut cmds);
+    assert_eq!(triples(&cmds), triples(&sorted));
+
+    // A merge followed by commands that must move down, sorted or not.
+    let sorted = vec![
+        cmd(0, 0, 10),
+        cmd(10, 10, 10),
+        cmd(70, 20, 0),
+        cmd(50, 20, 5),
+        cmd(20, 25, 5),
+        cmd(25, 30, 5),
+        cmd(90, 35, 1),
+    ];
+    let expected = vec![(0, 0, 20), (50, 20, 5), (20, 25, 10), (90, 35, 1)];
+    let mut cmds = sorted.clone();
+    testing_optimize_copy_cmds(&mut cmds);
+    assert_eq!(triples(&cmds), expected);
+    let mut cmds = sorted;
+    cmds.reverse();
+    testing_optimize_copy_cmds(&mut cmds);
+    assert_eq!(triples(&cmds), expected);
+
+    let mut cmds = vec![cmd(5, 5, 0)];
+    testing_optimize_copy_cmds(&mut cmds);
+    assert!(cmds.is_empty());
+}
+
+#[test]
+fn test_optimize_commands_overlapping() {
+    let cmd = |source: u64, target: u64, size: u32| CopyCmd {
+        source,
+        target,
+        size,
+        source_id: 0,
+    };
+    let triples = |cmds: &[CopyCmd]| {
+        cmds.iter()
+            .map(|cmd| (cmd.source, cmd.target, cmd.size))
+            .collect::<Vec<_>>()
+    };
+    // [0, 20) is overlapped at its end, [30, 60) swallows [40, 45), and [58, 70) starts inside
+    // [30, 60).
+    let cmds = vec![
+        cmd(100, 0, 20),
+        cmd(500, 17, 13),
+        cmd(200, 30, 30),
+        cmd(700, 40, 5),
+        cmd(900, 58, 12),
+    ];
+    let expected = vec![
+        (100, 0, 17),
+        (500, 17, 13),
+        (200, 30, 10),
+        (700, 40, 5),
+        (215, 45, 13),
+        (900, 58, 12),
+    ];
+    for rotation in 0..cmds.len() {
+        let mut rotated = cmds.clone();
+        rotated.rotate_left(rotation);
+        testing_optimize_copy_cmds(&mut rotated);
+        assert_eq!(triples(&rotated), expected);
+    }
+
+    // Across lists, `other` wins over `base` at the same target.
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.base = vec![cmd(0, 0, 16), cmd(16, 16, 16)];
+    patch_commands.other = vec![cmd(12, 12, 4), cmd(16, 16, 8)];
+    patch_commands.dedupe_overlaps();
+    assert_eq!(triples(&patch_commands.base), vec![(0, 0, 12), (24, 24, 8)]);
+    assert_eq!(
+        triples(&patch_commands.other),
+        vec![(12, 12, 4), (16, 16, 8)]
+    );
+
+    let a: Vec<u8> = (0..32u8).collect();
+    let b: Vec<u8> = (0..32u8)
+        .map(|i| if (12..24).contains(&i) { !i } else { i })
+        .collect();
+    let patch = build_patch(&b, &patch_commands);
+    assert!(patch.validate_coverage().is_ok());
+    assert_eq!(apply_patch(&a, &patch), b);
+}
+
+#[test]
+fn test_dedupe_overlaps_nested() {
+    let cmd = |source: u64, target: u64, size: u32| CopyCmd {
+        source,
+        target,
+        size,
+        source_id: 0,
+    };
+    // Each command lies within the one before it.
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.base = vec![cmd(1000, 0, 100), cmd(2000, 10, 10), cmd(3000, 12, 2)];
+    patch_commands.dedupe_overlaps();
+    let triples: Vec<_> = patch_commands
+        .base
+        .iter()
+        .map(|cmd| (cmd.source, cmd.target, cmd.size))
+        .collect();
+    assert_eq!(
+        triples,
+        vec![
+            (1000, 0, 10),
+            (2000, 10, 2),
+            (3000, 12, 2),
+            (2004, 14, 6),
+            (1020, 20, 80)
+        ]
+    );
+    assert!(patch_commands
+        .base
+        .windows(2)
+        .all(|pair| pair[0].target + pair[0].size as u64 <= pair[1].target));
+
+    let a: Vec<u8> = (0..4000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    let b: Vec<u8> = (0..100usize)
+        .map(|i| match i {
+            12..=13 => a[3000 + i - 12],
+            10..=19 => a[2000 + i - 10],
+            _ => a[1000 + i],
+        })
+        .collect();
+    let patch = build_patch(&b, &patch_commands);
+    assert!(patch.validate_coverage().is_ok());
+    assert_eq!(apply_patch(&a, &patch), b);
+}
+
+#[cfg(feature = "suffix")]
+#[test]
+fn test_patch_suffix_scattered_edits() {
+    let a: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    for i in (100..b.len()).step_by(1000) {
+        b[i] ^= 0xFF;
+    }
+    b.splice(5000..5000, b"inserted".iter().cloned());
+    let patch_commands = compute_diff_suffix(&b, &a, 16);
+    assert!(patch_commands.need_bytes_from_other() < 200);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    let block_size = 256;
+    let b_blocks = compute_blocks(&b, block_size);
+    let block_commands = compute_diff(&a, &b_blocks, block_size);
+    assert!(patch_commands.need_bytes_from_other() < block_commands.need_bytes_from_other());
+
+    do_test_suffix(b"".to_vec(), b"abc".to_vec());
+    do_test_suffix(b"abc".to_vec(), b"".to_vec());
+    do_test_suffix(b"abababab".to_vec(), b"babababa".to_vec());
+}
+
+#[cfg(feature = "suffix")]
+fn do_test_suffix(a: Vec<u8>, b: Vec<u8>) {
+    let patch_commands = compute_diff_suffix(&b, &a, 2);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+}
+
+#[cfg(test)]
+fn do_test_patch_full(a: &[u8], b: &[u8], block_size: usize) {
+    let b_blocks = compute_blocks(b, block_size);
+    let patch_commands = compute_diff(a, &b_blocks, block_size);
+    let patch = build_patch(b, &patch_commands);
+    assert_eq!(patch.other_size, Some(b.len() as u64));
+    assert_eq!(apply_patch(a, &patch), b);
+    assert_eq!(patch.validate_coverage(), Ok(()));
+}
+
+#[test]
+fn test_patch_empty_inputs() {
+    do_test_patch_full(b"", b"", 4);
+    do_test_patch_full(b"", b"abcdefghij", 4);
+    do_test_patch_full(b"abcdefghij", b"", 4);
+    assert!(compute_diff::<Hash128>(b"", &[], 4).is_synchronized());
+}
+
+#[test]
+fn test_compute_diff_iter() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let b_blocks = compute_blocks(&b, 4);
+    let ops: Vec<DiffOp> = compute_diff_iter(&a, &b_blocks, 4).collect();
+    assert_eq!(
+        ops,
+        vec![
+            DiffOp::Copy {
+                source: 4,
+                target: 0,
+                size: 4
+            },
+            DiffOp::Literal { target: 4, size: 4 },
+            DiffOp::Copy {
+                source: 0,
+                target: 8,
+                size: 4
+            },
+        ]
+    );
+    let a_blocks = compute_blocks(&a, 4);
+    assert_eq!(compute_diff_iter(&a, &a_blocks, 4).count(), 0);
+}
+
+#[test]
+fn test_copy_cmd_execute_checked() {
+    let source = b"0123456789".to_vec();
+    let mut target = vec![0u8; 4];
+    let cmd = |source: u64, target: u64, size: u32| CopyCmd {
+        source,
+        target,
+        size,
+        source_id: 0,
+    };
+    assert_eq!(cmd(6, 0, 4).execute_checked(&mut target, &source), Ok(()));
+    assert_eq!(target, b"6789".to_vec());
+    assert!(matches!(
+        cmd(7, 0, 4).execute_checked(&mut target, &source),
+        Err(PatchError::SourceOutOfRange { offset: 7, .. })
+    ));
+    assert!(matches!(
+        cmd(0, 1, 4).execute_checked(&mut target, &source),
+        Err(PatchError::TargetOutOfRange { offset: 1, .. })
+    ));
+    assert!(matches!(
+        cmd(u64::MAX - 1, 0, u32::MAX).execute_checked(&mut target, &source),
+        Err(PatchError::SourceOutOfRange { .. })
+    ));
+    assert_eq!(target, b"6789".to_vec());
+}
+
+#[test]
+fn test_rolling_hash_roll() {
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 7919 % 256) as u8).collect();
+    for &window in &[1, 16, 1000] {
+        let mut hash = RollingHash::new();
+        hash.update(&data[0..window]);
+        for begin in 1..data.len() - window {
+            hash.roll(data[begin - 1], data[begin + window - 1]);
+            assert_eq!(hash.count(), window);
+            assert_eq!(hash.get(), compute_hash_weak(&data[begin..begin + window]));
+        }
+    }
+}
+
+#[test]
+fn test_diff_scanner_segments() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let b_blocks = compute_blocks(&b, 4);
+    let other_set = OtherBlockSet::new(&b_blocks);
+
+    let mut one_shot = DiffScanner::new(&other_set, 4);
+    one_shot.feed(&a);
+    one_shot.finish();
+
+    let mut scanner = DiffScanner::new(&other_set, 4);
+    scanner.feed(&a[0..3]);
+    assert_eq!(scanner.window_begin(), 0);
+    assert_eq!(scanner.window_end(), 3);
+    assert_eq!(scanner.rolling_hash().count(), 3);
+    assert!(scanner.step(a[3]));
+    assert_eq!(scanner.window_begin(), 4);
+    assert_eq!(scanner.window_end(), 4);
+    scanner.feed(&a[4..6]);
+    scanner.feed(&a[6..11]);
+    scanner.feed(&a[11..]);
+    scanner.finish();
+    assert_eq!(scanner.window_end(), a.len() as u64);
+    assert_blocks_eq(scanner.base_blocks(), one_shot.base_blocks());
+    assert_eq!(scanner.base_blocks().len(), 2);
+    assert_eq!(scanner.base_blocks()[1].offset, 4);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_paths() {
+    let dir = std::env::temp_dir().join(format!("patchy_test_mmap_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let a: Vec<u8> = (0..10000u32).map(|i| (i * 31 % 251) as u8).collect();
+    let mut b = a.clone();
+    b.splice(3000..3000, b"inserted".iter().cloned());
+    let a_path = dir.join("a");
+    let b_path = dir.join("b");
+    let out_path = dir.join("out");
+    let empty_path = dir.join("empty");
+    std::fs::write(&a_path, &a).unwrap();
+    std::fs::write(&b_path, &b).unwrap();
+    std::fs::write(&empty_path, b"").unwrap();
+
+    let b_blocks = signature_from_path(&b_path, 64).unwrap();
+    assert_blocks_eq(&b_blocks, &compute_blocks(&b, 64));
+    assert!(signature_from_path(&empty_path, 64).unwrap().is_empty());
+    assert!(signature_from_path(&b_path, 0).is_err());
+
+    let patch = build_patch(&b, &compute_diff(&a, &b_blocks, 64));
+    apply_patch_to_path(&a_path, &patch, &out_path).unwrap();
+    assert_eq!(std::fs::read(&out_path).unwrap(), b);
+
+    let patch = build_patch(&a, &compute_diff(b"", &compute_blocks(&a, 64), 64));
+    apply_patch_to_path(&empty_path, &patch, &out_path).unwrap();
+    assert_eq!(std::fs::read(&out_path).unwrap(), a);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(all(feature = "mmap", unix))]
+#[test]
+fn test_apply_patch_to_path_sparse() {
+    use std::os::unix::fs::MetadataExt;
+    let dir = std::env::temp_dir().join(format!("patchy_test_sparse_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let a: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    // Zero runs in the middle and at the end of the target, neither present in the base.
+    let mut b = a[..10000].to_vec();
+    b.resize(b.len() + 4 * 1024 * 1024, 0);
+    b.extend_from_slice(&a[10000..]);
+    b.resize(b.len() + 2 * 1024 * 1024 + 123, 0);
+    let a_path = dir.join("a");
+    let out_path = dir.join("out");
+    std::fs::write(&a_path, &a).unwrap();
+
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 1024), 1024);
+    let patch = build_patch(&b, &patch_commands);
+    apply_patch_to_path(&a_path, &patch, &out_path).unwrap();
+    assert_eq!(std::fs::read(&out_path).unwrap(), b);
+    let metadata = std::fs::metadata(&out_path).unwrap();
+    assert_eq!(metadata.len(), b.len() as u64);
+    assert!(metadata.blocks() * 512 < b.len() as u64 / 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_build_patch_optimal() {
+    let a = b"0123456789".to_vec();
+    let b = b"01x34y67z9".to_vec();
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 2), 2);
+    assert!(!patch_commands.base.is_empty());
+    let (patch, strategy) = build_patch_optimal(&b, &patch_commands);
+    assert_eq!(strategy, PatchStrategy::Whole);
+    assert!(patch.base.is_empty());
+    assert_eq!(patch.other.len(), 1);
+    assert_eq!(patch.data, b);
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    let a: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7 % 253) as u8).collect();
+    let mut b = a.clone();
+    b[1000] ^= 0xFF;
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 1024), 1024);
+    let (patch, strategy) = build_patch_optimal(&b, &patch_commands);
+    assert_eq!(strategy, PatchStrategy::Delta);
+    assert_eq!(apply_patch(&a, &patch), b);
+}
+
+#[test]
+fn test_patch_deterministic() {
+    let a: Vec<u8> = (0..32 * 1024u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b.splice(100..100, a[5000..9000].iter().cloned());
+    b[20000] ^= 0xFF;
+    let serialize_patch = || {
+        let patch_commands = compute_diff(&a, &compute_blocks(&b, 256), 256);
+        bincode::serialize(&build_patch(&b, &patch_commands)).unwrap()
+    };
+    assert_eq!(serialize_patch(), serialize_patch());
+
+    let mut hashes = [
+        compute_hash_strong(b"b"),
+        compute_hash_strong(b"a"),
+        compute_hash_strong(b"c"),
+    ];
+    hashes.sort();
+    assert!(hashes[0].as_bytes() < hashes[1].as_bytes());
+    assert!(hashes[1].as_bytes() < hashes[2].as_bytes());
+}
+
+// Number of pairs of adjacent sorted window hashes that are equal.
+#[cfg(test)]
+fn count_weak_collisions<W: WeakHash>(input: &[u8], block_size: usize) -> usize {
+    let mut hashes: Vec<u32> = Vec::with_capacity(input.len());
+    let mut hash = W::new();
+    for &x in &input[0..block_size] {
+        hash.add(x);
+    }
+    hashes.push(hash.get());
+    for begin in 1..input.len() - block_size {
+        hash.roll(input[begin - 1], input[begin + block_size - 1]);
+        hashes.push(hash.get());
+    }
+    hashes.sort_unstable();
+    hashes.windows(2).filter(|pair| pair[0] == pair[1]).count()
+}
+
+#[test]
+fn test_rolling_hash32() {
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 7919 % 256) as u8).collect();
+    for &window in &[1, 16, 1000] {
+        let mut hash = RollingHash32::new();
+        hash.update(&data[0..window]);
+        for begin in 1..data.len() - window {
+            hash.roll(data[begin - 1], data[begin + window - 1]);
+            assert_eq!(hash.count(), window);
+            let mut expected = RollingHash32::new();
+            expected.update(&data[begin..begin + window]);
+            assert_eq!(hash.get(), expected.get());
+        }
+    }
+
+    let mut state: u64 = 1;
+    let mut random_bytes = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    };
+    let input = random_bytes(1024 * 1024);
+    let other = random_bytes(1024 * 1024);
+    let collisions16 = count_weak_collisions::<RollingHash>(&input, 2048);
+    let collisions32 = count_weak_collisions::<RollingHash32>(&input, 2048);
+    println!(
+        "weak collisions: RollingHash {}, RollingHash32 {}",
+        collisions16, collisions32
+    );
+    assert!(collisions32 * 3 < collisions16);
+
+    let mut b = other.clone();
+    b.splice(1000..1000, input[0..3000].iter().cloned());
+    let b_blocks = try_compute_blocks_with::<Hash128, RollingHash32>(&b, 2048).unwrap();
+    let patch_commands =
+        try_compute_diff_with::<Hash128, RollingHash32>(&other, &b_blocks, 2048).unwrap();
+    assert!(patch_commands.need_bytes_from_other() < 4 * 2048);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&other, &patch), b);
+}
+
+#[test]
+fn test_patch_stats() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 4), 4));
+    let stats = patch.stats();
+    assert_eq!(stats.literal_bytes, 4);
+    assert_eq!(stats.base_bytes, 8);
+    assert_eq!(stats.base_commands, 2);
+    assert_eq!(stats.other_commands, 1);
+    assert_eq!(stats.other_size, 12);
+    assert!((stats.reuse_ratio - 8.0 / 12.0).abs() < 1e-9);
+
+    let empty = build_patch(b"", &compute_diff(&a, &compute_blocks(b"", 4), 4));
+    assert_eq!(empty.stats().reuse_ratio, 0.0);
+}
+
+#[test]
+fn test_patch_efficiency() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 4), 4));
+    assert!((patch.efficiency() - 8.0 / 12.0).abs() < 1e-9);
+    assert!((patch.literal_ratio() - 4.0 / 12.0).abs() < 1e-9);
+
+    // A small edit in the middle of a large file reuses almost everything.
+    let a: Vec<u8> = (0..1024 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b[500_000..500_010].copy_from_slice(b"0123456789");
+    let block_size = recommended_block_size(b.len());
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, block_size), block_size);
+    let patch =
+        build_patch_compressed(&b, &patch_commands, Compression::Zstd { level: 3 }).unwrap();
+    assert!(patch.efficiency() > 0.99);
+    assert!(patch.literal_ratio() < 0.01);
+    assert!((patch.efficiency() + patch.literal_ratio() - 1.0).abs() < 1e-9);
+
+    let empty = build_patch(b"", &compute_diff(&a, &compute_blocks(b"", 4), 4));
+    assert_eq!(empty.efficiency(), 0.0);
+    assert_eq!(empty.literal_ratio(), 0.0);
+}
+
+#[test]
+fn test_apply_patch_into() {
+    let a: Vec<u8> = (0..8192u32).map(|i| (i * 17 % 251) as u8).collect();
+    let mut scratch: Vec<u8> = Vec::new();
+    let mut capacity = 0;
+    for len in &[8000usize, 4000, 6000, 0] {
+        let b = a[100..100 + len].to_vec();
+        let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 64), 64));
+        apply_patch_into(&a, &patch, &mut scratch).unwrap();
+        assert_eq!(scratch, b);
+        if capacity == 0 {
+            capacity = scratch.capacity();
+        } else {
+            assert_eq!(scratch.capacity(), capacity);
+        }
+    }
+}
+
+#[test]
+fn test_patch_encode_decode() {
+    assert_eq!(compute_crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(compute_crc32(b""), 0);
+
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 4), 4));
+    let encoded = patch.encode();
+    assert_eq!(&encoded[0..4], b"PTCY");
+    let decoded = Patch::decode(&encoded).unwrap();
+    assert_eq!(
+        bincode::serialize(&decoded).unwrap(),
+        bincode::serialize(&patch).unwrap()
+    );
+    assert_eq!(apply_patch(&a, &decoded), b);
+
+    let mut bad_magic = encoded.clone();
+    bad_magic[0] = b'X';
+    assert_eq!(Patch::decode(&bad_magic).err(), Some(PatchError::BadMagic));
+
+    let mut bad_version = encoded.clone();
+    bad_version[4] = 0xFF;
+    assert_eq!(
+        Patch::decode(&bad_version).err(),
+        Some(PatchError::UnsupportedVersion { version: 0xFF })
+    );
+
+    let truncated = &encoded[0..encoded.len() - 1];
+    assert_eq!(
+        Patch::decode(truncated).err(),
+        Some(PatchError::LengthMismatch {
+            expected: encoded.len() as u64,
+            actual: encoded.len() as u64 - 1
+        })
+    );
+    assert!(matches!(
+        Patch::decode(&encoded[0..8]),
+        Err(PatchError::LengthMismatch { .. })
+    ));
+
+    let mut corrupted = encoded.clone();
+    corrupted[20] ^= 1;
+    assert!(matches!(
+        Patch::decode(&corrupted),
+        Err(PatchError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_patch_encode_canonical_bytes() {
+    let patch = Patch {
+        data: b"hi".to_vec(),
+        base: vec![
+            CopyCmd {
+                source: 300,
+                target: 2,
+                size: 5,
+                source_id: 0,
+            },
+            CopyCmd {
+                source: 0,
+                target: 0,
+                size: 1,
+                source_id: 0,
+            },
+        ],
+        other: vec![],
+        other_size: Some(0x1234),
+        compression: Compression::Zstd { level: -1 },
+        other_hash: Some(Hash128::from_bytes(&[0xAA; 16])),
+        base_hash: None,
+        block_size: 4096,
+    };
+    // Spelled out byte by byte so the expectation does not depend on the host's endianness.
+    let mut body: Vec<u8> = vec![1];
+    body.extend_from_slice(&[0x34, 0x12, 0, 0, 0, 0, 0, 0]);
+    body.extend_from_slice(&[0x00, 0x10, 0, 0, 0, 0, 0, 0]);
+    body.extend_from_slice(&[1, 0xFF, 0xFF, 0xFF, 0xFF]);
+    body.push(1);
+    body.extend_from_slice(&[0xAA; 16]);
+    body.push(0);
+    body.push(2);
+    // source +300, target +2, size 5, source_id 0
+    body.extend_from_slice(&[0xD8, 0x04, 4, 5, 0]);
+    // source -305 and target -7 relative to the end of the previous command
+    body.extend_from_slice(&[0xE1, 0x04, 13, 1, 0]);
+    body.push(0);
+    body.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+    body.extend_from_slice(b"hi");
+    let mut expected: Vec<u8> = b"PTCY".to_vec();
+    expected.extend_from_slice(&[6, 0]);
+    expected.extend_from_slice(&[body.len() as u8, 0, 0, 0, 0, 0, 0, 0]);
+    expected.extend_from_slice(&body);
+    let crc = compute_crc32(&body);
+    expected.extend_from_slice(&[
+        crc as u8,
+        (crc >> 8) as u8,
+        (crc >> 16) as u8,
+        (crc >> 24) as u8,
+    ]);
+
+    assert_eq!(patch.encode(), expected);
+    let decoded = Patch::decode(&expected).unwrap();
+    assert_eq!(
+        bincode::serialize(&decoded).unwrap(),
+        bincode::serialize(&patch).unwrap()
+    );
+
+    // A command count larger than the body is rejected without allocating for it.
+    let mut bad_count = body.clone();
+    bad_count[40] = 0x7F;
+    let mut encoded: Vec<u8> = b"PTCY".to_vec();
+    encoded.extend_from_slice(&[6, 0]);
+    encoded.extend_from_slice(&[bad_count.len() as u8, 0, 0, 0, 0, 0, 0, 0]);
+    encoded.extend_from_slice(&bad_count);
+    encoded.extend_from_slice(&compute_crc32(&bad_count).to_le_bytes());
+    assert!(matches!(
+        Patch::decode(&encoded),
+        Err(PatchError::Serialization(_))
+    ));
+}
+
+#[test]
+fn test_patch_encode_varint_commands() {
+    // Fragmented: short copies alternating with short literals, as from a heavily edited file.
+    let mut base = vec![];
+    let mut other = vec![];
+    let mut data_len: u64 = 0;
+    for i in 0..2000u64 {
+        base.push(CopyCmd {
+            source: i * 40 + 1_000_000,
+            target: i * 40,
+            size: 32,
+            source_id: 0,
+        });
+        other.push(CopyCmd {
+            source: data_len,
+            target: i * 40 + 32,
+            size: 8,
+            source_id: 0,
+        });
+        data_len += 8;
+    }
+    let cmd_count = base.len() + other.len();
+    let patch = Patch {
+        data: vec![7; data_len as usize],
+        base,
+        other,
+        other_size: Some(2000 * 40),
+        compression: Compression::None,
+        other_hash: None,
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    };
+    let encoded = patch.encode();
+    let decoded = Patch::decode(&encoded).unwrap();
+    assert_eq!(
+        bincode::serialize(&decoded).unwrap(),
+        bincode::serialize(&patch).unwrap()
+    );
+    let fixed_cmds_len = cmd_count * (8 + 8 + 4 + 4);
+    // Header, checksum and the fixed-width body fields take 44 bytes.
+    let cmds_len = encoded.len() - data_len as usize - 44;
+    assert!(cmds_len * 100 < fixed_cmds_len * 40);
+
+    let extremes = Patch {
+        data: vec![],
+        base: vec![
+            CopyCmd {
+                source: u64::MAX - 10,
+                target: 5,
+                size: 10,
+                source_id: SELF_SOURCE_ID,
+            },
+            CopyCmd {
+                source: 0,
+                target: u64::MAX - u32::MAX as u64,
+                size: u32::MAX,
+                source_id: 3,
+            },
+        ],
+        other: vec![],
+        other_size: Some(0),
+        compression: Compression::None,
+        other_hash: None,
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    };
+    let decoded = Patch::decode(&extremes.encode()).unwrap();
+    assert_eq!(
+        bincode::serialize(&decoded).unwrap(),
+        bincode::serialize(&extremes).unwrap()
+    );
+}
+
+#[test]
+fn test_patch_invert() {
+    let a: Vec<u8> = (0..20000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b.splice(7000..7100, b"inserted".iter().cloned());
+    b.extend_from_slice(b"appended tail");
+    let forward = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 512), 512));
+    let result = apply_patch(&a, &forward);
+    let reverse = forward.invert(&a, &result);
+    assert_eq!(reverse.block_size, 512);
+    assert_eq!(apply_patch_verified(&result, &reverse).unwrap(), a);
+
+    let unchanged = build_patch(&a, &compute_diff(&a, &compute_blocks(&a, 512), 512));
+    assert_eq!(apply_patch(&a, &unchanged.invert(&a, &a)), a);
+
+    assert_eq!(
+        forward.try_invert(&a, &a).err(),
+        Some(PatchError::LengthMismatch {
+            expected: b.len() as u64,
+            actual: a.len() as u64
+        })
+    );
+}
+
+#[test]
+fn test_patch_self_ref_copy() {
+    let b: Vec<u8> = vec![b'x'; 100 * 1024];
+    let patch = Patch {
+        data: vec![b'x'],
+        base: vec![CopyCmd {
+            source: 0,
+            target: 1,
+            size: b.len() as u32 - 1,
+            source_id: SELF_SOURCE_ID,
+        }],
+        other: vec![CopyCmd {
+            source: 0,
+            target: 0,
+            size: 1,
+            source_id: 0,
+        }],
+        other_size: Some(b.len() as u64),
+        compression: Compression::None,
+        other_hash: Some(compute_hash_strong(&b)),
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    };
+    assert!(patch.validate_coverage().is_ok());
+    assert_eq!(apply_patch_verified(b"", &patch).unwrap(), b);
+    let mut out: Vec<u8> = Vec::new();
+    assert!(apply_patch_to_writer(b"", &patch, &mut out).is_err());
+
+    // Repeating a 3-byte pattern: source and target overlap by all but 3 bytes.
+    let mut output = b"abc\0\0\0\0\0\0\0\0".to_vec();
+    let cmd = CopyCmd {
+        source: 0,
+        target: 3,
+        size: 8,
+        source_id: SELF_SOURCE_ID,
+    };
+    cmd.execute_self_checked(&mut output).unwrap();
+    assert_eq!(output, b"abcabcabcab");
+
+    let backwards = CopyCmd {
+        source: 3,
+        target: 3,
+        size: 1,
+        source_id: SELF_SOURCE_ID,
+    };
+    assert!(backwards.execute_self_checked(&mut output).is_err());
+
+    // An empty self-copy reading after its target writes nothing.
+    let empty = CopyCmd {
+        source: 8,
+        target: 2,
+        size: 0,
+        source_id: SELF_SOURCE_ID,
+    };
+    empty.execute_self_checked(&mut output).unwrap();
+    assert_eq!(output, b"abcabcabcab");
+    let mut crafted = Patch {
+        data: b"abc".to_vec(),
+        base: vec![empty],
+        other: vec![CopyCmd {
+            source: 0,
+            target: 0,
+            size: 3,
+            source_id: 0,
+        }],
+        other_size: Some(3),
+        compression: Compression::None,
+        other_hash: None,
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    };
+    assert_eq!(apply_patch_checked(b"", &crafted).unwrap(), b"abc");
+    crafted.base[0].size = 1;
+    assert!(apply_patch_checked(b"", &crafted).is_err());
+}
+
+// Minimal RFC 3284 decoder for the instructions `to_vcdiff` emits: single ADD and COPY opcodes
+// of the default code table, no secondary compression.
+fn decode_vcdiff(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    fn read_int(bytes: &[u8], pos: &mut usize) -> u64 {
+        let mut value: u64 = 0;
+        loop {
+            let b = bytes[*pos];
+            *pos += 1;
+            value = (value << 7) | (b & 0x7F) as u64;
+            if b & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+    assert_eq!(&delta[..5], &[0xD6, 0xC3, 0xC4, 0x00, 0x00]);
+    let mut output: Vec<u8> = Vec::new();
+    let mut pos = 5;
+    while pos < delta.len() {
+        let indicator = delta[pos];
+        pos += 1;
+        let mut segment: Vec<u8> = Vec::new();
+        if indicator != 0 {
+            let len = read_int(delta, &mut pos) as usize;
+            let begin = read_int(delta, &mut pos) as usize;
+            segment = match indicator {
+                1 => base[begin..begin + len].to_vec(),
+                2 => output[begin..begin + len].to_vec(),
+                _ => panic!("bad window indicator {}", indicator),
+            };
+        }
+        let delta_len = read_int(delta, &mut pos) as usize;
+        let window_end = pos + delta_len;
+        let target_len = read_int(delta, &mut pos) as usize;
+        assert_eq!(delta[pos], 0);
+        pos += 1;
+        let data_len = read_int(delta, &mut pos) as usize;
+        let inst_len = read_int(delta, &mut pos) as usize;
+        let addr_len = read_int(delta, &mut pos) as usize;
+        let mut data = pos;
+        let mut inst = data + data_len;
+        let inst_end = inst + inst_len;
+        let mut addr = inst_end;
+        assert_eq!(addr + addr_len, window_end);
+
+        let mut near = [0usize; 4];
+        let mut next_slot = 0;
+        let mut same = [0usize; 3 * 256];
+        let mut u = segment;
+        let target_begin = u.len();
+        while inst < inst_end {
+            let opcode = delta[inst] as usize;
+            inst += 1;
+            match opcode {
+                1..=18 => {
+                    let size = if opcode == 1 {
+                        read_int(delta, &mut inst) as usize
+                    } else {
+                        opcode - 1
+                    };
+                    u.extend_from_slice(&delta[data..data + size]);
+                    data += size;
+                }
+                19..=162 => {
+                    let mode = (opcode - 19) / 16;
+                    let size = match (opcode - 19) % 16 {
+                        0 => read_int(delta, &mut inst) as usize,
+                        n => n + 3,
+                    };
+                    let here = u.len();
+                    let a = match mode {
+                        0 => read_int(delta, &mut addr) as usize,
+                        1 => here - read_int(delta, &mut addr) as usize,
+                        2..=5 => near[mode - 2] + read_int(delta, &mut addr) as usize,
+                        _ => {
+                            addr += 1;
+                            same[(mode - 6) * 256 + delta[addr - 1] as usize]
+                        }
+                    };
+                    near[next_slot] = a;
+                    next_slot = (next_slot + 1) % 4;
+                    same[a % (3 * 256)] = a;
+                    for i in 0..size {
+                        let b = u[a + i];
+                        u.push(b);
+                    }
+                }
+                _ => panic!("unexpected opcode {}", opcode),
+            }
+        }
+        assert_eq!(u.len() - target_begin, target_len);
+        output.extend_from_slice(&u[target_begin..]);
+        pos = window_end;
+    }
+    output
+}
+
+#[test]
+fn test_patch_to_vcdiff() {
+    let a: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b.splice(100..100, a[50000..53000].iter().cloned());
+    b.splice(20000..20000, (0..700u32).map(|i| (i * 7) as u8));
+    b[40000] ^= 0xFF;
+    b.extend_from_slice(&a[1000..9000]);
+    b.truncate(b.len() - 5);
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 256), 256);
+    let patch = build_patch(&b, &patch_commands);
+    let vcdiff = patch.to_vcdiff().unwrap();
+    assert_eq!(decode_vcdiff(&a, &vcdiff), b);
+    assert!(vcdiff.len() < patch.data.len() + 1024);
+    // Small windows split commands and reset the address caches.
+    let vcdiff = crate::vcdiff::encode_vcdiff(&patch, 1000).unwrap();
+    assert_eq!(decode_vcdiff(&a, &vcdiff), b);
+
+    // Self-copies, including overlapping ones and ones reaching back into earlier windows.
+    let mut c: Vec<u8> = b"abc".to_vec();
+    for i in 3..5000 {
+        let x = c[i - 3];
+        c.push(x);
+    }
+    c.extend_from_within(100..2100);
+    let mut patch = Patch {
+        data: b"abc".to_vec(),
+        base: vec![
+            CopyCmd {
+                source: 0,
+                target: 3,
+                size: 4997,
+                source_id: SELF_SOURCE_ID,
+            },
+            CopyCmd {
+                source: 100,
+                target: 5000,
+                size: 2000,
+                source_id: SELF_SOURCE_ID,
+            },
+        ],
+        other: vec![CopyCmd {
+            source: 0,
+            target: 0,
+            size: 3,
+            source_id: 0,
+        }],
+        other_size: Some(c.len() as u64),
+        compression: Compression::None,
+        other_hash: None,
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    };
+    assert_eq!(apply_patch_verified(b"", &patch).unwrap(), c);
+    for window_size in [64, 1000, 1 << 23] {
+        let vcdiff = crate::vcdiff::encode_vcdiff(&patch, window_size).unwrap();
+        assert_eq!(decode_vcdiff(b"", &vcdiff), c);
+    }
+
+    patch.base[0].source_id = 1;
+    assert_eq!(
+        patch.to_vcdiff(),
+        Err(PatchError::InvalidSourceId { source_id: 1 })
+    );
+}
+
+#[test]
+fn test_files_identical() {
+    assert!(files_identical(b"", b""));
+    assert!(files_identical(b"0123456789", b"0123456789"));
+    assert!(!files_identical(b"0123456789", b"012345678"));
+    assert!(!files_identical(b"0123456789", b"0123456780"));
+    assert!(!files_identical(b"", b"0"));
+}
+
+#[test]
+fn test_apply_patch_from_base_reader() {
+    let a: Vec<u8> = (0..8 * 1024u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b.splice(100..100, a[5000..6000].iter().cloned());
+    b[7000] ^= 0xFF;
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 256), 256);
+    let patch = build_patch_with_base(&a, &b, &patch_commands);
+    let mut out: Vec<u8> = Vec::new();
+    apply_patch_from_base_reader(&mut std::io::Cursor::new(&a), &patch, &mut out).unwrap();
+    assert_eq!(out, b);
+
+    let mut wrong_base = a.clone();
+    wrong_base[0] ^= 1;
+    let error = apply_patch_from_base_reader(
+        &mut std::io::Cursor::new(&wrong_base),
+        &patch,
+        &mut Vec::new(),
+    )
+    .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // Without a base hash, a base that is too short fails on the first copy past its end.
+    let unchecked = build_patch(&b, &patch_commands);
+    let error = apply_patch_from_base_reader(
+        &mut std::io::Cursor::new(&a[0..4096]),
+        &unchecked,
+        &mut Vec::new(),
+    )
+    .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_apply_patch_partial() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123yy".to_vec();
+    let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 4), 4));
+    let command_count = patch.base.len() + patch.other.len();
+
+    let (output, missing) = apply_patch_partial(&a, &patch, 0);
+    assert_eq!(output, vec![0; b.len()]);
+    assert_eq!(missing, vec![0..b.len() as u64]);
+
+    let (output, missing) = apply_patch_partial(&a, &patch, command_count);
+    assert_eq!(output, b);
+    assert!(missing.is_empty());
+
+    // Only the base copies arrived: the literal ranges are missing.
+    let (output, missing) = apply_patch_partial(&a, &patch, patch.base.len());
+    let mut expected_missing: Vec<std::ops::Range<u64>> = patch
+        .other
+        .iter()
+        .map(|cmd| cmd.target..cmd.target + cmd.size as u64)
+        .collect();
+    expected_missing.sort_by_key(|range| range.start);
+    assert_eq!(missing, expected_missing);
+    for range in &missing {
+        assert!(output[range.start as usize..range.end as usize]
+            .iter()
+            .all(|x| *x == 0));
+    }
+    assert_eq!(&output[0..4], b"4567");
+
+    // A self copy from a region that hasn't arrived is reported as missing too.
+    let self_patch = Patch {
+        data: b"ab".to_vec(),
+        base: vec![CopyCmd {
+            source: 0,
+            target: 2,
+            size: 4,
+            source_id: SELF_SOURCE_ID,
+        }],
+        other: vec![CopyCmd {
+            source: 0,
+            target: 0,
+            size: 2,
+            source_id: 0,
+        }],
+        other_size: Some(6),
+        compression: Compression::None,
+        other_hash: None,
+        base_hash: None,
+        block_size: 4,
+    };
+    assert_eq!(apply_patch_partial(b"", &self_patch, 1).1, vec![0..6]);
+    let (output, missing) = apply_patch_partial(b"", &self_patch, 2);
+    assert_eq!(output, b"ababab");
+    assert!(missing.is_empty());
+}
+
+#[test]
+fn test_compute_diff_with_whole_hash() {
+    let a: Vec<u8> = (0..10000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let whole_hash = compute_hash_strong(&a);
+
+    // Blocks with wrong hashes would produce literals if the scan ran.
+    let mut a_blocks = compute_blocks(&a, 256);
+    for block in a_blocks.iter_mut() {
+        block.hash_weak = 0;
+        block.hash_strong = Hash128::new_zero();
+    }
+    let patch_commands = compute_diff_with_whole_hash(&a, &a_blocks, 256, Some(whole_hash));
+    assert!(patch_commands.is_synchronized());
+    let patch_commands = compute_diff_with_whole_hash(&a, &a_blocks, 256, None);
+    assert!(!patch_commands.is_synchronized());
+
+    let mut b = a.clone();
+    b[5000] ^= 0xFF;
+    let b_blocks = compute_blocks(&b, 256);
+    let patch_commands =
+        compute_diff_with_whole_hash(&a, &b_blocks, 256, Some(compute_hash_strong(&b)));
+    assert_eq!(patch_commands.need_bytes_from_other(), 256);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_patch_to_json() {
+    let patch = Patch {
+        data: b"hello".to_vec(),
+        base: vec![CopyCmd {
+            source: 0x1000,
+            target: 0,
+            size: 0x20,
+            source_id: 0,
+        }],
+        other: vec![CopyCmd {
+            source: 0,
+            target: 0x20,
+            size: 5,
+            source_id: 0,
+        }],
+        other_size: Some(0x25),
+        compression: Compression::None,
+        other_hash: Some(Hash128::from_bytes(&[0xAB; 16])),
+        base_hash: None,
+        block_size: 4096,
+    };
+    let json: serde_json::Value = serde_json::from_str(&patch.to_json(true)).unwrap();
+    assert_eq!(json["other_size"], "0x25");
+    assert_eq!(json["block_size"], "0x1000");
+    assert_eq!(json["other_hash"], "ab".repeat(16));
+    assert_eq!(json["base_hash"], serde_json::Value::Null);
+    assert_eq!(json["base"][0]["source"], "0x1000");
+    assert_eq!(json["base"][0]["size"], "0x20");
+    assert_eq!(json["other"][0]["target"], "0x20");
+    assert_eq!(json["data_len"], "0x5");
+    assert_eq!(json["data"], "aGVsbG8=");
+
+    let json: serde_json::Value = serde_json::from_str(&patch.to_json(false)).unwrap();
+    assert_eq!(json["data_len"], "0x5");
+    assert!(json.get("data").is_none());
+
+    for (data, expected) in &[
+        (&b""[..], ""),
+        (b"h", "aA=="),
+        (b"hi", "aGk="),
+        (b"hi!", "aGkh"),
+    ] {
+        let patch = Patch {
+            data: data.to_vec(),
+            ..Patch::decode(&patch.encode()).unwrap()
+        };
+        let json: serde_json::Value = serde_json::from_str(&patch.to_json(true)).unwrap();
+        assert_eq!(json["data"], *expected);
+    }
+}
+
+#[cfg(feature = "signing")]
+#[test]
+fn test_patch_signed() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 4), 4));
+    let signing_key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+    let key = signing_key.verifying_key();
+    let signed = patch.encode_signed(&signing_key);
+    assert_eq!(
+        &signed[0..signed.len() - SIGNATURE_LEN],
+        &patch.encode()[..]
+    );
+    let decoded = Patch::decode_verified(&signed, &key).unwrap();
+    assert_eq!(apply_patch(&a, &decoded), b);
+
+    // RFC 8032 test vector 1: the signature of the empty message.
+    let rfc_key = ed25519_dalek::SigningKey::from_bytes(&[
+        0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c,
+        0xc4, 0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae,
+        0x7f, 0x60,
+    ]);
+    let signature = ed25519_dalek::Signer::sign(&rfc_key, b"").to_bytes();
+    assert_eq!(&signature[..4], &[0xe5, 0x56, 0x43, 0x00]);
+    assert_eq!(&signature[60..], &[0x8e, 0x7a, 0x10, 0x0b]);
+
+    let other_key = ed25519_dalek::SigningKey::from_bytes(&[8; 32]).verifying_key();
+    assert_eq!(
+        Patch::decode_verified(&signed, &other_key).err(),
+        Some(PatchError::BadSignature)
+    );
+    for i in &[0, 5, 20, signed.len() - 40, signed.len() - 1] {
+        let mut tampered = signed.clone();
+        tampered[*i] ^= 1;
+        assert_eq!(
+            Patch::decode_verified(&tampered, &key).err(),
+            Some(PatchError::BadSignature)
+        );
+    }
+    assert!(Patch::decode_verified(&signed[0..10], &key).is_err());
+}
+
+#[test]
+fn test_checked_usize() {
+    assert_eq!(checked_usize(0), Ok(0));
+    assert_eq!(checked_usize(u32::MAX as u64), Ok(u32::MAX as usize));
+    if usize::BITS < 64 {
+        assert_eq!(
+            checked_usize(u32::MAX as u64 + 1),
+            Err(PatchError::SizeOverflow {
+                size: u32::MAX as u64 + 1
+            })
+        );
+        let patch = Patch {
+            data: Vec::new(),
+            base: Vec::new(),
+            other: Vec::new(),
+            other_size: Some(u64::MAX),
+            compression: Compression::None,
+            other_hash: None,
+            base_hash: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+        };
+        assert_eq!(
+            apply_patch_checked(b"", &patch).err(),
+            Some(PatchError::SizeOverflow { size: u64::MAX })
+        );
+    } else {
+        assert_eq!(checked_usize(u64::MAX), Ok(usize::MAX));
+    }
+}
+
+#[test]
+fn test_differ() {
+    let a: Vec<u8> = (0..20000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b.splice(7000..7000, b"inserted".iter().cloned());
+
+    // Without a block size, the signature picks one for the length of its data.
+    let differ = Differ::new();
+    assert_eq!(differ.get_block_size(), None);
+    assert_eq!(differ.strong_hash_bits(), 128);
+    let signature = differ.signature(&b).unwrap();
+    assert_eq!(signature.block_size, recommended_block_size(b.len()));
+    let patch = differ
+        .build(&b, &differ.diff(&a, &signature).unwrap())
+        .unwrap();
+    assert_eq!(patch.block_size, signature.block_size);
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    let differ = Differ::new().block_size(DEFAULT_BLOCK_SIZE);
+    let signature = differ.signature(&b).unwrap();
+    assert_eq!(signature.block_size, DEFAULT_BLOCK_SIZE);
+    assert_blocks_eq(&signature.blocks, &compute_blocks(&b, DEFAULT_BLOCK_SIZE));
+    let patch_commands = differ.diff(&a, &signature).unwrap();
+    let patch = differ.build(&b, &patch_commands).unwrap();
+    let expected = build_patch(&b, &compute_diff(&a, &signature.blocks, DEFAULT_BLOCK_SIZE));
+    assert_eq!(
+        bincode::serialize(&patch).unwrap(),
+        bincode::serialize(&expected).unwrap()
+    );
+
+    let differ = Differ::new()
+        .block_size(512)
+        .strong_hash::<Hash256>()
+        .compression(Compression::Zstd { level: 3 });
+    assert_eq!(differ.strong_hash_bits(), 256);
+    let signature = differ.signature(&b).unwrap();
+    let patch_commands = differ.diff(&a, &signature).unwrap();
+    let patch = differ.build(&b, &patch_commands).unwrap();
+    assert_eq!(patch.block_size, 512);
+    assert_eq!(patch.compression, Compression::Zstd { level: 3 });
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    let other_differ = Differ::new().block_size(1024).strong_hash::<Hash256>();
+    assert_eq!(
+        other_differ.diff(&a, &signature).err(),
+        Some(PatchError::BlockSizeMismatch {
+            expected: 1024,
+            actual: 512
+        })
+    );
+    // Blocks that don't fit the recorded block size are rejected as well.
+    let small_differ = Differ::new().block_size(256).strong_hash::<Hash256>();
+    let mislabeled = Signature {
+        block_size: 256,
+        blocks: signature.blocks,
+    };
+    assert!(small_differ.diff(&a, &mislabeled).is_err());
+    assert_eq!(
+        other_differ.build(&b, &patch_commands).err(),
+        Some(PatchError::BlockSizeMismatch {
+            expected: 1024,
+            actual: 512
+        })
+    );
+}
+
+#[test]
+fn test_window_differ() {
+    let a: Vec<u8> = (0..10000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b[5000..5016].copy_from_slice(b"0123456789abcdef");
+    b.truncate(9000);
+
+    let mut differ = WindowDiffer::new(4096, 256);
+    assert_eq!(differ.window_size(), 4096);
+    let mut patches = vec![];
+    for (a_chunk, b_chunk) in a
+        .chunks(1000)
+        .zip(b.chunks(1000).chain(std::iter::repeat(&[][..])))
+    {
+        differ.feed_base(a_chunk);
+        differ.feed_target(b_chunk);
+        while let Some(patch) = differ.next_window() {
+            patches.push(patch);
+        }
+    }
+    assert_eq!(patches.len(), 2);
+    differ.finish();
+    while let Some(patch) = differ.next_window() {
+        patches.push(patch);
+    }
+    assert!(differ.next_window().is_none());
+    assert_eq!(patches.len(), 3);
+
+    let mut result = vec![];
+    for (i, patch) in patches.iter().enumerate() {
+        let base_window = &a[i * 4096..a.len().min((i + 1) * 4096)];
+        result.extend(apply_patch(base_window, patch));
+    }
+    assert_eq!(result, b);
+    assert_eq!(patches[0].data.len(), 0);
+    assert_eq!(patches[0].base.len(), 1);
+    assert!(patches[1].data.len() < 4096);
+}
+
+#[test]
+fn test_compute_diff_block_size_mismatch() {
+    let a: Vec<u8> = (0..20000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let blocks = compute_blocks(&a, 4096);
+    assert_eq!(
+        try_compute_diff(&a, &blocks, 2048).err(),
+        Some(PatchError::BlockSizeMismatch {
+            expected: 2048,
+            actual: 4096
+        })
+    );
+    assert_eq!(
+        try_compute_diff(&a, &blocks, 8192).err(),
+        Some(PatchError::BlockSizeMismatch {
+            expected: 8192,
+            actual: 4096
+        })
+    );
+    // A single short block is a valid signature for any larger block size.
+    let short_blocks = compute_blocks(&a[0..1000], 4096);
+    assert!(try_compute_diff(&a, &short_blocks, 2048).is_ok());
+    assert!(try_compute_diff(&a, &short_blocks, 512).is_err());
+    assert!(try_compute_diff(&a, &blocks, 4096).is_ok());
+}
+
+#[test]
+fn test_apply_patch_parallel() {
+    let a: Vec<u8> = (0..5 * PARALLEL_APPLY_CHUNK_SIZE as u32 / 2)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b.splice(1000..1000, b"inserted".iter().cloned());
+    b[PARALLEL_APPLY_CHUNK_SIZE - 100..PARALLEL_APPLY_CHUNK_SIZE + 100]
+        .copy_from_slice(&[b'x'; 200]);
+    let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 4096), 4096));
+    assert!(patch
+        .base
+        .iter()
+        .chain(patch.other.iter())
+        .any(|cmd| cmd.target / PARALLEL_APPLY_CHUNK_SIZE as u64
+            != (cmd.target + cmd.size as u64 - 1) / PARALLEL_APPLY_CHUNK_SIZE as u64));
+    assert_eq!(apply_patch_parallel(&a, &patch), apply_patch(&a, &patch));
+    assert_eq!(apply_patch_parallel(&a, &patch), b);
+
+    // Later commands win where copies overlap, as in the serial apply.
+    let overlapping = Patch {
+        data: vec![b'y'; 10],
+        base: vec![CopyCmd {
+            source: 0,
+            target: 0,
+            size: 20,
+            source_id: 0,
+        }],
+        other: vec![CopyCmd {
+            source: 0,
+            target: 5,
+            size: 10,
+            source_id: 0,
+        }],
+        other_size: Some(20),
+        compression: Compression::None,
+        other_hash: None,
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    };
+    assert_eq!(
+        apply_patch_parallel(&a, &overlapping),
+        apply_patch(&a, &overlapping)
+    );
+    assert_eq!(
+        apply_patch_parallel_checked(&a[0..10], &overlapping).err(),
+        apply_patch_checked(&a[0..10], &overlapping).err()
+    );
+}
+
+#[test]
+fn test_compute_blocks_cdc() {
+    let a: Vec<u8> = (0..200000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let a_blocks = compute_blocks_cdc(&a, 512, 2048, 8192);
+    assert_eq!(
+        a_blocks.iter().map(|b| b.size as usize).sum::<usize>(),
+        a.len()
+    );
+    for block in &a_blocks[..a_blocks.len() - 1] {
+        assert!(block.size >= 512 && block.size <= 8192);
+    }
+    assert!(a_blocks.len() > 200000 / 8192 && a_blocks.len() < 200000 / 512);
+
+    let mut b = a.clone();
+    b.insert(100, b'x');
+    let b_blocks = compute_blocks_cdc(&b, 512, 2048, 8192);
+    // Boundaries after the insertion are the same, shifted by one byte.
+    let shifted: Vec<(u64, u32)> = a_blocks[2..]
+        .iter()
+        .map(|b| (b.offset + 1, b.size))
+        .collect();
+    let b_tail: Vec<(u64, u32)> = b_blocks[2..].iter().map(|b| (b.offset, b.size)).collect();
+    assert_eq!(shifted, b_tail);
+
+    let patch_commands = compute_diff_cdc(&a, &b_blocks, 512, 2048, 8192);
+    assert!(patch_commands.need_bytes_from_other() <= 8192);
+    assert_eq!(patch_commands.block_size, 2048);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    assert!(try_compute_blocks_cdc::<Hash128>(&a, 0, 2048, 8192).is_err());
+    assert!(try_compute_blocks_cdc::<Hash128>(&a, 4096, 2048, 8192).is_err());
+    assert!(compute_blocks_cdc(&[], 512, 2048, 8192).is_empty());
+}
+
+#[test]
+fn test_compute_diff_with_progress() {
+    let a: Vec<u8> = (0..5 * DIFF_PROGRESS_INTERVAL as u32 / 2)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b.splice(1000..1000, b"inserted".iter().cloned());
+    let b_blocks = compute_blocks(&b, 4096);
+
+    let mut positions: Vec<u64> = vec![];
+    let mut record = |position: u64| positions.push(position);
+    let patch_commands = compute_diff_with_progress(&a, &b_blocks, 4096, Some(&mut record));
+    assert_eq!(positions.len(), 3);
+    assert!(positions.windows(2).all(|w| w[0] < w[1]));
+    assert!(*positions.last().unwrap() <= a.len() as u64);
+    assert!(positions[0] + 4096 > DIFF_PROGRESS_INTERVAL as u64);
+
+    let expected = compute_diff(&a, &b_blocks, 4096);
+    assert_eq!(patch_commands.base.len(), expected.base.len());
+    assert_eq!(patch_commands.other.len(), expected.other.len());
+    let silent = compute_diff_with_progress(&a, &b_blocks, 4096, None);
+    assert_eq!(
+        silent.need_bytes_from_other(),
+        expected.need_bytes_from_other()
+    );
+}
+
+#[test]
+fn test_chunk_store() {
+    let shared: Vec<u8> = (0..40960u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut a = shared.clone();
+    a.extend_from_slice(&[1; 4096]);
+    let mut b = shared.clone();
+    b.extend_from_slice(&[2; 4096]);
+    b.extend_from_slice(&shared[0..4096]);
+
+    let mut store = ChunkStore::new();
+    assert!(store.is_empty());
+    let a_manifest = store.ingest(&a, 4096);
+    let b_manifest = store.ingest(&b, 4096);
+    assert_eq!(a_manifest.len(), 11);
+    assert_eq!(b_manifest.len(), 12);
+
+    let unique: std::collections::HashSet<Hash128> = a_manifest
+        .iter()
+        .chain(b_manifest.iter())
+        .cloned()
+        .collect();
+    // 10 shared blocks plus one block of each tail; b's last block repeats its first.
+    assert_eq!(store.len(), unique.len());
+    assert_eq!(store.len(), 12);
+    assert_eq!(store.stored_bytes(), 40960 + 4096 + 4096);
+    assert_eq!(store.reconstruct(&a_manifest).unwrap(), a);
+    assert_eq!(store.reconstruct(&b_manifest).unwrap(), b);
+
+    let missing = compute_hash_strong(b"missing");
+    assert!(!store.contains(&missing));
+    assert_eq!(
+        store.reconstruct(&[a_manifest[0], missing]).err(),
+        Some(PatchError::MissingChunk { hash: missing })
+    );
+}
+
+#[test]
+fn test_signature_cache() {
+    let dir = std::env::temp_dir().join(format!("patchy_test_sig_cache_{}", std::process::id()));
+    let cache_dir = dir.join("cache");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("base.bin");
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 253) as u8).collect();
+    std::fs::write(&path, &data).unwrap();
+
+    let mut cache = SignatureCache::new(&cache_dir);
+    let first = cache.signature(&path, 1024).unwrap();
+    assert_blocks_eq(&first, &compute_blocks(&data, 1024));
+    assert_eq!((cache.hits(), cache.misses()), (0, 1));
+    let second = cache.signature(&path, 1024).unwrap();
+    assert_blocks_eq(&second, &first);
+    assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+    // A new cache over the same directory reuses the stored signature.
+    let mut reopened = SignatureCache::new(&cache_dir);
+    assert_blocks_eq(&reopened.signature(&path, 1024).unwrap(), &first);
+    assert_eq!((reopened.hits(), reopened.misses()), (1, 0));
+
+    assert_blocks_eq(
+        &cache.signature(&path, 512).unwrap(),
+        &compute_blocks(&data, 512),
+    );
+    assert_eq!(cache.misses(), 2);
+
+    let changed = &data[0..9000];
+    std::fs::write(&path, changed).unwrap();
+    assert_blocks_eq(
+        &cache.signature(&path, 1024).unwrap(),
+        &compute_blocks(changed, 1024),
+    );
+    assert_eq!(cache.misses(), 3);
+
+    for entry in std::fs::read_dir(&cache_dir).unwrap() {
+        std::fs::write(entry.unwrap().path(), b"garbage").unwrap();
+    }
+    assert_blocks_eq(
+        &cache.signature(&path, 1024).unwrap(),
+        &compute_blocks(changed, 1024),
+    );
+    assert_eq!(cache.misses(), 4);
+    assert!(cache.signature(&path, 0).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_tree_patch() {
+    let big: Vec<u8> = (0..16 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut big_changed = big.clone();
+    big_changed[5000] ^= 0xFF;
+    let mut base = TreeListing::new();
+    base.insert("keep.txt".to_string(), b"same".to_vec());
+    base.insert("old/name.bin".to_string(), b"moved content".to_vec());
+    base.insert("big.bin".to_string(), big.clone());
+    base.insert("gone.txt".to_string(), b"bye".to_vec());
+    let mut other = TreeListing::new();
+    other.insert("keep.txt".to_string(), b"same".to_vec());
+    other.insert("new/name.bin".to_string(), b"moved content".to_vec());
+    other.insert("big.bin".to_string(), big_changed);
+    other.insert("added.txt".to_string(), b"hello".to_vec());
+
+    let tree_patch = diff_tree_listings(&base, &other);
+    let kinds: Vec<(&str, &TreeEntryKind)> = tree_patch
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), &entry.kind))
+        .collect();
+    let renamed = TreeEntryKind::Renamed {
+        base_path: "old/name.bin".to_string(),
+    };
+    assert_eq!(
+        kinds,
+        vec![
+            ("added.txt", &TreeEntryKind::Added),
+            ("big.bin", &TreeEntryKind::Modified),
+            ("gone.txt", &TreeEntryKind::Removed),
+            ("keep.txt", &TreeEntryKind::Unchanged),
+            ("new/name.bin", &renamed),
+            ("old/name.bin", &TreeEntryKind::Removed),
+        ]
+    );
+    let big_patch = tree_patch.entries[1].patch.as_ref().unwrap();
+    assert!(big_patch.data.len() < big.len() / 4);
+
+    let decoded = TreePatch::decode(&tree_patch.encode()).unwrap();
+    assert_eq!(apply_tree_patch_listing(&base, &decoded).unwrap(), other);
+
+    base.remove("old/name.bin");
+    assert_eq!(
+        apply_tree_patch_listing(&base, &tree_patch).err(),
+        Some(PatchError::MissingFile {
+            path: "old/name.bin".to_string()
+        })
+    );
+    base.insert("big.bin".to_string(), b"wrong base".to_vec());
+    base.insert("old/name.bin".to_string(), b"moved content".to_vec());
+    assert!(matches!(
+        apply_tree_patch_listing(&base, &tree_patch),
+        Err(PatchError::WrongBase { .. })
+    ));
+}
+
+#[test]
+fn test_tree_patch_dirs() {
+    let dir = std::env::temp_dir().join(format!("patchy_test_tree_{}", std::process::id()));
+    let base_dir = dir.join("base");
+    let other_dir = dir.join("other");
+    let out_dir = dir.join("out");
+    std::fs::create_dir_all(base_dir.join("sub")).unwrap();
+    std::fs::create_dir_all(other_dir.join("moved")).unwrap();
+    std::fs::write(base_dir.join("a.txt"), b"0123456789abcdef").unwrap();
+    std::fs::write(base_dir.join("sub/b.txt"), b"bbbb").unwrap();
+    std::fs::write(other_dir.join("a.txt"), b"0123456789ABCDEF").unwrap();
+    std::fs::write(other_dir.join("moved/b.txt"), b"bbbb").unwrap();
+
+    let tree_patch = diff_trees(&base_dir, &other_dir).unwrap();
+    apply_tree_patch(&base_dir, &tree_patch, &out_dir).unwrap();
+    assert_eq!(read_tree(&out_dir).unwrap(), read_tree(&other_dir).unwrap());
+    assert!(!out_dir.join("sub").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_tree_patch_rejects_unsafe_paths() {
+    let dir = std::env::temp_dir().join(format!("patchy_test_tree_paths_{}", std::process::id()));
+    let base_dir = dir.join("base");
+    let out_dir = dir.join("out");
+    std::fs::create_dir_all(&base_dir).unwrap();
+    for path in &["../x", "/x", "a/../../x", ""] {
+        let tree_patch = TreePatch {
+            entries: vec![TreeEntry {
+                path: path.to_string(),
+                kind: TreeEntryKind::Added,
+                patch: Some(build_patch(b"x", &PatchCommands::new())),
+            }],
+        };
+        assert_eq!(
+            apply_tree_patch_listing(&TreeListing::new(), &tree_patch).err(),
+            Some(PatchError::UnsafePath {
+                path: path.to_string()
+            })
+        );
+        let decoded = TreePatch::decode(&tree_patch.encode()).unwrap();
+        let err = apply_tree_patch(&base_dir, &decoded, &out_dir).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+    assert!(!dir.join("x").exists());
+    assert!(!out_dir.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_recommended_block_size() {
+    assert_eq!(recommended_block_size(0), MIN_RECOMMENDED_BLOCK_SIZE);
+    assert_eq!(recommended_block_size(1000), MIN_RECOMMENDED_BLOCK_SIZE);
+    assert_eq!(recommended_block_size(4 * 1024 * 1024), DEFAULT_BLOCK_SIZE);
+    assert_eq!(
+        recommended_block_size(usize::MAX),
+        MAX_RECOMMENDED_BLOCK_SIZE
+    );
+    let mut previous = 0;
+    for shift in 0..usize::BITS {
+        for len in &[1usize << shift, (1usize << shift) + (1usize << shift) / 2] {
+            let block_size = recommended_block_size(*len);
+            assert!(block_size >= previous);
+            assert!(block_size >= MIN_RECOMMENDED_BLOCK_SIZE);
+            assert!(block_size <= MAX_RECOMMENDED_BLOCK_SIZE);
+            assert_eq!(block_size % 64, 0);
+            previous = block_size;
+        }
+    }
+
+    let a: Vec<u8> = (0..1024 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b[1000] ^= 1;
+    let differ = Differ::new().adaptive_block_size(b.len());
+    assert_eq!(differ.get_block_size(), Some(1024));
+    assert_eq!(Differ::new().signature(&b).unwrap().block_size, 1024);
+    let patch_commands = differ.diff(&a, &differ.signature(&b).unwrap()).unwrap();
+    let patch = differ.build(&b, &patch_commands).unwrap();
+    assert_eq!(patch.block_size, 1024);
+    assert_eq!(apply_patch(&a, &patch), b);
+}
+
+#[test]
+fn test_coverage_report() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 4), 4);
+    let span = |offset, size, kind| CoverageSpan { offset, size, kind };
+    assert_eq!(
+        patch_commands.coverage_report(b.len()),
+        vec![
+            span(0, 4, CoverageKind::FromBase),
+            span(4, 4, CoverageKind::FromOther),
+            span(8, 4, CoverageKind::FromBase),
+        ]
+    );
+    let mut partial = PatchCommands::with_block_size(4);
+    partial.base.push(CopyCmd {
+        source: 0,
+        target: 4,
+        size: 4,
+        source_id: 0,
+    });
+    assert_eq!(
+        partial.coverage_report(12),
+        vec![
+            span(0, 4, CoverageKind::Uncovered),
+            span(4, 4, CoverageKind::FromBase),
+            span(8, 4, CoverageKind::Uncovered),
+        ]
+    );
+    let identical = compute_diff(&a, &compute_blocks(&a, 4), 4);
+    assert_eq!(
+        identical.coverage_report(a.len()),
+        vec![span(0, 16, CoverageKind::FromBase)]
+    );
+    assert!(identical.coverage_report(0).is_empty());
+}
+
+#[test]
+fn test_compute_diff_budgeted() {
+    let a: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b[1000] ^= 1;
+    let b_blocks = compute_blocks(&b, 1024);
+    let patch_commands = compute_diff_budgeted(&a, &b_blocks, 1024, 1024).unwrap();
+    assert_eq!(patch_commands.need_bytes_from_other(), 1024);
+    assert!(compute_diff_budgeted(&a, &b_blocks, 1024, 1023).is_none());
+
+    // Deleted base content doesn't count against the budget.
+    let c = b[0..10 * 1024].to_vec();
+    let c_blocks = compute_blocks(&c, 1024);
+    let patch_commands = compute_diff_budgeted(&a, &c_blocks, 1024, 1024).unwrap();
+    assert_eq!(apply_patch(&a, &build_patch(&c, &patch_commands)), c);
+
+    // Unrelated data gives up early.
+    let d: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+    let d_blocks = compute_blocks(&d, 1024);
+    assert!(compute_diff_budgeted(&a, &d_blocks, 1024, 4096).is_none());
+}
+
+#[test]
+fn test_compute_blocks_seq() {
+    let data: Vec<u8> = (0..1024 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    for &len in &[0, 1000, 300 * 1024, data.len()] {
+        assert_blocks_eq(
+            &compute_blocks_seq(&data[0..len], 2048),
+            &compute_blocks(&data[0..len], 2048),
+        );
+    }
+}
+
+#[test]
+fn test_patch_compose() {
+    let v1: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut v2 = v1.clone();
+    v2.splice(5000..5000, b"first insert".iter().cloned());
+    v2.drain(20000..30000);
+    v2[40000] ^= 0xFF;
+    let mut v3 = v2.clone();
+    v3.splice(100..100, b"second insert".iter().cloned());
+    v3.extend_from_slice(&v2[0..3000]);
+    v3[5005] ^= 0xFF;
+
+    let block_size = 256;
+    let mut first = build_patch_compressed(
+        &v2,
+        &compute_diff(&v1, &compute_blocks(&v2, block_size), block_size),
+        Compression::Zstd { level: 3 },
+    )
+    .unwrap();
+    let second = build_patch(
+        &v3,
+        &compute_diff(&v2, &compute_blocks(&v3, block_size), block_size),
+    );
+    assert_eq!(apply_patch(&apply_patch(&v1, &first), &second), v3);
+    let composed = Patch::compose(&first, &second).unwrap();
+    assert!(composed.validate_coverage().is_ok());
+    assert_eq!(apply_patch_verified(&v1, &composed).unwrap(), v3);
+
+    // With the size of v2 unknown, copies are still checked against its actual end.
+    first.other_size = None;
+    let composed = Patch::compose(&first, &second).unwrap();
+    assert_eq!(apply_patch_verified(&v1, &composed).unwrap(), v3);
+    let mut past_end = Patch::decode(&second.encode()).unwrap();
+    past_end.base.push(CopyCmd {
+        source: v2.len() as u64,
+        target: 0,
+        size: 1,
+        source_id: 0,
+    });
+    assert!(matches!(
+        Patch::compose(&first, &past_end),
+        Err(PatchError::SourceOutOfRange { .. })
+    ));
+
+    first.other_size = Some(v2.len() as u64 - 1);
+    assert!(Patch::compose(&first, &second).is_err());
+}
+
+#[test]
+fn test_diff_signatures() {
+    let base = b"0000111122223333".to_vec();
+    let other = b"000011114444222255550000".to_vec();
+    let base_blocks = compute_blocks(&base, 4);
+    let other_blocks = compute_blocks(&other, 4);
+    let hash = |s: &[u8]| compute_hash_strong(s);
+    let diff = diff_signatures(&base_blocks, &other_blocks);
+    assert_eq!(diff.added, vec![hash(b"4444"), hash(b"5555")]);
+    assert_eq!(diff.removed, vec![hash(b"3333")]);
+    assert_eq!(
+        diff.common,
+        vec![hash(b"0000"), hash(b"1111"), hash(b"2222")]
+    );
+    assert_eq!(diff.divergent_positions, vec![2, 3, 4, 5]);
+    assert_eq!(diff.other_blocks_changed, 2);
+    assert!((diff.changed_fraction() - 2.0 / 6.0).abs() < 1e-9);
+
+    let same = diff_signatures(&base_blocks, &base_blocks);
+    assert!(same.added.is_empty() && same.removed.is_empty());
+    assert!(same.divergent_positions.is_empty());
+    assert_eq!(same.changed_fraction(), 0.0);
+    assert_eq!(diff_signatures::<Hash128>(&[], &[]).changed_fraction(), 0.0);
+}
+
+#[test]
+fn test_compute_diff_deterministic() {
+    // Repeated blocks give several candidate base offsets for the same strong hash.
+    let mut a: Vec<u8> = Vec::new();
+    for i in 0..64u32 {
+        a.extend_from_slice(if i % 3 == 0 { b"repeated" } else { b"distinct" });
+        a.extend_from_slice(&i.to_le_bytes());
+        a.extend_from_slice(b"padd");
+    }
+    let mut b = a.clone();
+    b.reverse();
+    b.extend_from_slice(&a[0..200]);
+    let commands = || {
+        let patch_commands = compute_diff(&a, &compute_blocks(&b, 8), 8);
+        patch_commands
+            .base
+            .iter()
+            .chain(patch_commands.other.iter())
+            .map(|cmd| (cmd.source, cmd.target, cmd.size, cmd.source_id))
+            .collect::<Vec<_>>()
+    };
+    let first = commands();
+    for _ in 0..4 {
+        assert_eq!(commands(), first);
+    }
+}
+
+// Sorted vectors instead of hash sets, with a weak filter that accepts everything.
+struct SortedBlockIndex {
+    strong: Vec<(Hash128, u64)>,
+}
+
+impl BlockIndex for SortedBlockIndex {
+    fn contains_weak(&self, _hash_weak: u32) -> bool {
+        true
+    }
+    fn lookup_strong(&self, hash_strong: &Hash128) -> Option<u64> {
+        self.strong
+            .binary_search_by(|(hash, _)| hash.cmp(hash_strong))
+            .ok()
+            .map(|i| self.strong[i].1)
+    }
+}
+
+#[test]
+fn test_compute_diff_with_index() {
+    let a: Vec<u8> = (0..8 * 1024u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b.splice(100..100, a[5000..6000].iter().cloned());
+    b[7000] ^= 0xFF;
+    let b_blocks = compute_blocks(&b, 256);
+    let mut strong: Vec<(Hash128, u64)> = b_blocks
+        .iter()
+        .map(|block| (block.hash_strong, block.offset))
+        .collect();
+    strong.sort_by_key(|x| x.0);
+    let index = SortedBlockIndex { strong };
+
+    let expected = compute_diff(&a, &b_blocks, 256);
+    let actual = compute_diff_with_index(&a, &b_blocks, &index, 256);
+    let cmds = |cmds: &[CopyCmd]| {
+        cmds.iter()
+            .map(|cmd| (cmd.source, cmd.target, cmd.size))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(cmds(&actual.base), cmds(&expected.base));
+    assert_eq!(cmds(&actual.other), cmds(&expected.other));
+    assert_eq!(apply_patch(&a, &build_patch(&b, &actual)), b);
+
+    let other_set = OtherBlockSet::new(&b_blocks);
+    assert_eq!(
+        other_set.lookup_strong(&b_blocks[3].hash_strong),
+        Some(b_blocks[3].offset)
+    );
+    assert!(other_set.contains_weak(b_blocks[3].hash_weak));
+    assert_eq!(
+        other_set.lookup_strong(&compute_hash_strong(b"missing")),
+        None
+    );
+    assert!(other_set.contains_block(&b_blocks[3].hash_strong, 256));
+    assert!(!other_set.contains_block(&b_blocks[3].hash_strong, 255));
+
+    // A short tail block only matches windows of its own length, even with the strong hash of a
+    // full block.
+    let mut forged = compute_blocks(&b, 256);
+    let tail = forged.len() - 1;
+    forged[tail].hash_strong = forged[3].hash_strong;
+    forged.remove(3);
+    let tail_size = forged[tail - 1].size;
+    assert_ne!(tail_size, 256);
+    let other_set = OtherBlockSet::new(&forged);
+    assert!(other_set.contains_block(&b_blocks[3].hash_strong, tail_size));
+    assert!(!other_set.contains_block(&b_blocks[3].hash_strong, 256));
+}
+
+#[cfg(feature = "bloom")]
+#[test]
+fn test_bloom_block_index() {
+    let a: Vec<u8> = (0..8 * 1024u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b.splice(100..100, a[5000..6000].iter().cloned());
+    b[7000] ^= 0xFF;
+    let b_blocks = compute_blocks(&b, 256);
+    let index = BloomBlockIndex::new(&b_blocks, 0.01);
+    let expected = compute_diff(&a, &b_blocks, 256);
+    let actual = compute_diff_with_index(&a, &b_blocks, &index, 256);
+    assert_eq!(actual.base.len(), expected.base.len());
+    assert_eq!(actual.other.len(), expected.other.len());
+    assert_eq!(apply_patch(&a, &build_patch(&b, &actual)), b);
+
+    // 1M block signature: the filter takes about 1.2 MB where the weak hash set takes over 4 MB.
+    let block_count = 1 << 20;
+    let blocks: Vec<Block> = (0..block_count as u32)
+        .map(|i| {
+            let mut strong = [0u8; 16];
+            strong[0..4].copy_from_slice(&i.to_le_bytes());
+            Block {
+                offset: i as u64 * 2048,
+                size: 2048,
+                hash_weak: i.wrapping_mul(2654435761),
+                hash_strong: Hash128::from_bytes(&strong),
+            }
+        })
+        .collect();
+    let index = BloomBlockIndex::new(&blocks, 0.01);
+    let weak_set: std::collections::HashSet<u32> =
+        blocks.iter().map(|block| block.hash_weak).collect();
+    let weak_set_bytes = weak_set.capacity() * std::mem::size_of::<u32>();
+    assert!(index.filter_bytes() < 1300 * 1024);
+    assert!(index.filter_bytes() * 3 < weak_set_bytes);
+    assert!(blocks
+        .iter()
+        .all(|block| index.contains_weak(block.hash_weak)));
+    let false_positives = (0..100_000u32)
+        .map(|i| (block_count as u32 + i).wrapping_mul(2654435761))
+        .filter(|hash_weak| index.contains_weak(*hash_weak))
+        .count();
+    assert!(
+        false_positives < 2_000,
+        "{} false positives",
+        false_positives
+    );
+}
+
+#[test]
+fn test_custom_strong_hasher() {
+    struct KeyedHasher([u8; 32]);
+    impl StrongHasher for KeyedHasher {
+        fn hash(&self, input: &[u8]) -> Hash128 {
+            Hash128::new_from_blake3(&blake3::keyed_hash(&self.0, input))
+        }
+    }
+    let hasher = KeyedHasher([7; 32]);
+    let a: Vec<u8> = (0..8 * 1024u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b.splice(100..100, a[5000..6000].iter().cloned());
+    b[7000] ^= 0xFF;
+    b.truncate(b.len() - 10);
+
+    let b_blocks = compute_blocks_with_hasher(&b, 256, &hasher);
+    assert_eq!(b_blocks[1].hash_strong, hasher.hash(&b[256..512]));
+    assert_ne!(b_blocks[1].hash_strong, compute_hash_strong(&b[256..512]));
+    let patch_commands = compute_diff_with_hasher(&a, &b_blocks, 256, &hasher);
+    let expected = compute_diff(&a, &compute_blocks(&b, 256), 256);
+    let cmds = |cmds: &[CopyCmd]| {
+        cmds.iter()
+            .map(|cmd| (cmd.source, cmd.target, cmd.size))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(cmds(&patch_commands.base), cmds(&expected.base));
+    assert_eq!(cmds(&patch_commands.other), cmds(&expected.other));
+    assert_eq!(apply_patch(&a, &build_patch(&b, &patch_commands)), b);
+
+    // The default hasher doesn't confirm any block of a signature made with another one.
+    let mismatched = compute_diff(&a, &b_blocks, 256);
+    assert!(mismatched.base.is_empty());
+    assert_eq!(
+        compute_blocks_with_hasher::<Hash128>(&b, 256, &Blake3Hasher)[1].hash_strong,
+        compute_blocks(&b, 256)[1].hash_strong
+    );
+}
+
+#[test]
+fn test_compute_diff_reuse() {
+    let cmds = |cmds: &PatchCommands| {
+        let list = |cmds: &[CopyCmd]| {
+            cmds.iter()
+                .map(|cmd| (cmd.source, cmd.target, cmd.size))
+                .collect::<Vec<_>>()
+        };
+        (list(&cmds.base), list(&cmds.other), cmds.block_size)
+    };
+    let mut ctx = DiffContext::new();
+    for seed in 1..6u32 {
+        let len = 1024 * seed as usize + 37 * seed as usize;
+        let a: Vec<u8> = (0..len as u32)
+            .map(|i| (i.wrapping_mul(2654435761).wrapping_add(seed) >> 13) as u8)
+            .collect();
+        let mut b = a.clone();
+        b.splice(50..50, a[len / 2..len / 2 + 400].iter().cloned());
+        b[len / 3] ^= 0xFF;
+        let block_size = 32 * seed as usize;
+        let b_blocks = compute_blocks(&b, block_size);
+        let reused = compute_diff_reuse(&mut ctx, &a, &b_blocks, block_size);
+        assert_eq!(
+            cmds(&reused),
+            cmds(&compute_diff(&a, &b_blocks, block_size))
+        );
+        assert!(!reused.base.is_empty());
+        // The swapped direction and an empty input reuse the same buffers.
+        let a_blocks = compute_blocks(&a, block_size);
+        assert_eq!(
+            cmds(&compute_diff_reuse(&mut ctx, &b, &a_blocks, block_size)),
+            cmds(&compute_diff(&b, &a_blocks, block_size))
+        );
+        assert_eq!(
+            cmds(&compute_diff_reuse(&mut ctx, &[], &a_blocks, block_size)),
+            cmds(&compute_diff(&[], &a_blocks, block_size))
+        );
+    }
+}
+
+#[test]
+fn test_compute_diff_with_min_run() {
+    let noise = |seed: u32, len: u32| -> Vec<u8> {
+        (0..len)
+            .map(|i| ((i + seed).wrapping_mul(2654435761) >> 13) as u8)
+            .collect()
+    };
+    let b = noise(0, 64 * 64);
+    let mut a = noise(1 << 20, 64 * 64);
+    // Three isolated single block matches and one run of eight blocks.
+    for (block, offset) in &[(3, 100), (20, 900), (41, 2000)] {
+        a[*offset..*offset + 64].copy_from_slice(&b[block * 64..block * 64 + 64]);
+    }
+    a[3000..3000 + 8 * 64].copy_from_slice(&b[50 * 64..58 * 64]);
+    let b_blocks = compute_blocks(&b, 64);
+
+    let all = build_patch(&b, &compute_diff(&a, &b_blocks, 64));
+    let filtered_commands = compute_diff_with_min_run(&a, &b_blocks, 64, 4);
+    assert_eq!(filtered_commands.need_bytes_from_base(), 8 * 64);
+    let filtered = build_patch(&b, &filtered_commands);
+    assert_eq!(all.base.len(), 4);
+    assert_eq!(all.other.len(), 5);
+    assert_eq!(filtered.base.len(), 1);
+    assert_eq!(filtered.other.len(), 2);
+    assert_eq!(apply_patch(&a, &filtered), b);
+
+    let unfiltered = compute_diff_with_min_run(&a, &b_blocks, 64, 1);
+    assert_eq!(build_patch(&b, &unfiltered).base.len(), all.base.len());
+}
+
+#[test]
+fn test_compute_diff_caches_repeated_rejections() {
+    // Collides with four zero bytes under RollingHash but has a different strong hash.
+    let b = vec![1, 0xFE, 1, 0];
+    assert_eq!(compute_hash_weak(&b), compute_hash_weak(&[0, 0, 0, 0]));
+    let mut a = vec![0u8; 64];
+    a.extend_from_slice(&b);
+    let (patch_commands, metrics) = compute_diff_with_metrics(&a, &compute_blocks(&b, 4), 4);
+    assert_eq!(patch_commands.need_bytes_from_other(), 0);
+    assert_eq!(patch_commands.base[0].source, 64);
+    assert_eq!(metrics.strong_confirmations, 1);
+    // Only the first all-zero window is hashed, the following 60 repeat its content. The other
+    // false positive is the window [0, 1, 0xFE, 1] right before the match.
+    assert_eq!(metrics.strong_false_positives, 2);
+    assert_eq!(metrics.cached_rejections, 60);
+    assert_eq!(metrics.window_slides, 64);
+}
+
+#[test]
+fn test_compute_diff_with_metrics() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let (patch_commands, metrics) = compute_diff_with_metrics(&a, &compute_blocks(&b, 4), 4);
+    assert_eq!(patch_commands.need_bytes_from_other(), 4);
+    assert_eq!(metrics.strong_confirmations, 2);
+    assert_eq!(metrics.weak_hits, 2 + metrics.strong_false_positives);
+    assert_eq!(metrics.bytes_matched, 8);
+    assert_eq!(metrics.bytes_literal, 4);
+    // Two windows matched and consumed 8 bytes, every other byte slid out of a window.
+    assert_eq!(metrics.window_slides, 8);
+
+    let (_, metrics) = compute_diff_with_metrics(&a, &compute_blocks(&a, 4), 4);
+    assert_eq!(metrics.bytes_matched, 16);
+    assert_eq!(metrics.bytes_literal, 0);
+    assert_eq!(metrics.window_slides, 0);
+}
+
+#[test]
+fn test_logical_copy() {
+    let huge = LogicalCopy {
+        source: 100,
+        target: 7,
+        size: 5 * 1024 * 1024 * 1024,
+    };
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.push_base_copy(huge);
+    assert_eq!(patch_commands.base.len(), 2);
+    assert_eq!(patch_commands.base[0].size, u32::MAX);
+    assert_eq!(patch_commands.base[1].source, 100 + u32::MAX as u64);
+    assert_eq!(patch_commands.need_bytes_from_base() as u64, huge.size);
+    assert_eq!(
+        LogicalCopy::from_copy_cmds(&patch_commands.base),
+        vec![huge]
+    );
+
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"89abcdefxyz".to_vec();
+    let mut patch_commands = PatchCommands::new();
+    patch_commands.push_base_copy(LogicalCopy {
+        source: 8,
+        target: 0,
+        size: 8,
+    });
+    patch_commands.push_literal(8, 3);
+    patch_commands.push_literal(11, 0);
+    assert!(patch_commands.other.iter().all(|cmd| cmd.size != 0));
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+    assert_eq!(
+        LogicalCopy::from_copy_cmds(&patch.base),
+        vec![LogicalCopy {
+            source: 8,
+            target: 0,
+            size: 8
+        }]
+    );
+}
+
+#[test]
+fn test_apply_patch_strict() {
+    let a: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut b = a.clone();
+    b[500] ^= 1;
+    let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 64), 64));
+    assert_eq!(apply_patch_strict(&a, &patch).unwrap(), b);
+
+    let gappy = Patch {
+        data: b"xyz".to_vec(),
+        base: vec![CopyCmd {
+            source: 0,
+            target: 0,
+            size: 70,
+            source_id: 0,
+        }],
+        other: vec![CopyCmd {
+            source: 0,
+            target: 71,
+            size: 3,
+            source_id: 0,
+        }],
+        other_size: Some(74),
+        compression: Compression::None,
+        other_hash: None,
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    };
+    assert!(apply_patch_checked(&a, &gappy).is_ok());
+    assert_eq!(
+        apply_patch_strict(&a, &gappy).err(),
+        Some(PatchError::CoverageGap { offset: 70 })
+    );
+    let mut gappy = gappy;
+    gappy.base[0].size = 71;
+    assert_eq!(apply_patch_strict(&a, &gappy).unwrap().len(), 74);
+    // Output past the last command is rejected before it is allocated.
+    gappy.other_size = Some(130);
+    assert_eq!(
+        apply_patch_checked(&a, &gappy).err(),
+        Some(PatchError::CoverageGap { offset: 74 })
+    );
+    gappy.other_size = Some(u64::MAX);
+    assert_eq!(
+        apply_patch_strict(&a, &gappy).err(),
+        Some(PatchError::CoverageGap { offset: 74 })
+    );
+}
+
+#[test]
+fn test_build_patch_from_reader() {
+    let a: Vec<u8> = (0..10000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b.splice(3000..3000, b"inserted".iter().cloned());
+    b[8000] ^= 1;
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 256), 256);
+    let patch = build_patch_from_reader(&mut std::io::Cursor::new(&b), &patch_commands).unwrap();
+    let expected = build_patch(&b, &patch_commands);
+    assert_eq!(
+        bincode::serialize(&patch).unwrap(),
+        bincode::serialize(&expected).unwrap()
+    );
+    assert_eq!(apply_patch_verified(&a, &patch).unwrap(), b);
+
+    let truncated = &b[0..5000];
+    assert!(
+        build_patch_from_reader(&mut std::io::Cursor::new(truncated), &patch_commands).is_err()
+    );
+}
+
+#[test]
+fn test_rolling_hash_known_answers() {
+    assert_eq!(ROLLING_HASH_CHAR_OFFSET, 31);
+    assert_eq!(compute_hash_weak(b""), 0);
+    assert_eq!(compute_hash_weak(b"abc"), 0x0304_0183);
+    assert_eq!(
+        compute_hash_weak(b"The quick brown fox jumps over the lazy dog"),
+        0xce30_150e
+    );
+    // The offset wraps at 8 bits: 0xF0 + 31 = 0x0F.
+    assert_eq!(compute_hash_weak(&[0xF0, 0xFF, 0xE1]), 0x0069_002d);
+}
+
+#[test]
+fn test_compute_rolling_hashes() {
+    let input: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+    for &(window, step) in &[(16, 1), (16, 5), (64, 63), (16, 16), (10, 37), (1000, 1)] {
+        let hashes = compute_rolling_hashes(&input, window, step);
+        let expected: Vec<(u64, u32)> = (0..=input.len() - window)
+            .step_by(step)
+            .map(|offset| {
+                let hash_weak = compute_hash_weak(&input[offset..offset + window]);
+                (offset as u64, hash_weak)
+            })
+            .collect();
+        assert_eq!(hashes, expected);
+    }
+    assert!(compute_rolling_hashes(&input, 1001, 1).is_empty());
+    assert!(compute_rolling_hashes(b"", 4, 1).is_empty());
+}
+
+#[test]
+fn test_rolling_hash_state() {
+    let mut hash = RollingHash::new();
+    assert_eq!(hash.state(), (0, 0, 0));
+    // c = 'a' + 31, 'b' + 31, 'c' + 31 = 128, 129, 130; b sums a after each byte.
+    hash.update(b"abc");
+    assert_eq!(hash.state(), (387, 128 + 257 + 387, 3));
+    assert_eq!(hash.get(), 0x0304_0183);
+    hash.sub(b'a');
+    assert_eq!(hash.state(), (259, 129 + 259, 2));
+    assert_eq!(hash.get(), compute_hash_weak(b"bc"));
+}
+
+#[test]
+fn test_flat_patch_round_trip() {
+    let a: Vec<u8> = (0..8 * 1024u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b.splice(100..100, b"inserted".iter().cloned());
+    b[5000] ^= 0xFF;
+    let patch = build_patch_compressed(
+        &b,
+        &compute_diff(&a, &compute_blocks(&b, 256), 256),
+        Compression::Zstd { level: 3 },
+    )
+    .unwrap();
+    let flat = patch.to_flat().unwrap();
+    assert_eq!(flat.other_size, Some(b.len() as u64));
+    let mut output: Vec<u8> = Vec::new();
+    for op in &flat.ops {
+        match op {
+            FlatOp::Copy { source, size, .. } => {
+                output.extend_from_slice(&a[*source as usize..*source as usize + *size as usize])
+            }
+            FlatOp::Literal(bytes) => output.extend_from_slice(bytes),
+        }
+    }
+    assert_eq!(output, b);
+
+    let round_trip = flat.to_patch();
+    assert_eq!(apply_patch(&a, &round_trip), b);
+    assert_eq!(round_trip.to_flat().unwrap(), flat);
+
+    let gappy = Patch {
+        other_size: Some(patch.output_size() + 1),
+        ..patch
+    };
+    assert!(matches!(
+        gappy.to_flat(),
+        Err(PatchError::CoverageGap { .. })
+    ));
+}
+
+#[test]
+fn test_first_divergence() {
+    let a = b"0123456789abcdef".to_vec();
+    let b = b"4567xxxx0123".to_vec();
+    let mut patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 4), 4));
+    assert!(first_divergence(&a, &patch, &b).unwrap().is_none());
+
+    // A wrong base gives a divergence inside the base copy from offset 8.
+    let wrong_base = b"01X3456789abcdef".to_vec();
+    let (offset, cmd) = first_divergence(&wrong_base, &patch, &b).unwrap().unwrap();
+    assert_eq!(offset, 10);
+    let cmd = cmd.unwrap();
+    assert_eq!((cmd.source, cmd.target, cmd.size), (0, 8, 4));
+
+    let (offset, cmd) = first_divergence(&a, &patch, b"4567xxxx0123!")
+        .unwrap()
+        .unwrap();
+    assert_eq!(offset, 12);
+    assert!(cmd.is_none());
+
+    // Drop the literal command: bytes 4..8 are never written.
+    patch.other.clear();
+    let (offset, cmd) = first_divergence(&a, &patch, &b).unwrap().unwrap();
+    assert_eq!(offset, 4);
+    assert!(cmd.is_none());
+}
+
+#[test]
+fn test_compute_diff_aligned() {
+    let a: Vec<u8> = (0..16 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    b[5000] ^= 1;
+    b[10240..10496].copy_from_slice(&a[2048..2304]);
+    let b_blocks = compute_blocks(&b, 256);
+    let aligned = compute_diff_aligned(&a, &b_blocks, 256);
+    assert_eq!(aligned.need_bytes_from_other(), 256);
+    assert_eq!(apply_patch(&a, &build_patch(&b, &aligned)), b);
+
+    // A shifted copy is not found without rolling.
+    let mut c = vec![b'x'];
+    c.extend_from_slice(&a);
+    let c_blocks = compute_blocks(&c, 256);
+    let aligned = compute_diff_aligned(&a, &c_blocks, 256);
+    assert!(aligned.need_bytes_from_other() > c.len() - 256);
+    assert!(compute_diff(&a, &c_blocks, 256).need_bytes_from_other() < 512);
+    assert_eq!(apply_patch(&a, &build_patch(&c, &aligned)), c);
+
+    assert!(compute_diff_aligned(&a, &compute_blocks(&a, 256), 256).is_synchronized());
+}
+
+#[test]
+fn test_apply_patch_in_place() {
+    let dir = std::env::temp_dir().join(format!("patchy_test_in_place_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file");
+    let apply_in_place = |base: &[u8], patch: &Patch| {
+        std::fs::write(&path, base).unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        apply_patch_in_place(&mut file, patch).unwrap();
+        drop(file);
+        std::fs::read(&path).unwrap()
+    };
+    let cmd = |source: u64, target: u64, size: u32| CopyCmd {
+        source,
+        target,
+        size,
+        source_id: 0,
+    };
+    let a: Vec<u8> = (0..64u8).collect();
+    let mut patch = build_patch(&a, &compute_diff(&a, &compute_blocks(&a, 4), 4));
+    patch.other.clear();
+    patch.base_hash = None;
+
+    // Forward: the output starts with bytes from later in the base.
+    patch.base = vec![cmd(8, 0, 56), cmd(0, 56, 8)];
+    let steps = crate::in_place::plan_in_place_copies(&patch.base);
+    assert!(steps.contains(&crate::in_place::InPlaceStep::Spill(1)));
+    assert_eq!(apply_in_place(&a, &patch), apply_patch(&a, &patch));
+
+    // Backward: each copy overwrites the rest of its own source.
+    patch.base = vec![cmd(0, 0, 8), cmd(0, 8, 56)];
+    let steps = crate::in_place::plan_in_place_copies(&patch.base);
+    assert_eq!(
+        steps,
+        vec![
+            crate::in_place::InPlaceStep::Copy(1),
+            crate::in_place::InPlaceStep::Copy(0)
+        ]
+    );
+    assert_eq!(apply_in_place(&a, &patch), apply_patch(&a, &patch));
+
+    // Swapping blocks needs spills, the rest orders itself.
+    patch.base = (0..16u64).map(|i| cmd((i ^ 1) * 4, i * 4, 4)).collect();
+    assert_eq!(apply_in_place(&a, &patch), apply_patch(&a, &patch));
+
+    // Real diffs that move data both ways across chunk boundaries, grow and shrink the file.
+    let a: Vec<u8> = (0..200 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut grown = b"inserted".repeat(1000);
+    grown.extend_from_slice(&a[100 * 1024..]);
+    grown.extend_from_slice(&a[..100 * 1024]);
+    let mut shrunk = a[70000..].to_vec();
+    shrunk.extend_from_slice(&a[1000..5000]);
+    for b in [grown, shrunk] {
+        let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 256), 256));
+        assert_eq!(apply_in_place(&a, &patch), b);
+    }
+
+    let mut patch = build_patch(&a, &compute_diff(&a, &compute_blocks(&a, 256), 256));
+    patch.base_hash = Some(compute_hash_strong(b"other"));
+    std::fs::write(&path, &a).unwrap();
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let err = apply_patch_in_place(&mut file, &patch).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    drop(file);
+    assert_eq!(std::fs::read(&path).unwrap(), a);
+
+    // A self copy that reads at or after its target fails before any base copy writes.
+    patch.base_hash = None;
+    patch.base = vec![
+        cmd(8, 0, 8),
+        CopyCmd {
+            source: 8,
+            target: 8,
+            size: a.len() as u32 - 8,
+            source_id: SELF_SOURCE_ID,
+        },
+    ];
+    patch.other.clear();
+    patch.other_size = Some(a.len() as u64);
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let err = apply_patch_in_place(&mut file, &patch).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    drop(file);
+    assert_eq!(std::fs::read(&path).unwrap(), a);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_decompressed_data_bounded() {
+    // A small frame that expands far beyond the literals the commands read.
+    let bomb = zstd::stream::encode_all(&vec![0u8; 1 << 24][..], 19).unwrap();
+    let mut patch = Patch {
+        data: bomb.clone(),
+        base: Vec::new(),
+        other: vec![CopyCmd {
+            source: 0,
+            target: 0,
+            size: 3,
+            source_id: 0,
+        }],
+        other_size: Some(3),
+        compression: Compression::Zstd { level: 19 },
+        other_hash: None,
+        base_hash: None,
+        block_size: DEFAULT_BLOCK_SIZE,
+    };
+    assert!(matches!(
+        patch.decompressed_data(),
+        Err(PatchError::Compression(_))
+    ));
+
+    let mut data = vec![1u8];
+    data.extend_from_slice(&bomb);
+    patch.data = data;
+    patch.compression = Compression::PerRun(LiteralEncoding {
+        level: 19,
+        min_compress_len: 0,
+    });
+    assert!(matches!(
+        patch.decompressed_data(),
+        Err(PatchError::Compression(_))
+    ));
+    assert!(apply_patch_checked(&[], &patch).is_err());
+}
+
+#[test]
+fn test_literal_encoding_per_run() {
+    let a: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a.clone();
+    // A few scattered single-byte edits and one large, compressible insertion.
+    for offset in [1000, 9000, 30000] {
+        b[offset] ^= 1;
+    }
+    let inserted = b"some literal text ".repeat(1000);
+    b.splice(40000..40000, inserted.iter().cloned());
+
+    let encoding = LiteralEncoding {
+        level: 3,
+        min_compress_len: 256,
+    };
+    let differ = Differ::new().block_size(256).literal_encoding(encoding);
+    let patch_commands = differ.diff(&a, &differ.signature(&b).unwrap()).unwrap();
+    let patch = differ.build(&b, &patch_commands).unwrap();
+    let raw = build_patch(&b, &patch_commands);
+    assert_eq!(patch.compression, Compression::PerRun(encoding));
+    assert!(patch.other.iter().all(|cmd| cmd.source_id == 0));
+    let mut runs: Vec<&CopyCmd> = patch.other.iter().collect();
+    runs.sort_by_key(|cmd| cmd.source);
+    let (flags, _) = literal_run_flags(&patch.data, runs.len()).unwrap();
+    assert!(flags.contains(&false) && flags.contains(&true));
+    for (cmd, compressed) in runs.iter().zip(&flags) {
+        assert!(!compressed || cmd.size >= encoding.min_compress_len);
+    }
+    assert!(patch.data.len() < raw.data.len() / 10);
+    assert_eq!(patch.decompressed_data().unwrap().as_ref(), &raw.data[..]);
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    let decoded = Patch::decode(&patch.encode()).unwrap();
+    assert_eq!(decoded.compression, patch.compression);
+    assert_eq!(apply_patch(&a, &decoded), b);
+
+    // Compressed runs are ordinary literals to every other consumer.
+    assert!(first_divergence(&a, &patch, &b).unwrap().is_none());
+    let compressed_run = runs[flags.iter().position(|&compressed| compressed).unwrap()];
+    let mut expected = b.clone();
+    expected[compressed_run.target as usize + 5] ^= 1;
+    let (offset, cmd) = first_divergence(&a, &patch, &expected).unwrap().unwrap();
+    assert_eq!(offset, compressed_run.target + 5);
+    assert_eq!(cmd.unwrap().target, compressed_run.target);
+
+    // A run that doesn't shrink is stored raw even above the threshold.
+    let mut noise = vec![0u8; 4096];
+    blake3::Hasher::new().finalize_xof().fill(&mut noise);
+    let patch = build_patch_compressed(
+        &noise,
+        &compute_diff(&a, &compute_blocks(&noise, 256), 256),
+        Compression::PerRun(encoding),
+    )
+    .unwrap();
+    let (flags, stored) = literal_run_flags(&patch.data, patch.other.len()).unwrap();
+    assert!(flags.iter().all(|&compressed| !compressed));
+    assert_eq!(stored, &noise[..]);
+
+    let mut truncated = Patch::decode(&decoded.encode()).unwrap();
+    truncated.data.pop();
+    assert!(matches!(
+        truncated.decompressed_data(),
+        Err(PatchError::Compression(_))
+    ));
+}
+
+#[test]
+fn test_base_read_ranges() {
+    let a: Vec<u8> = (0..16 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a[8192..12288].to_vec();
+    b.extend_from_slice(&a[0..2048]);
+    b.extend_from_slice(&a[1024..3072]);
+    let patch = build_patch(&b, &compute_diff(&a, &compute_blocks(&b, 256), 256));
+    assert_eq!(patch.base_read_ranges(), vec![0..3072, 8192..12288]);
+
+    let cmd = |source: u64, size: u32, source_id: u32| CopyCmd {
+        source,
+        target: 0,
+        size,
+        source_id,
+    };
+    let mut patch = build_patch(b"", &PatchCommands::new());
+    patch.base = vec![
+        cmd(100, 10, 0),
+        cmd(0, 10, 0),
+        cmd(10, 5, 0),
+        cmd(105, 2, 0),
+        cmd(50, 0, 0),
+        cmd(60, 10, 1),
+        cmd(60, 10, SELF_SOURCE_ID),
+    ];
+    assert_eq!(patch.base_read_ranges(), vec![0..15, 100..110]);
+}
+
+#[test]
+fn test_compute_diff_prefers_continuation() {
+    let block_size = 64;
+    let pattern: Vec<u8> = (0..2 * block_size as u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    // ABAB...: every block of `b` matches at 16 offsets of `a`.
+    let a = pattern.repeat(16);
+    let mut b = b"xyz".to_vec();
+    b.extend_from_slice(&a[..a.len() - 3]);
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, block_size), block_size);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(patch.base.len(), 1);
+    assert_eq!(apply_patch(&a, &patch), b);
+    assert_eq!(
+        bincode::serialize(&patch).unwrap(),
+        bincode::serialize(&build_patch(
+            &b,
+            &compute_diff(&a, &compute_blocks(&b, block_size), block_size)
+        ))
+        .unwrap()
+    );
+
+    // Past an edited block, copying carries on from the base right behind the edit.
+    let edit = 10 * block_size + 5;
+    b[edit] ^= 1;
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, block_size), block_size);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(patch.base.len(), 2);
+    let (first, second) = (&patch.base[0], &patch.base[1]);
+    assert_eq!(second.source - first.source, second.target - first.target);
+    assert_eq!(apply_patch(&a, &patch), b);
+}
+
+#[test]
+fn test_weak_hashes() {
+    let a: Vec<u8> = (0..300 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    for input in [&a[..1000], &a[..]] {
+        let blocks = compute_blocks(input, 1024);
+        let hashes: Vec<u32> = blocks.iter().map(|block| block.hash_weak).collect();
+        assert_eq!(weak_hashes(input, 1024), hashes);
+        let blocks = try_compute_blocks_with::<Hash128, RollingHash32>(input, 1024).unwrap();
+        let hashes: Vec<u32> = blocks.iter().map(|block| block.hash_weak).collect();
+        assert_eq!(weak_hashes_with::<RollingHash32>(input, 1024), hashes);
+    }
+    assert!(weak_hashes(b"", 1024).is_empty());
+}
+
+#[test]
+fn test_update_signature_append() {
+    let a: Vec<u8> = (0..10 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let fields = |blocks: &[Block]| {
+        blocks
+            .iter()
+            .map(|block| (block.offset, block.size, block.hash_weak, block.hash_strong))
+            .collect::<Vec<_>>()
+    };
+    let mut blocks = compute_blocks(&a[..1000], 256);
+    for len in [1000, 1024, 1500, 4096, 10 * 1024] {
+        let old_len = blocks.iter().map(|block| block.size as usize).sum();
+        update_signature(&mut blocks, &a[..len], old_len..len, 256);
+        assert_eq!(fields(&blocks), fields(&compute_blocks(&a[..len], 256)));
+    }
+}
+
+#[test]
+fn test_update_signature_edit() {
+    let a: Vec<u8> = (0..10 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let fields = |blocks: &[Block]| {
+        blocks
+            .iter()
+            .map(|block| (block.offset, block.size, block.hash_weak, block.hash_strong))
+            .collect::<Vec<_>>()
+    };
+    let splice = |begin: usize, end: usize, replacement: &[u8]| {
+        let mut b = a[..begin].to_vec();
+        b.extend_from_slice(replacement);
+        b.extend_from_slice(&a[end..]);
+        b
+    };
+    let cases = [
+        (1000, 1010, vec![b'x'; 10]),
+        (1000, 1000, vec![b'x'; 7]),
+        (1000, 1000, vec![b'x'; 512]),
+        (1000, 1300, Vec::new()),
+        (1024, 1536, Vec::new()),
+        (0, 0, vec![b'x'; 256]),
+        (9000, 10 * 1024, vec![b'x'; 3]),
+    ];
+    for (begin, end, replacement) in cases {
+        let b = splice(begin, end, &replacement);
+        let mut blocks = compute_blocks(&a, 256);
+        // Marks a block past the edit, to tell whether it is hashed again.
+        let last = blocks.len() - 1;
+        blocks[last].hash_weak ^= 1;
+        update_signature(&mut blocks, &b, begin..begin + replacement.len(), 256);
+        let expected = compute_blocks(&b, 256);
+        let last = blocks.len() - 1;
+        if (b.len() as i64 - a.len() as i64) % 256 == 0 && end < 10 * 1024 {
+            assert_eq!(blocks[last].hash_weak, expected[last].hash_weak ^ 1);
+            blocks[last].hash_weak ^= 1;
+        }
+        assert_eq!(fields(&blocks), fields(&expected));
+    }
+
+    let mut blocks = compute_blocks(&a[..1000], 256);
+    assert_eq!(
+        try_update_signature(&mut blocks, &a, 500..600, 256),
+        Err(PatchError::LengthMismatch {
+            expected: 1000,
+            actual: 500 + 10 * 1024 - 600,
+        })
+    );
+}
+
+#[test]
+fn test_compute_diff_refined() {
+    let a: Vec<u8> = (0..16 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let literal_bytes = |patch_commands: &PatchCommands| {
+        patch_commands.other.iter().map(|cmd| cmd.size).sum::<u32>()
+    };
+    let mut edited = a.clone();
+    edited[5000] ^= 1;
+    edited[5002] ^= 1;
+    let mut first_block = a.clone();
+    first_block[10] ^= 1;
+    let mut last_block = a.clone();
+    last_block[16 * 1024 - 10] ^= 1;
+    let mut inserted = a[..5000].to_vec();
+    inserted.extend_from_slice(b"xyz");
+    inserted.extend_from_slice(&a[5000..]);
+    // Without a copy before (after) it, a literal only shrinks from its end (start).
+    let cases = [
+        (edited, 3),
+        (first_block, 11),
+        (last_block, 10),
+        (inserted, 3),
+    ];
+    for (b, expected_literal_bytes) in cases {
+        let coarse = compute_diff(&a, &compute_blocks(&b, 256), 256);
+        let refined = compute_diff_refined(&a, &b, 256);
+        assert!(literal_bytes(&coarse) >= 256);
+        assert_eq!(literal_bytes(&refined), expected_literal_bytes);
+        assert_eq!(apply_patch(&a, &build_patch(&b, &refined)), b);
+    }
+
+    // Nothing in common: everything stays a literal.
+    let b = vec![0u8; 1000];
+    let refined = compute_diff_refined(&a, &b, 256);
+    assert_eq!(literal_bytes(&refined), 1000);
+    assert_eq!(apply_patch(&a, &build_patch(&b, &refined)), b);
+}
+
+#[test]
+fn test_patch_serialized_size() {
+    let a: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut b = a[30000..].to_vec();
+    b.extend_from_slice(b"some literal text ");
+    b.extend_from_slice(&a[..30000]);
+    let patch_commands = compute_diff(&a, &compute_blocks(&b, 256), 256);
+    let mut patches = vec![
+        build_patch(&b, &patch_commands),
+        build_patch_with_base(&a, &b, &patch_commands),
+        build_patch_compressed(&b, &patch_commands, Compression::Zstd { level: 3 }).unwrap(),
+        build_patch_compressed(
+            &b,
+            &patch_commands,
+            Compression::PerRun(LiteralEncoding {
+                level: 3,
+                min_compress_len: 8,
+            }),
+        )
+        .unwrap(),
+        build_patch(b"", &PatchCommands::new()),
+    ];
+    let mut far = build_patch(&b, &patch_commands);
+    far.other_hash = None;
+    far.base[0].source = u64::MAX - 1;
+    far.base[0].source_id = u32::MAX;
+    patches.push(far);
+    for patch in &patches {
+        let encoded = patch.encode();
+        assert_eq!(patch.serialized_size(), encoded.len());
+        let mut out = b"prefix".to_vec();
+        patch.encode_into(&mut out);
+        assert_eq!(&out[..6], b"prefix");
+        assert_eq!(&out[6..], &encoded[..]);
+    }
+}
+
+#[test]
+fn test_compute_diff_append_aware() {
+    let a: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let appended = |len: usize| {
+        let mut b = a.clone();
+        b.extend((0..len as u32).map(|i| (i.wrapping_mul(2246822519) >> 11) as u8));
+        b
+    };
+    let mut prepended = b"header".to_vec();
+    prepended.extend_from_slice(&a);
+    let mut edited = a.clone();
+    edited.splice(30000..30010, b"edited in the middle".iter().cloned());
+    let cases = [
+        appended(1024),
+        appended(1),
+        prepended,
+        edited,
+        a[..40000].to_vec(),
+        a[..40000].iter().chain(&a[50000..]).cloned().collect(),
+        a.clone(),
+    ];
+    for b in &cases {
+        let b_blocks = compute_blocks(b, 1024);
+        let patch_commands = compute_diff_append_aware(&a, &b_blocks, 1024);
+        let c = if patch_commands.is_synchronized() {
+            a.clone()
+        } else {
+            apply_patch(&a, &build_patch(b, &patch_commands))
+        };
+        assert_eq!(&c, b);
+        assert!(
+            patch_commands.need_bytes_from_other()
+                <= compute_diff(&a, &b_blocks, 1024).need_bytes_from_other() + 1024
+        );
+    }
+
+    let b = appended(1024);
+    let patch_commands = compute_diff_append_aware(&a, &compute_blocks(&b, 1024), 1024);
+    assert_eq!(patch_commands.base.len(), 1);
+    assert_eq!(patch_commands.base[0].size as usize, a.len());
+    assert_eq!(patch_commands.need_bytes_from_other(), 1024);
+
+    let patch_commands = compute_diff_append_aware(b"", &compute_blocks(&a, 1024), 1024);
+    assert_eq!(patch_commands.need_bytes_from_other(), a.len());
+}
+
+// A 1 GiB base with 1 KiB appended. Needs about 1 GiB of memory; run with
+// `cargo test --release -- --ignored`.
+#[test]
+#[ignore]
+fn test_compute_diff_append_aware_large() {
+    let base_len = 1 << 30;
+    let mut b: Vec<u8> = (0..base_len as u64 + 1024)
+        .map(|i| (i.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 56) as u8)
+        .collect();
+    let b_blocks = compute_blocks(&b, DEFAULT_BLOCK_SIZE);
+    let patch_commands = compute_diff_append_aware(&b[..base_len], &b_blocks, DEFAULT_BLOCK_SIZE);
+    assert_eq!(patch_commands.base.len(), 1);
+    assert_eq!(patch_commands.base[0].size as usize, base_len);
+    assert_eq!(patch_commands.need_bytes_from_other(), 1024);
+    let patch = build_patch(&b, &patch_commands);
+    let output = apply_patch(&b[..base_len], &patch);
+    assert!(output == b);
+    b.clear();
+}
+
+#[test]
+fn test_compute_diff_bounded() {
+    let a: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let mut edited = a.clone();
+    for offset in (1000..64 * 1024).step_by(3000) {
+        edited[offset] ^= 1;
+    }
+    // Only copies, from all over the base.
+    let shuffled: Vec<u8> = (0..64)
+        .flat_map(|i| {
+            let block = (i * 37) % 64 * 1024;
+            a[block..block + 1024].to_vec()
+        })
+        .collect();
+    for b in [edited, shuffled] {
+        let b_blocks = compute_blocks(&b, 256);
+        let unbounded = compute_diff(&a, &b_blocks, 256);
+        let total = unbounded.base.len() + unbounded.other.len();
+        assert!(total > 20);
+        let mut previous_literal_bytes = unbounded.need_bytes_from_other();
+        for max_commands in [total, 20, 10, 3, 1, 0] {
+            let bounded = compute_diff_bounded(&a, &b_blocks, 256, max_commands);
+            assert!(bounded.base.len() + bounded.other.len() <= max_commands.max(1));
+            assert!(bounded.need_bytes_from_other() >= previous_literal_bytes);
+            previous_literal_bytes = bounded.need_bytes_from_other();
+            assert_eq!(apply_patch(&a, &build_patch(&b, &bounded)), b);
+        }
+        assert_eq!(previous_literal_bytes, b.len());
+    }
+}
+
+#[test]
+fn test_hash_display_from_str() {
+    use std::str::FromStr;
+    let h = compute_hash_strong(b"display");
+    assert_eq!(h.to_string(), h.to_hex_string());
+    assert_eq!(format!("{}", h).len(), 32);
+    assert_eq!(Hash128::from_str(&h.to_string()), Ok(h));
+    assert_eq!(h.to_string().parse::<Hash128>(), Ok(h));
+    let h256 = Hash256::compute(b"display");
+    assert_eq!(Hash256::from_str(&h256.to_string()), Ok(h256));
+
+    assert!(matches!(
+        Hash128::from_str(&h.to_string()[..30]),
+        Err(PatchError::LengthMismatch { .. })
+    ));
+    assert!(matches!(
+        Hash128::from_str(&format!("{}00", h)),
+        Err(PatchError::LengthMismatch { .. })
+    ));
+    assert!(matches!(
+        Hash128::from_str(&"zz".repeat(16)),
+        Err(PatchError::InvalidHex { offset: 0 })
+    ));
+    // Separators that `from_hex` accepts are rejected.
+    assert_eq!(
+        Hash128::from_str(&h.to_hex_delimited(':')),
+        Err(PatchError::InvalidHex { offset: 2 })
+    );
+    assert_eq!(
+        Hash128::from_str(&format!("{} ", &h.to_string()[..31])),
+        Err(PatchError::InvalidHex { offset: 31 })
+    );
+    assert_eq!(Hash128::from_str(&h.to_string().to_uppercase()), Ok(h));
+}
+
+#[test]
+fn test_apply_patch_unknown_other_size() {
+    let a: Vec<u8> = (0..50_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 11) as u8)
+        .collect();
+    let mut b: Vec<u8> = Vec::new();
+    b.extend_from_slice(&a[20_000..]);
+    b.extend((0..7000u32).map(|i| (i * 3 + i / 100) as u8));
+    b.extend_from_slice(&a[..15_000]);
+    let block_size = 512;
+    let mut builder = SignatureBuilder::new(block_size);
+    builder
+        .update(&mut ChunkedReader {
+            data: &a,
+            chunk_size: 100,
+        })
+        .unwrap();
+    let a_blocks = builder.finalize();
+    assert_blocks_eq(&a_blocks, &compute_blocks(&a, block_size));
+
+    let mut out: Vec<u8> = Vec::new();
+    let mut reader = ChunkedReader {
+        data: &b,
+        chunk_size: 100,
+    };
+    let mut patch = compute_diff_streaming(&mut reader, &a_blocks, block_size, &mut out).unwrap();
+    patch.data = out;
+    patch.other_size = None;
+    assert_eq!(patch.output_size(), b.len() as u64);
+    let decoded = Patch::decode(&patch.encode()).unwrap();
+    assert_eq!(decoded.other_size, None);
+    assert_eq!(patch.serialized_size(), patch.encode().len());
+    assert!(patch.validate_coverage().is_ok());
+    assert_eq!(patch.stats().other_size, b.len() as u64);
+    assert_eq!(apply_patch(&a, &patch), b);
+    assert_eq!(apply_patch_verified(&a, &patch).unwrap(), b);
+    let mut written: Vec<u8> = Vec::new();
+    apply_patch_to_writer(&a, &patch, &mut written).unwrap();
+    assert_eq!(written, b);
+
+    let mut out: Vec<u8> = Vec::new();
+    let mut patch = compute_diff_streaming(&mut &b""[..], &a_blocks, block_size, &mut out).unwrap();
+    patch.other_size = None;
+    assert_eq!(patch.output_size(), 0);
+    assert_eq!(apply_patch(&a, &patch), b"");
+}
+
+#[test]
+fn test_compute_diff_matches_block_size() {
+    let block_size = 64;
+    let a: Vec<u8> = (0..1024u32).map(|i| (i * 13 + i / 7) as u8).collect();
+    let mut b: Vec<u8> = a[..512].to_vec();
+    b.extend_from_slice(b"short tail");
+    let mut b_blocks = compute_blocks(&b, block_size);
+    let tail = b_blocks.len() - 1;
+    assert_eq!(b_blocks[tail].size, 10);
+    // A tail block whose hashes collide with those of a full block of `a` not in `b`.
+    let a_blocks = compute_blocks(&a, block_size);
+    b_blocks[tail].hash_weak = a_blocks[12].hash_weak;
+    b_blocks[tail].hash_strong = a_blocks[12].hash_strong;
+
+    let patch_commands = compute_diff(&a, &b_blocks, block_size);
+    assert!(patch_commands
+        .base
+        .iter()
+        .all(|cmd| cmd.size as usize == block_size));
+    assert_eq!(patch_commands.other.len(), 1);
+    assert_eq!(patch_commands.other[0].target, 512);
+    assert_eq!(patch_commands.other[0].size, 10);
+    let patch = build_patch(&b, &patch_commands);
+    assert_eq!(apply_patch(&a, &patch), b);
+
+    let patch_commands = compute_diff_multi(&b, &[&a_blocks], block_size);
+    assert_eq!(apply_patch(&a, &build_patch(&b, &patch_commands)), b);
+}
+
+// Inputs of the patch files in tests/data, written by `patchy diff -b 6 base other patch` with
+// the command line tool of each version.
+#[cfg(test)]
+fn patch_file_fixture_inputs() -> (Vec<u8>, Vec<u8>) {
+    let base: Vec<u8> = (0..4096u32).map(|i| (i * 13 + i / 7) as u8).collect();
+    let mut other = base[1000..].to_vec();
+    other.extend_from_slice(b"fixture literal bytes");
+    other.extend_from_slice(&base[..1000]);
+    (base, other)
+}
+
+#[cfg(test)]
+fn check_patch_file_fixture(file: &[u8], version: u32) -> Patch {
+    let (base, other) = patch_file_fixture_inputs();
+    let bytes = zstd::stream::decode_all(file).unwrap();
+    let file = PatchWithHeader::deserialize(&bytes).unwrap();
+    assert_eq!(file.version, version);
+    assert_eq!(file.base_hash, compute_hash_strong(&base));
+    assert_eq!(file.other_hash, compute_hash_strong(&other));
+    assert_eq!(apply_patch(&base, &file.patch), other);
+
+    // Written again in the current layout.
+    let current = PatchWithHeader::new(file.base_hash, file.other_hash, file.patch);
+    let file = PatchWithHeader::deserialize(&current.serialize()).unwrap();
+    assert_eq!(file.version, PATCH_FILE_VERSION);
+    assert_eq!(apply_patch(&base, &file.patch), other);
+    file.patch
+}
+
+#[test]
+fn test_patch_file_v1() {
+    let patch = check_patch_file_fixture(include_bytes!("../tests/data/patch_v1.bin"), 1);
+    assert_eq!(patch.compression, Compression::None);
+    assert_eq!(patch.block_size, DEFAULT_BLOCK_SIZE);
+}
+
+#[test]
+fn test_patch_file_v2_compression() {
+    check_patch_file_fixture(include_bytes!("../tests/data/patch_v2_compression.bin"), 2);
+}
+
+#[test]
+fn test_patch_file_v2_other_hash() {
+    let (_, other) = patch_file_fixture_inputs();
+    let patch =
+        check_patch_file_fixture(include_bytes!("../tests/data/patch_v2_other_hash.bin"), 2);
+    assert_eq!(patch.other_hash, Some(compute_hash_strong(&other)));
+}
+
+#[test]
+fn test_patch_file_v2_source_id() {
+    let patch = check_patch_file_fixture(include_bytes!("../tests/data/patch_v2_source_id.bin"), 2);
+    assert!(patch.base.iter().all(|cmd| cmd.source_id == 0));
+}
+
+#[test]
+fn test_patch_file_v2_block_size() {
+    let patch =
+        check_patch_file_fixture(include_bytes!("../tests/data/patch_v2_block_size.bin"), 2);
+    assert_eq!(patch.block_size, 64);
+}
+
+#[test]
+fn test_patch_file_v2_base_hash() {
+    let (base, _) = patch_file_fixture_inputs();
+    let patch = check_patch_file_fixture(include_bytes!("../tests/data/patch_v2_base_hash.bin"), 2);
+    assert_eq!(patch.base_hash, Some(compute_hash_strong(&base)));
+    assert_eq!(patch.block_size, 64);
+}
+
+#[test]
+fn test_patch_file_v2_unknown_other_size() {
+    let (base, other) = patch_file_fixture_inputs();
+    let file = include_bytes!("../tests/data/patch_v2_base_hash.bin");
+    let mut bytes = zstd::stream::decode_all(&file[..]).unwrap();
+    let patch = PatchWithHeader::deserialize(&bytes).unwrap().patch;
+    // `other_size` follows the header and the lengths and contents of data, base and other.
+    let offset = 44 + 8 + patch.data.len() + 8 + 24 * patch.base.len() + 8 + 24 * patch.other.len();
+    assert_eq!(
+        bytes[offset..offset + 8],
+        (other.len() as u64).to_le_bytes()
+    );
+    bytes[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    let patch = PatchWithHeader::deserialize(&bytes).unwrap().patch;
+    assert_eq!(patch.other_size, None);
+    assert_eq!(apply_patch(&base, &patch), other);
+
+    // Files with optional `other_size` were also written as version 2.
+    let current = PatchWithHeader::new(
+        compute_hash_strong(&base),
+        compute_hash_strong(&other),
+        patch,
+    );
+    let mut bytes = current.serialize();
+    bytes[8] = 2;
+    let file = PatchWithHeader::deserialize(&bytes).unwrap();
+    assert_eq!(file.version, 2);
+    assert_eq!(file.patch.other_size, None);
+    assert_eq!(apply_patch(&base, &file.patch), other);
+}
+
+#[test]
+fn test_patch_file_rejects_unknown_header() {
+    let (base, other) = patch_file_fixture_inputs();
+    let patch = build_patch(
+        &other,
+        &compute_diff(&base, &compute_blocks(&other, 64), 64),
+    );
+    let file = PatchWithHeader::new(
+        compute_hash_strong(&base),
+        compute_hash_strong(&other),
+        patch,
+    );
+    let bytes = file.serialize();
+
+    let mut bad_id = bytes.clone();
+    bad_id[0] ^= 1;
+    assert!(PatchWithHeader::deserialize(&bad_id).is_err());
+    let mut bad_version = bytes.clone();
+    bad_version[8] = PATCH_FILE_VERSION as u8 + 1;
+    assert!(PatchWithHeader::deserialize(&bad_version).is_err());
+    let mut trailing = bytes;
+    trailing.push(0);
+    assert!(PatchWithHeader::deserialize(&trailing).is_err());
+}
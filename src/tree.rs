@@ -0,0 +1,272 @@
+use crate::hash::*;
+use crate::patchy::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+// Files of a directory tree keyed by their path relative to the root, with `/` separators.
+pub type TreeListing = BTreeMap<String, Vec<u8>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeEntryKind {
+    Unchanged,
+    // Same content as `base_path` in the base tree, which may also still exist. No data is stored.
+    Renamed { base_path: String },
+    // Patch against the base file at the same path.
+    Modified,
+    // Patch against empty base data.
+    Added,
+    Removed,
+}
+
+pub struct TreeEntry {
+    pub path: String,
+    pub kind: TreeEntryKind,
+    pub patch: Option<Patch>,
+}
+
+// One entry per path of either tree, sorted by path. Applying it to the base tree reproduces
+// the other tree.
+pub struct TreePatch {
+    pub entries: Vec<TreeEntry>,
+}
+
+const TREE_PATCH_MAGIC: [u8; 4] = *b"PTRE";
+const TREE_PATCH_FORMAT_VERSION: u16 = 1;
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn read_string(reader: &mut BodyReader) -> Result<String, PatchError> {
+    let len = checked_usize(reader.read_varint()?)?;
+    String::from_utf8(reader.read_bytes(len)?.to_vec())
+        .map_err(|e| PatchError::Serialization(e.to_string()))
+}
+
+fn diff_file(base_data: &[u8], other_data: &[u8]) -> Patch {
+    let block_size = recommended_block_size(other_data.len());
+    let other_blocks = compute_blocks(other_data, block_size);
+    let mut patch_commands = compute_diff(base_data, &other_blocks, block_size);
+    patch_commands.expand_synchronized(other_data.len());
+    build_patch_with_base(base_data, other_data, &patch_commands)
+}
+
+// Files of `other` whose content equals some file of `base` become `Renamed` entries, so moved
+// files cost no data. A file that keeps its path but changes content is patched against its old
+// version.
+pub fn diff_tree_listings(base: &TreeListing, other: &TreeListing) -> TreePatch {
+    let mut base_by_hash: HashMap<Hash128, &str> = HashMap::new();
+    for (path, data) in base {
+        base_by_hash
+            .entry(compute_hash_strong(data))
+            .or_insert(path.as_str());
+    }
+    let mut entries: Vec<TreeEntry> = Vec::new();
+    for (path, other_data) in other {
+        let base_data = base.get(path);
+        let entry = |kind: TreeEntryKind, patch: Option<Patch>| TreeEntry {
+            path: path.clone(),
+            kind,
+            patch,
+        };
+        if base_data == Some(other_data) {
+            entries.push(entry(TreeEntryKind::Unchanged, None));
+        } else if let Some(base_path) = base_by_hash.get(&compute_hash_strong(other_data)) {
+            let kind = TreeEntryKind::Renamed {
+                base_path: base_path.to_string(),
+            };
+            entries.push(entry(kind, None));
+        } else if let Some(base_data) = base_data {
+            let patch = diff_file(base_data, other_data);
+            entries.push(entry(TreeEntryKind::Modified, Some(patch)));
+        } else {
+            let patch = diff_file(&[], other_data);
+            entries.push(entry(TreeEntryKind::Added, Some(patch)));
+        }
+    }
+    for path in base.keys().filter(|path| !other.contains_key(*path)) {
+        entries.push(TreeEntry {
+            path: path.clone(),
+            kind: TreeEntryKind::Removed,
+            patch: None,
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    TreePatch { entries }
+}
+
+// Paths of entries are joined to the output directory, so they may only name files below it.
+fn check_entry_path(path: &str) -> Result<(), PatchError> {
+    let path_ref = Path::new(path);
+    if path.is_empty()
+        || !path_ref
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(PatchError::UnsafePath {
+            path: path.to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub fn apply_tree_patch_listing(
+    base: &TreeListing,
+    tree_patch: &TreePatch,
+) -> Result<TreeListing, PatchError> {
+    let base_file = |path: &str| {
+        base.get(path).ok_or_else(|| PatchError::MissingFile {
+            path: path.to_string(),
+        })
+    };
+    let mut result = TreeListing::new();
+    for entry in &tree_patch.entries {
+        check_entry_path(&entry.path)?;
+        let data = match (&entry.kind, &entry.patch) {
+            (TreeEntryKind::Unchanged, None) => base_file(&entry.path)?.clone(),
+            (TreeEntryKind::Renamed { base_path }, None) => base_file(base_path)?.clone(),
+            (TreeEntryKind::Modified, Some(patch)) => {
+                apply_patch_verified(base_file(&entry.path)?, patch)?
+            }
+            (TreeEntryKind::Added, Some(patch)) => apply_patch_verified(&[], patch)?,
+            (TreeEntryKind::Removed, None) => continue,
+            _ => {
+                return Err(PatchError::Serialization(format!(
+                    "entry {} has an unexpected patch",
+                    entry.path
+                )))
+            }
+        };
+        result.insert(entry.path.clone(), data);
+    }
+    Ok(result)
+}
+
+fn read_dir_into(root: &Path, dir: &Path, listing: &mut TreeListing) -> io::Result<()> {
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        if path.is_dir() {
+            read_dir_into(root, &path, listing)?;
+        } else {
+            let relative = path.strip_prefix(root).unwrap();
+            let key: Vec<String> = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            listing.insert(key.join("/"), fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+// Reads every file below `dir`. Directories that contain no files are not represented.
+pub fn read_tree(dir: impl AsRef<Path>) -> io::Result<TreeListing> {
+    let mut listing = TreeListing::new();
+    read_dir_into(dir.as_ref(), dir.as_ref(), &mut listing)?;
+    Ok(listing)
+}
+
+pub fn diff_trees(
+    base_dir: impl AsRef<Path>,
+    other_dir: impl AsRef<Path>,
+) -> io::Result<TreePatch> {
+    Ok(diff_tree_listings(
+        &read_tree(base_dir)?,
+        &read_tree(other_dir)?,
+    ))
+}
+
+// Writes the patched tree into `out_dir`, which must not be inside `base_dir`.
+pub fn apply_tree_patch(
+    base_dir: impl AsRef<Path>,
+    tree_patch: &TreePatch,
+    out_dir: impl AsRef<Path>,
+) -> io::Result<()> {
+    let base = read_tree(base_dir)?;
+    let result = apply_tree_patch_listing(&base, tree_patch)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    for (path, data) in &result {
+        let out_path = out_dir.as_ref().join(path);
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(out_path, data)?;
+    }
+    Ok(())
+}
+
+impl TreePatch {
+    // Layout: the `Patch::encode` container with its own magic and version. The body is the
+    // entry count (varint) followed by, per entry, a kind tag (u8: 0 = unchanged, 1 = renamed,
+    // 2 = modified, 3 = added, 4 = removed) and the path. Renamed entries add the base path,
+    // modified and added entries the encoded `Patch` preceded by its length (varint). Strings
+    // are a varint length followed by UTF-8 bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body: Vec<u8> = Vec::new();
+        write_varint(&mut body, self.entries.len() as u64);
+        for entry in &self.entries {
+            let tag = match entry.kind {
+                TreeEntryKind::Unchanged => 0,
+                TreeEntryKind::Renamed { .. } => 1,
+                TreeEntryKind::Modified => 2,
+                TreeEntryKind::Added => 3,
+                TreeEntryKind::Removed => 4,
+            };
+            body.push(tag);
+            write_string(&mut body, &entry.path);
+            if let TreeEntryKind::Renamed { base_path } = &entry.kind {
+                write_string(&mut body, base_path);
+            }
+            if let Some(patch) = &entry.patch {
+                let encoded = patch.encode();
+                write_varint(&mut body, encoded.len() as u64);
+                body.extend_from_slice(&encoded);
+            }
+        }
+        encode_container(&TREE_PATCH_MAGIC, TREE_PATCH_FORMAT_VERSION, &body)
+    }
+    pub fn decode(bytes: &[u8]) -> Result<TreePatch, PatchError> {
+        let body = decode_container(bytes, &TREE_PATCH_MAGIC, TREE_PATCH_FORMAT_VERSION)?;
+        let mut reader = BodyReader { bytes: body };
+        let count = checked_usize(reader.read_varint()?)?;
+        // Every entry takes at least two bytes, so a bogus count can't force a huge allocation.
+        let mut entries: Vec<TreeEntry> = Vec::with_capacity(count.min(body.len() / 2));
+        for _ in 0..count {
+            let tag = reader.read_u8()?;
+            let path = read_string(&mut reader)?;
+            let kind = match tag {
+                0 => TreeEntryKind::Unchanged,
+                1 => TreeEntryKind::Renamed {
+                    base_path: read_string(&mut reader)?,
+                },
+                2 => TreeEntryKind::Modified,
+                3 => TreeEntryKind::Added,
+                4 => TreeEntryKind::Removed,
+                tag => {
+                    return Err(PatchError::Serialization(format!(
+                        "unknown tree entry tag {}",
+                        tag
+                    )))
+                }
+            };
+            let patch = match kind {
+                TreeEntryKind::Modified | TreeEntryKind::Added => {
+                    let len = checked_usize(reader.read_varint()?)?;
+                    Some(Patch::decode(reader.read_bytes(len)?)?)
+                }
+                _ => None,
+            };
+            entries.push(TreeEntry { path, kind, patch });
+        }
+        if !reader.bytes.is_empty() {
+            return Err(PatchError::Serialization(format!(
+                "{} trailing bytes after tree patch body",
+                reader.bytes.len()
+            )));
+        }
+        Ok(TreePatch { entries })
+    }
+}
@@ -0,0 +1,296 @@
+use crate::flat::*;
+use crate::patchy::*;
+
+const VCDIFF_MAGIC: [u8; 4] = [0xD6, 0xC3, 0xC4, 0x00];
+// Win_Indicator bits: the window copies from a segment of the source file or of earlier output.
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+// Output bytes per window, xdelta3's default window size.
+const VCDIFF_WINDOW_SIZE: u64 = 1 << 23;
+// Address cache sizes of the default code table.
+const NEAR_SIZE: usize = 4;
+const SAME_SIZE: usize = 3;
+const MODE_SELF: u8 = 0;
+const MODE_HERE: u8 = 1;
+// First COPY opcode of the default code table. Each address mode has 16 opcodes: one with the
+// size in the instruction section, then sizes 4 to 18.
+const COPY_OPCODE: u8 = 19;
+
+// Big-endian base 128, with the high bit set on all bytes but the last.
+fn write_vcdiff_int(out: &mut Vec<u8>, value: u64) {
+    let mut digits = [0u8; 10];
+    let mut begin = digits.len();
+    let mut value = value;
+    loop {
+        begin -= 1;
+        digits[begin] = (value & 0x7F) as u8;
+        if begin != digits.len() - 1 {
+            digits[begin] |= 0x80;
+        }
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(&digits[begin..]);
+}
+
+// Mirrors the decoder's cache, which starts out zeroed in every window.
+struct AddressCache {
+    near: [u64; NEAR_SIZE],
+    next_slot: usize,
+    same: [u64; SAME_SIZE * 256],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        AddressCache {
+            near: [0; NEAR_SIZE],
+            next_slot: 0,
+            same: [0; SAME_SIZE * 256],
+        }
+    }
+
+    // Writes `addr` in the mode that gives the smallest value and returns the mode. `here` is
+    // the position the copy writes to, which is always past `addr`.
+    fn encode(&mut self, addr: u64, here: u64, out: &mut Vec<u8>) -> u8 {
+        let same_slot = (addr % (SAME_SIZE * 256) as u64) as usize;
+        let mode = if self.same[same_slot] == addr {
+            out.push((same_slot % 256) as u8);
+            (2 + NEAR_SIZE + same_slot / 256) as u8
+        } else {
+            let mut mode = MODE_SELF;
+            let mut value = addr;
+            if here - addr < value {
+                mode = MODE_HERE;
+                value = here - addr;
+            }
+            for (i, &near) in self.near.iter().enumerate() {
+                if addr >= near && addr - near < value {
+                    mode = 2 + i as u8;
+                    value = addr - near;
+                }
+            }
+            write_vcdiff_int(out, value);
+            mode
+        };
+        self.near[self.next_slot] = addr;
+        self.next_slot = (self.next_slot + 1) % NEAR_SIZE;
+        self.same[same_slot] = addr;
+        mode
+    }
+}
+
+enum Instruction {
+    Add { size: u64 },
+    // Offset into the base.
+    CopySource { source: u64, size: u64 },
+    // Offset into the output.
+    CopyTarget { source: u64, size: u64 },
+}
+
+struct Segment {
+    indicator: u8,
+    begin: u64,
+    end: u64,
+}
+
+// Collects the instructions of the current window. Addresses depend on the final source
+// segment, so they are only encoded when the window is flushed.
+struct VcdiffEncoder {
+    out: Vec<u8>,
+    window_size: u64,
+    window_begin: u64,
+    window_len: u64,
+    segment: Option<Segment>,
+    instructions: Vec<Instruction>,
+    data: Vec<u8>,
+}
+
+impl VcdiffEncoder {
+    fn room(&mut self) -> u64 {
+        if self.window_len == self.window_size {
+            self.flush();
+        }
+        self.window_size - self.window_len
+    }
+
+    fn add(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+        self.instructions.push(Instruction::Add {
+            size: bytes.len() as u64,
+        });
+        self.window_len += bytes.len() as u64;
+    }
+
+    // The source segment spans every base range copied in the window. VCD_TARGET windows are
+    // flushed right away, so the segment is always from the base here.
+    fn copy_source(&mut self, source: u64, size: u64) {
+        match &mut self.segment {
+            Some(segment) => {
+                segment.begin = segment.begin.min(source);
+                segment.end = segment.end.max(source + size);
+            }
+            None => {
+                self.segment = Some(Segment {
+                    indicator: VCD_SOURCE,
+                    begin: source,
+                    end: source + size,
+                })
+            }
+        }
+        self.instructions
+            .push(Instruction::CopySource { source, size });
+        self.window_len += size;
+    }
+
+    // Output written before the window is only reachable through a VCD_TARGET segment, so
+    // such a copy gets a window of its own. Where it overlaps the bytes it writes, the copy
+    // runs on from the segment into the window, just like the self-copy it encodes.
+    fn copy_target(&mut self, source: u64, size: u64) {
+        if source < self.window_begin {
+            if self.window_len != 0 {
+                self.flush();
+            }
+            self.segment = Some(Segment {
+                indicator: VCD_TARGET,
+                begin: source,
+                end: (source + size).min(self.window_begin),
+            });
+        }
+        self.instructions
+            .push(Instruction::CopyTarget { source, size });
+        self.window_len += size;
+        if source < self.window_begin {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let (indicator, segment_begin, segment_len) = match &self.segment {
+            Some(segment) => (
+                segment.indicator,
+                segment.begin,
+                segment.end - segment.begin,
+            ),
+            None => (0, 0, 0),
+        };
+        let mut cache = AddressCache::new();
+        let mut opcodes: Vec<u8> = Vec::new();
+        let mut addresses: Vec<u8> = Vec::new();
+        let mut here = segment_len;
+        for instruction in &self.instructions {
+            let (opcode, size) = match *instruction {
+                Instruction::Add { size } if (1..=17).contains(&size) => {
+                    opcodes.push(1 + size as u8);
+                    here += size;
+                    continue;
+                }
+                Instruction::Add { size } => (1, size),
+                Instruction::CopySource { source, size } => {
+                    let mode = cache.encode(source - segment_begin, here, &mut addresses);
+                    (COPY_OPCODE + 16 * mode, size)
+                }
+                Instruction::CopyTarget { source, size } => {
+                    let addr = if source < self.window_begin {
+                        source - segment_begin
+                    } else {
+                        segment_len + source - self.window_begin
+                    };
+                    let mode = cache.encode(addr, here, &mut addresses);
+                    (COPY_OPCODE + 16 * mode, size)
+                }
+            };
+            here += size;
+            if opcode != 1 && (4..=18).contains(&size) {
+                opcodes.push(opcode + size as u8 - 3);
+            } else {
+                opcodes.push(opcode);
+                write_vcdiff_int(&mut opcodes, size);
+            }
+        }
+
+        let mut delta: Vec<u8> = Vec::new();
+        write_vcdiff_int(&mut delta, self.window_len);
+        delta.push(0);
+        write_vcdiff_int(&mut delta, self.data.len() as u64);
+        write_vcdiff_int(&mut delta, opcodes.len() as u64);
+        write_vcdiff_int(&mut delta, addresses.len() as u64);
+        delta.extend_from_slice(&self.data);
+        delta.extend_from_slice(&opcodes);
+        delta.extend_from_slice(&addresses);
+
+        self.out.push(indicator);
+        if indicator != 0 {
+            write_vcdiff_int(&mut self.out, segment_len);
+            write_vcdiff_int(&mut self.out, segment_begin);
+        }
+        write_vcdiff_int(&mut self.out, delta.len() as u64);
+        self.out.extend_from_slice(&delta);
+
+        self.window_begin += self.window_len;
+        self.window_len = 0;
+        self.segment = None;
+        self.instructions.clear();
+        self.data.clear();
+    }
+}
+
+pub(crate) fn encode_vcdiff(patch: &Patch, window_size: u64) -> Result<Vec<u8>, PatchError> {
+    let flat = patch.to_flat()?;
+    let mut encoder = VcdiffEncoder {
+        out: VCDIFF_MAGIC.to_vec(),
+        window_size,
+        window_begin: 0,
+        window_len: 0,
+        segment: None,
+        instructions: Vec::new(),
+        data: Vec::new(),
+    };
+    // Hdr_Indicator: no secondary compressor, default code table.
+    encoder.out.push(0);
+    for op in &flat.ops {
+        let size = match op {
+            FlatOp::Copy { size, .. } => *size as u64,
+            FlatOp::Literal(bytes) => bytes.len() as u64,
+        };
+        // Ops longer than the space left in the window are split.
+        let mut done: u64 = 0;
+        while done < size {
+            let len = (size - done).min(encoder.room());
+            match *op {
+                FlatOp::Literal(ref bytes) => {
+                    encoder.add(&bytes[done as usize..(done + len) as usize])
+                }
+                FlatOp::Copy {
+                    source,
+                    source_id: 0,
+                    ..
+                } => encoder.copy_source(source + done, len),
+                FlatOp::Copy {
+                    source,
+                    source_id: SELF_SOURCE_ID,
+                    ..
+                } => encoder.copy_target(source + done, len),
+                FlatOp::Copy { source_id, .. } => {
+                    return Err(PatchError::InvalidSourceId { source_id })
+                }
+            }
+            done += len;
+        }
+    }
+    if encoder.window_len != 0 {
+        encoder.flush();
+    }
+    Ok(encoder.out)
+}
+
+impl Patch {
+    // RFC 3284 delta of the output against the base, for tools such as `xdelta3 -d -s base`.
+    // Base copies become COPY instructions from the source file and literals ADD
+    // instructions. Fails for patches with several bases or that don't cover the output
+    // exactly once.
+    pub fn to_vcdiff(&self) -> Result<Vec<u8>, PatchError> {
+        encode_vcdiff(self, VCDIFF_WINDOW_SIZE)
+    }
+}